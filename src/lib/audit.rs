@@ -0,0 +1,408 @@
+use crate::lib::settings::Audit;
+use chrono::Utc;
+use ring::digest;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The `prev` of the very first record.
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+const HASH_FIELD: &str = ",\"hash\":\"";
+
+/// One audited command, as handed over by `operations::dispatch`.
+pub struct Event<'a> {
+    pub addr: Option<&'a str>,
+    pub identity: Option<&'a str>,
+    pub command: &'static str,
+    pub channel: Option<&'a str>,
+    pub uid: Option<&'a str>,
+    pub error: Option<&'a str>,
+}
+
+/// A line of the audit file, less its `hash`. The hash covers these fields,
+/// including the previous line's hash, so a changed, removed or reordered
+/// line breaks every hash after it.
+#[derive(Serialize)]
+struct Record<'a> {
+    seq: u64,
+    ts: String,
+    addr: Option<&'a str>,
+    identity: Option<&'a str>,
+    command: &'static str,
+    channel: Option<&'a str>,
+    uid: Option<&'a str>,
+    outcome: &'static str,
+    error: Option<&'a str>,
+    prev: &'a str,
+}
+
+fn hash(body: &str) -> String {
+    digest::digest(&digest::SHA256, body.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Splits a line into the body its hash covers and the hash itself.
+fn split(line: &str) -> Option<(String, &str)> {
+    let at = line.rfind(HASH_FIELD)?;
+    let hash = line[at + HASH_FIELD.len()..].strip_suffix("\"}")?;
+    Some((format!("{}}}", &line[..at]), hash))
+}
+
+/// The append-only audit file. Rotated files are renamed with a timestamp
+/// and never removed, and the chain carries on into the new file.
+pub struct Sink {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+    opened: Instant,
+    max_size: u64,
+    max_age: Option<Duration>,
+    seq: u64,
+    last: String,
+}
+
+impl Sink {
+    pub fn open(settings: &Audit) -> io::Result<Sink> {
+        let path = PathBuf::from(&settings.path);
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let size = file.metadata()?.len();
+        // Pick the chain up from the end of this file, or of the newest
+        // rotated one if this file was only just started.
+        let tail = match size {
+            0 => newest_rotated(&path)?,
+            _ => Some(path.clone()),
+        };
+        let (seq, last) = match tail {
+            Some(tail) => last_record(&tail)?,
+            None => (0, GENESIS.to_string()),
+        };
+        Ok(Sink {
+            path,
+            file,
+            size,
+            opened: Instant::now(),
+            max_size: settings.rotate_size,
+            max_age: match settings.rotate_age {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds)),
+            },
+            seq,
+            last,
+        })
+    }
+
+    pub fn write(&mut self, event: &Event) -> io::Result<()> {
+        let record = Record {
+            seq: self.seq + 1,
+            ts: Utc::now().to_rfc3339(),
+            addr: event.addr,
+            identity: event.identity,
+            command: event.command,
+            channel: event.channel,
+            uid: event.uid,
+            outcome: match event.error {
+                Some(_) => "ER",
+                None => "OK",
+            },
+            error: event.error,
+            prev: &self.last,
+        };
+        let body = serde_json::to_string(&record)?;
+        let hash = hash(&body);
+        let line = format!("{}{}{}\"}}\n", &body[..body.len() - 1], HASH_FIELD, hash);
+
+        if self.due(line.len()) {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        self.size += line.len() as u64;
+        self.seq += 1;
+        self.last = hash;
+        Ok(())
+    }
+
+    fn due(&self, incoming: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let too_big = self.max_size > 0 && self.size + incoming as u64 > self.max_size;
+        let too_old = self
+            .max_age
+            .is_some_and(|age| self.opened.elapsed() >= age);
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", Utc::now().format("%Y%m%dT%H%M%S%.6fZ")));
+        // Never overwrite an earlier file, however quickly they rotate.
+        let mut rotated = PathBuf::from(&name);
+        let mut attempt = 0;
+        while rotated.exists() {
+            attempt += 1;
+            let mut retry = name.clone();
+            retry.push(format!("-{}", attempt));
+            rotated = PathBuf::from(retry);
+        }
+        fs::rename(&self.path, &rotated)?;
+        self.file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+/// Rotated names sort by time, so the newest is the greatest.
+fn newest_rotated(path: &Path) -> io::Result<Option<PathBuf>> {
+    let prefix = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => format!("{}.", name),
+        None => return Ok(None),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut newest = None;
+    for entry in fs::read_dir(&dir)? {
+        let name = entry?.file_name();
+        let name = match name.to_str() {
+            Some(name) if name.starts_with(&prefix) => name.to_string(),
+            _ => continue,
+        };
+        if newest
+            .as_ref()
+            .is_none_or(|newest: &String| name > *newest)
+        {
+            newest = Some(name);
+        }
+    }
+    Ok(newest.map(|name| dir.join(name)))
+}
+
+fn last_record(path: &Path) -> io::Result<(u64, String)> {
+    let mut last = None;
+    for line in BufReader::new(fs::File::open(path)?).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            last = Some(line);
+        }
+    }
+    let line = match last {
+        Some(line) => line,
+        None => return Ok((0, GENESIS.to_string())),
+    };
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: last line is not an audit record", path.display()),
+        )
+    };
+    let (body, hash) = split(&line).ok_or_else(invalid)?;
+    let record: serde_json::Value = serde_json::from_str(&body).map_err(|_| invalid())?;
+    let seq = record["seq"].as_u64().ok_or_else(invalid)?;
+    Ok((seq, hash.to_string()))
+}
+
+pub static AUDIT: Mutex<Option<Sink>> = Mutex::new(None);
+
+pub fn install(sink: Sink) {
+    *AUDIT.lock().unwrap() = Some(sink);
+}
+
+/// Appends `event` if an audit sink is installed.
+pub fn record(event: Event) {
+    if let Some(sink) = AUDIT.lock().unwrap().as_mut() {
+        if let Err(e) = sink.write(&event) {
+            error!("unable to write audit record; error = {:?}", e);
+        }
+    }
+}
+
+/// Where a chain check got to, carried from one file to the next.
+#[derive(Debug, Default)]
+pub struct Chain {
+    pub records: usize,
+    /// The seq the check started at, when that wasn't the first record ever.
+    pub continues: Option<u64>,
+    pub seq: Option<u64>,
+    pub last: Option<String>,
+}
+
+impl Chain {
+    /// Checks every line of `input` against its hash and the line before,
+    /// naming `source` and the line number in any error. The first record
+    /// checked may continue a chain from a file that wasn't given.
+    pub fn verify<R: BufRead>(&mut self, source: &str, input: R) -> Result<(), String> {
+        for (number, line) in input.lines().enumerate() {
+            let fail = |message: String| format!("{}:{}: {}", source, number + 1, message);
+            let line = line.map_err(|e| fail(e.to_string()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (body, expected) = split(&line).ok_or_else(|| fail("missing hash".to_string()))?;
+            if hash(&body) != expected {
+                return Err(fail("hash does not match the record".to_string()));
+            }
+            let record: serde_json::Value =
+                serde_json::from_str(&body).map_err(|e| fail(e.to_string()))?;
+            let seq = record["seq"]
+                .as_u64()
+                .ok_or_else(|| fail("missing seq".to_string()))?;
+            let prev = record["prev"]
+                .as_str()
+                .ok_or_else(|| fail("missing prev".to_string()))?;
+            if let Some(last) = self.seq {
+                if seq != last + 1 {
+                    return Err(fail(format!("expected seq {}, found {}", last + 1, seq)));
+                }
+            }
+            if let Some(ref last) = self.last {
+                if prev != last {
+                    return Err(fail("prev does not match the previous hash".to_string()));
+                }
+            }
+            if self.records == 0 && prev != GENESIS {
+                self.continues = Some(seq);
+            }
+            self.records += 1;
+            self.seq = Some(seq);
+            self.last = Some(expected.to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(name: &str, rotate_size: u64) -> (PathBuf, Audit) {
+        let dir =
+            std::env::temp_dir().join(format!("merkava-audit-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let settings = Audit {
+            enabled: true,
+            path: dir.join("audit.log").to_str().unwrap().to_string(),
+            rotate_size,
+            rotate_age: 0,
+        };
+        (dir, settings)
+    }
+
+    fn event<'a>(uid: &'a str, error: Option<&'a str>) -> Event<'a> {
+        Event {
+            addr: Some("127.0.0.1:50000"),
+            identity: Some("chat"),
+            command: "PUSH",
+            channel: Some("room-1"),
+            uid: Some(uid),
+            error,
+        }
+    }
+
+    fn verify(path: &Path) -> Result<Chain, String> {
+        let mut chain = Chain::default();
+        let file = fs::File::open(path).unwrap();
+        chain.verify("audit.log", BufReader::new(file))?;
+        Ok(chain)
+    }
+
+    #[test]
+    fn chain_verifies_and_survives_reopening() {
+        let (_, settings) = settings("chain", 0);
+        let mut sink = Sink::open(&settings).unwrap();
+        sink.write(&event("a", None)).unwrap();
+        sink.write(&event("b", Some("No message found"))).unwrap();
+        drop(sink);
+        let mut sink = Sink::open(&settings).unwrap();
+        sink.write(&event("c", None)).unwrap();
+
+        let chain = verify(Path::new(&settings.path)).unwrap();
+        assert_eq!(chain.records, 3);
+        assert_eq!(chain.seq, Some(3));
+
+        let text = fs::read_to_string(&settings.path).unwrap();
+        let first: serde_json::Value = serde_json::from_str(text.lines().next().unwrap()).unwrap();
+        assert_eq!(first["prev"], GENESIS);
+        assert_eq!(first["identity"], "chat");
+        let second: serde_json::Value = serde_json::from_str(text.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(second["outcome"], "ER");
+    }
+
+    #[test]
+    fn verify_finds_edits_and_removals() {
+        let (_, settings) = settings("tamper", 0);
+        let mut sink = Sink::open(&settings).unwrap();
+        for uid in &["a", "b", "c"] {
+            sink.write(&event(uid, None)).unwrap();
+        }
+        let text = fs::read_to_string(&settings.path).unwrap();
+
+        fs::write(
+            &settings.path,
+            text.replace("\"uid\":\"b\"", "\"uid\":\"x\""),
+        )
+        .unwrap();
+        let error = verify(Path::new(&settings.path)).unwrap_err();
+        assert!(error.starts_with("audit.log:2: hash"), "{}", error);
+
+        let without_second: Vec<&str> = text
+            .lines()
+            .enumerate()
+            .filter(|(number, _)| *number != 1)
+            .map(|(_, line)| line)
+            .collect();
+        fs::write(&settings.path, without_second.join("\n")).unwrap();
+        let error = verify(Path::new(&settings.path)).unwrap_err();
+        assert!(error.contains("expected seq 2"), "{}", error);
+    }
+
+    #[test]
+    fn rotation_keeps_the_chain() {
+        let (dir, settings) = settings("rotate", 300);
+        let mut sink = Sink::open(&settings).unwrap();
+        for uid in &["a", "b", "c", "d"] {
+            sink.write(&event(uid, None)).unwrap();
+        }
+        drop(sink);
+        let mut sink = Sink::open(&settings).unwrap();
+        sink.write(&event("e", None)).unwrap();
+
+        let mut files: Vec<PathBuf> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path != Path::new(&settings.path))
+            .collect();
+        files.sort();
+        assert!(!files.is_empty());
+        files.push(PathBuf::from(&settings.path));
+
+        let mut chain = Chain::default();
+        for path in &files {
+            let file = BufReader::new(fs::File::open(path).unwrap());
+            chain.verify(path.to_str().unwrap(), file).unwrap();
+        }
+        assert_eq!(chain.records, 5);
+        assert_eq!(chain.continues, None);
+
+        let mut chain = Chain::default();
+        let file = BufReader::new(fs::File::open(&settings.path).unwrap());
+        chain.verify("audit.log", file).unwrap();
+        assert_eq!(chain.continues, Some(5));
+    }
+}
@@ -0,0 +1,159 @@
+use crate::lib::types::Request;
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Permission {
+    fn name(&self) -> &'static str {
+        match *self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenConfig {
+    pub token: String,
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub write: Vec<String>,
+    #[serde(default)]
+    pub admin: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Grants {
+    pub identity: String,
+    rules: Vec<(Pattern, Permission)>,
+}
+
+impl Grants {
+    pub fn allows(&self, permission: Permission, channel_id: &str) -> bool {
+        self.rules
+            .iter()
+            .any(|(pattern, granted)| *granted >= permission && pattern.matches(channel_id))
+    }
+}
+
+pub fn is_enabled(conf: &config::Config) -> bool {
+    conf.get::<bool>("auth.enabled").unwrap_or(false)
+}
+
+pub fn tokens_match(given: &str, expected: &str) -> bool {
+    // Compare every byte so the time taken doesn't leak how much matched.
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+pub fn authenticate(conf: &config::Config, token: &str) -> Result<Grants, String> {
+    let tokens = conf
+        .get::<HashMap<String, TokenConfig>>("auth.tokens")
+        .unwrap_or_default();
+    let (identity, found) = match tokens.iter().find(|(_, t)| tokens_match(token, &t.token)) {
+        Some(found) => found,
+        None => return Err("invalid token".to_string()),
+    };
+
+    let mut rules = Vec::new();
+    for (permission, patterns) in &[
+        (Permission::Read, &found.read),
+        (Permission::Write, &found.write),
+        (Permission::Admin, &found.admin),
+    ] {
+        for pattern in patterns.iter() {
+            match Pattern::new(pattern) {
+                Ok(pattern) => rules.push((pattern, *permission)),
+                Err(e) => {
+                    return Err(format!(
+                        "invalid pattern {:?} for auth.tokens.{}: {}",
+                        pattern, identity, e.msg
+                    ));
+                }
+            }
+        }
+    }
+    Ok(Grants {
+        identity: identity.clone(),
+        rules,
+    })
+}
+
+fn required(request: &Request) -> Vec<(Permission, &str)> {
+    match *request {
+        Request::Auth { .. } => vec![],
+        Request::Push { ref channel_id, .. } | Request::Update { ref channel_id, .. } => {
+            vec![(Permission::Write, channel_id)]
+        }
+        Request::Recent { ref channel_id, .. }
+        | Request::Retrieve { ref channel_id, .. }
+        | Request::Connect { ref channel_id }
+        | Request::Subscribe { ref channel_id }
+        | Request::Stats { ref channel_id } => vec![(Permission::Read, channel_id)],
+        Request::Unsubscribe { .. } => vec![],
+        Request::Flush { ref channel_id, .. }
+        | Request::Backup { ref channel_id }
+        | Request::Import { ref channel_id, .. } => vec![(Permission::Admin, channel_id)],
+        Request::Rename {
+            ref channel_id,
+            ref target_id,
+        }
+        | Request::Copy {
+            ref channel_id,
+            ref target_id,
+        } => vec![(Permission::Admin, channel_id), (Permission::Admin, target_id)],
+        // Server-wide listings need a grant that covers every channel.
+        Request::List { .. } | Request::ClusterSlots | Request::Changes { .. } => {
+            vec![(Permission::Read, "*")]
+        }
+        Request::ConfigGet { .. }
+        | Request::ConfigSet { .. }
+        | Request::SlowlogGet { .. }
+        | Request::SlowlogReset
+        | Request::Info
+        | Request::ClientList
+        | Request::ClientKill { .. }
+        | Request::Replication
+        | Request::Promote => vec![(Permission::Admin, "*")],
+    }
+}
+
+/// Checks a parsed request against the grants of the current session,
+/// returning the error message to send back if it may not run.
+pub fn check(conf: &config::Config, grants: Option<&Grants>, request: &Request) -> Result<(), String> {
+    if !is_enabled(conf) {
+        return Ok(());
+    }
+    let needed = required(request);
+    if needed.is_empty() {
+        return Ok(());
+    }
+    let grants = match grants {
+        Some(grants) => grants,
+        None => return Err("NOAUTH authentication required".to_string()),
+    };
+    for (permission, channel_id) in needed {
+        if !grants.allows(permission, channel_id) {
+            return Err(format!(
+                "NOPERM {} has no {} permission on {}",
+                grants.identity,
+                permission.name(),
+                channel_id
+            ));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,423 @@
+use crate::lib::replication::Change;
+use crate::lib::settings;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// One entry in the change feed. `lsn` counts every change this node has
+/// made or applied, and never goes back, even across restarts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    pub lsn: u64,
+    pub at: DateTime<Utc>,
+    pub op: String,
+    pub channel: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// Where a channel was renamed or copied to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+impl Event {
+    fn new(op: &str, channel: &str) -> Event {
+        Event {
+            lsn: 0,
+            at: Utc::now(),
+            op: op.to_string(),
+            channel: channel.to_string(),
+            uid: None,
+            value: None,
+            target: None,
+        }
+    }
+
+    fn message(mut self, uid: &str, value: &str) -> Event {
+        self.uid = Some(uid.to_string());
+        self.value = Some(value.to_string());
+        self
+    }
+
+    fn target(mut self, target: &str) -> Event {
+        self.target = Some(target.to_string());
+        self
+    }
+}
+
+/// The events a change is recorded as. An import, when a cluster node is
+/// sent a channel, is one event for each message it carried.
+fn events(change: &Change) -> Vec<Event> {
+    match *change {
+        Change::Push {
+            ref channel,
+            ref message,
+            ..
+        } => vec![Event::new("push", channel).message(&message.uid, &message.value)],
+        Change::Update {
+            ref channel,
+            ref uid,
+            ref value,
+        } => vec![Event::new("update", channel).message(uid, value)],
+        Change::Flush { ref channel, .. } => vec![Event::new("flush", channel)],
+        Change::Rename {
+            ref channel,
+            ref target,
+        } => vec![Event::new("rename", channel).target(target)],
+        Change::Copy {
+            ref channel,
+            ref target,
+        } => vec![Event::new("copy", channel).target(target)],
+        Change::Import {
+            ref channel,
+            ref export,
+        } => export
+            .messages
+            .iter()
+            .map(|message| Event::new("import", channel).message(&message.uid, &message.value))
+            .collect(),
+    }
+}
+
+/// The change feed: an append-only file of JSON lines, and the newest
+/// `max_len` events in memory for `CHANGES`. Once the file holds twice
+/// `max_len` events it is rewritten with only the ones kept.
+pub struct Log {
+    path: PathBuf,
+    file: fs::File,
+    /// Bytes of the file holding whole events, so a failed write can be
+    /// cut off rather than leave half a line.
+    size: u64,
+    /// Events in the file, the ones kept and any dropped since it was last
+    /// rewritten.
+    written: usize,
+    events: VecDeque<Event>,
+    max_len: usize,
+    lsn: u64,
+}
+
+impl Log {
+    /// Opens the file and carries on from its last event. Only the newest
+    /// `max_len` events are kept, so the file is rewritten with just those.
+    /// A last line that can't be read is taken to be cut short by a crash
+    /// and dropped; a bad line before it means the file is damaged.
+    pub fn open(settings: &settings::Changes) -> io::Result<Log> {
+        let path = PathBuf::from(&settings.path);
+        let mut events = VecDeque::new();
+        let mut lsn = 0;
+        let mut torn: Option<io::Error> = None;
+        if path.exists() {
+            for (number, line) in BufReader::new(fs::File::open(&path)?).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                if let Some(e) = torn.take() {
+                    return Err(e);
+                }
+                let event: Event = match serde_json::from_str(&line) {
+                    Ok(event) => event,
+                    Err(e) => {
+                        let message = format!("{}:{}: {}", path.display(), number + 1, e);
+                        torn = Some(io::Error::new(io::ErrorKind::InvalidData, message));
+                        continue;
+                    }
+                };
+                lsn = event.lsn;
+                events.push_back(event);
+                if events.len() > settings.max_len {
+                    events.pop_front();
+                }
+            }
+        }
+        if let Some(e) = torn {
+            warn!("dropping a change cut short: {}", e);
+        }
+        let (file, size) = compact(&path, &events)?;
+        Ok(Log {
+            path,
+            file,
+            size,
+            written: events.len(),
+            events,
+            max_len: settings.max_len,
+            lsn,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends the events for `change`. They are only given lsns and kept
+    /// for `read` once they are on disk, so a failed write never hands out
+    /// an lsn that could be handed out again after a restart.
+    pub fn record(&mut self, change: &Change) -> io::Result<()> {
+        let mut events = events(change);
+        let mut lines = String::new();
+        for (event, lsn) in events.iter_mut().zip(self.lsn + 1..) {
+            event.lsn = lsn;
+            lines.push_str(&serde_json::to_string(&event)?);
+            lines.push('\n');
+        }
+        if let Err(e) = self.file.write_all(lines.as_bytes()).and_then(|_| self.file.flush()) {
+            // Don't leave part of a line for the next write to follow.
+            let _ = self.file.set_len(self.size);
+            return Err(e);
+        }
+        self.size += lines.len() as u64;
+        self.written += events.len();
+        for event in events {
+            self.lsn = event.lsn;
+            self.events.push_back(event);
+            if self.events.len() > self.max_len {
+                self.events.pop_front();
+            }
+        }
+        if self.written >= self.max_len.saturating_mul(2) {
+            // The events are safely written either way, so a failed rewrite
+            // only leaves the file longer until the next one.
+            if let Err(e) = self.compact() {
+                warn!("unable to compact {}: {}", self.path.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        let (file, size) = compact(&self.path, &self.events)?;
+        self.file = file;
+        self.size = size;
+        self.written = self.events.len();
+        Ok(())
+    }
+
+    /// Up to `count` events from `from` on, and the lsn to read from next.
+    /// `from` 0 starts at the oldest event kept. Otherwise this fails if
+    /// events from `from` have already been dropped, so a consumer knows it
+    /// missed some.
+    pub fn read(&self, from: u64, count: usize) -> Result<(Vec<Event>, u64), String> {
+        let oldest = self.events.front().map_or(self.lsn + 1, |event| event.lsn);
+        let from = match from {
+            0 => oldest,
+            from => from,
+        };
+        if from < oldest {
+            return Err(format!("TRIMMED the oldest change kept is {}", oldest));
+        }
+        let events: Vec<Event> = self
+            .events
+            .iter()
+            .skip_while(|event| event.lsn < from)
+            .take(count)
+            .cloned()
+            .collect();
+        let next = events
+            .last()
+            .map_or(from.max(oldest), |event| event.lsn + 1);
+        Ok((events, next))
+    }
+}
+
+/// Rewrites the file with only `events`, through a temporary file so a crash
+/// can't lose the ones kept, returning it opened for appending and its size.
+fn compact(path: &Path, events: &VecDeque<Event>) -> io::Result<(fs::File, u64)> {
+    let mut temporary = path.as_os_str().to_os_string();
+    temporary.push(".tmp");
+    let mut body = String::new();
+    for event in events {
+        body.push_str(&serde_json::to_string(event)?);
+        body.push('\n');
+    }
+    fs::write(&temporary, &body)?;
+    // Opened before the rename, so the handle is never left on a file that
+    // has been replaced.
+    let file = fs::OpenOptions::new().append(true).open(&temporary)?;
+    fs::rename(&temporary, path)?;
+    Ok((file, body.len() as u64))
+}
+
+pub static CHANGES: Mutex<Option<Log>> = Mutex::new(None);
+
+pub fn install(log: Log) {
+    *CHANGES.lock().unwrap() = Some(log);
+}
+
+/// Adds `change` to the feed if it is enabled. Called by
+/// `state::Database::record`, so events are in the order changes were made.
+pub fn record(change: &Change) {
+    if let Some(log) = CHANGES.lock().unwrap().as_mut() {
+        if let Err(e) = log.record(change) {
+            error!(
+                "unable to write to {}; error = {:?}",
+                log.path().display(),
+                e
+            );
+        }
+    }
+}
+
+/// What `CHANGES FROM` answers with: the events, and the lsn to ask for to
+/// carry on after them.
+pub fn read(from: u64, count: usize) -> Result<(Vec<Event>, u64), String> {
+    match CHANGES.lock().unwrap().as_ref() {
+        Some(log) => log.read(from, count),
+        None => Err("the change feed is not enabled".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::state::Message;
+
+    fn settings(name: &str, max_len: usize) -> settings::Changes {
+        let path = std::env::temp_dir().join(format!(
+            "merkava-changes-{}-{}.log",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        settings::Changes {
+            enabled: true,
+            path: path.to_str().unwrap().to_string(),
+            max_len,
+        }
+    }
+
+    fn push(channel: &str, uid: &str) -> Change {
+        Change::Push {
+            channel: channel.to_string(),
+            message: Message {
+                uid: uid.to_string(),
+                created: Utc::now(),
+                value: format!("value of {}", uid),
+            },
+            key: None,
+        }
+    }
+
+    #[test]
+    fn log_reads_from_an_lsn_and_carries_on_after_reopening() {
+        let settings = settings("resume", 100);
+        let mut log = Log::open(&settings).unwrap();
+        log.record(&push("room", "a")).unwrap();
+        log.record(&Change::Update {
+            channel: "room".to_string(),
+            uid: "a".to_string(),
+            value: "changed".to_string(),
+        })
+        .unwrap();
+        log.record(&Change::Flush {
+            channel: "room".to_string(),
+            archive: false,
+        })
+        .unwrap();
+
+        let (events, next) = log.read(0, 2).unwrap();
+        assert_eq!(events[0].lsn, 1);
+        assert_eq!(events[0].op, "push");
+        assert_eq!(events[0].value.as_deref(), Some("value of a"));
+        assert_eq!(events[1].op, "update");
+        assert_eq!(events[1].uid.as_deref(), Some("a"));
+        assert_eq!(next, 3);
+        let (events, next) = log.read(next, 10).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].op, "flush");
+        assert_eq!(next, 4);
+        assert!(log.read(next, 10).unwrap().0.is_empty());
+
+        drop(log);
+        let mut log = Log::open(&settings).unwrap();
+        log.record(&push("lobby", "b")).unwrap();
+        let (events, next) = log.read(4, 10).unwrap();
+        assert_eq!(events[0].lsn, 4);
+        assert_eq!(events[0].channel, "lobby");
+        assert_eq!(next, 5);
+    }
+
+    #[test]
+    fn log_only_hands_out_lsns_that_were_written() {
+        let settings = settings("failed-write", 100);
+        let mut log = Log::open(&settings).unwrap();
+        log.record(&push("room", "a")).unwrap();
+        log.file = fs::File::open(&settings.path).unwrap();
+        assert!(log.record(&push("room", "b")).is_err());
+        assert_eq!(log.read(0, 10).unwrap().1, 2);
+
+        drop(log);
+        let mut log = Log::open(&settings).unwrap();
+        log.record(&push("room", "c")).unwrap();
+        let (events, _) = log.read(0, 10).unwrap();
+        let uids: Vec<_> = events.iter().map(|event| (event.lsn, event.uid.clone())).collect();
+        assert_eq!(uids, vec![(1, Some("a".to_string())), (2, Some("c".to_string()))]);
+    }
+
+    #[test]
+    fn log_drops_a_torn_last_line() {
+        let settings = settings("torn", 100);
+        let mut log = Log::open(&settings).unwrap();
+        log.record(&push("room", "a")).unwrap();
+        drop(log);
+        let mut file = fs::OpenOptions::new().append(true).open(&settings.path).unwrap();
+        file.write_all(b"{\"lsn\":2,\"at\":").unwrap();
+
+        let mut log = Log::open(&settings).unwrap();
+        log.record(&push("room", "b")).unwrap();
+        assert_eq!(log.read(0, 10).unwrap().0.len(), 2);
+        drop(log);
+        Log::open(&settings).unwrap();
+
+        let body = fs::read_to_string(&settings.path).unwrap();
+        fs::write(&settings.path, format!("{{\"lsn\":\n{}", body)).unwrap();
+        let error = Log::open(&settings).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains(":1:"), "{}", error);
+    }
+
+    #[test]
+    fn log_keeps_only_the_newest_events() {
+        let settings = settings("trim", 2);
+        let mut log = Log::open(&settings).unwrap();
+        for uid in &["a", "b", "c"] {
+            log.record(&push("room", uid)).unwrap();
+        }
+        assert_eq!(
+            log.read(1, 10).unwrap_err(),
+            "TRIMMED the oldest change kept is 2"
+        );
+        assert_eq!(log.read(2, 10).unwrap().0.len(), 2);
+        assert_eq!(log.read(0, 10).unwrap().0[0].lsn, 2);
+
+        drop(log);
+        let body = fs::read_to_string(&settings.path).unwrap();
+        assert_eq!(body.lines().count(), 3);
+        Log::open(&settings).unwrap();
+        let body = fs::read_to_string(&settings.path).unwrap();
+        assert_eq!(body.lines().count(), 2);
+    }
+
+    #[test]
+    fn log_compacts_the_file_as_it_grows() {
+        let settings = settings("compact", 2);
+        let mut log = Log::open(&settings).unwrap();
+        for uid in 0..9 {
+            log.record(&push("room", &uid.to_string())).unwrap();
+            let body = fs::read_to_string(&settings.path).unwrap();
+            assert!(body.lines().count() < 4, "{}", body);
+        }
+        log.record(&push("room", "last")).unwrap();
+        let (events, next) = log.read(0, 10).unwrap();
+        assert_eq!((events[0].lsn, next), (9, 11));
+
+        drop(log);
+        let mut log = Log::open(&settings).unwrap();
+        log.record(&push("room", "after")).unwrap();
+        assert_eq!(log.read(0, 10).unwrap().0[1].lsn, 11);
+    }
+}
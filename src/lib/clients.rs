@@ -0,0 +1,175 @@
+use futures::sync::oneshot;
+use futures::Future;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// A connection as the registry sees it.
+struct Client {
+    kind: &'static str,
+    addr: Option<String>,
+    connected: Instant,
+    last_active: Instant,
+    commands: u64,
+    channel: Option<String>,
+    kill: Option<oneshot::Sender<()>>,
+}
+
+/// What `CLIENT LIST` reports for one connection. `age` and `idle` are in
+/// seconds.
+#[derive(Serialize, Debug, Clone)]
+pub struct Info {
+    pub id: u64,
+    pub kind: &'static str,
+    pub addr: Option<String>,
+    pub age: u64,
+    pub idle: u64,
+    pub commands: u64,
+    pub channel: Option<String>,
+}
+
+/// Every open client connection, by session id.
+pub struct Registry {
+    clients: Mutex<BTreeMap<u64, Client>>,
+    total: AtomicU64,
+}
+
+pub static CLIENTS: Registry = Registry::new();
+
+impl Registry {
+    pub const fn new() -> Registry {
+        Registry {
+            clients: Mutex::new(BTreeMap::new()),
+            total: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds a connection, returning what resolves when it is killed.
+    pub fn register(
+        &self,
+        id: u64,
+        kind: &'static str,
+        addr: Option<String>,
+    ) -> oneshot::Receiver<()> {
+        let (kill, killed) = oneshot::channel();
+        let now = Instant::now();
+        let client = Client {
+            kind,
+            addr,
+            connected: now,
+            last_active: now,
+            commands: 0,
+            channel: None,
+            kill: Some(kill),
+        };
+        self.clients.lock().unwrap().insert(id, client);
+        self.total.fetch_add(1, Ordering::Relaxed);
+        killed
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    /// Notes a command from `id`, and the channel it was for.
+    pub fn touch(&self, id: u64, channel: Option<&str>) {
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            client.last_active = Instant::now();
+            client.commands += 1;
+            if let Some(channel) = channel {
+                client.channel = Some(channel.to_string());
+            }
+        }
+    }
+
+    /// Closes connection `id`, returning false if there is no such client.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.clients.lock().unwrap().get_mut(&id) {
+            Some(client) => {
+                if let Some(kill) = client.kill.take() {
+                    let _ = kill.send(());
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn list(&self) -> Vec<Info> {
+        let now = Instant::now();
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, client)| Info {
+                id: *id,
+                kind: client.kind,
+                addr: client.addr.clone(),
+                age: (now - client.connected).as_secs(),
+                idle: (now - client.last_active).as_secs(),
+                commands: client.commands,
+                channel: client.channel.clone(),
+            })
+            .collect()
+    }
+
+    /// Open connections by kind.
+    pub fn counts(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for client in self.clients.lock().unwrap().values() {
+            *counts.entry(client.kind).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    pub fn connected(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// Connections accepted since the server started.
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+}
+
+/// Runs `connection` until it finishes or is killed with `CLIENT KILL`,
+/// dropping it, and so closing its socket, in the second case.
+pub fn killable<F>(
+    connection: F,
+    killed: oneshot::Receiver<()>,
+) -> impl Future<Item = (), Error = ()>
+where
+    F: Future<Item = (), Error = ()>,
+{
+    connection.select2(killed).then(|_| Ok(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_tracks_and_kills_clients() {
+        let registry = Registry::new();
+        let killed = registry.register(1, "tcp", Some("127.0.0.1:5000".to_string()));
+        let _connected = registry.register(2, "resp", None);
+        registry.touch(1, Some("room-1"));
+        registry.touch(1, None);
+
+        let clients = registry.list();
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0].commands, 2);
+        assert_eq!(clients[0].channel.as_ref().unwrap(), "room-1");
+        assert_eq!(registry.counts()["resp"], 1);
+
+        assert!(registry.kill(1));
+        assert_eq!(killed.wait(), Ok(()));
+        assert!(!registry.kill(3));
+
+        registry.unregister(1);
+        assert_eq!(registry.connected(), 1);
+        assert_eq!(registry.total(), 2);
+    }
+}
@@ -0,0 +1,440 @@
+use crate::lib::{conf, operations, replication, state, types};
+use futures::future;
+use futures::stream::iter_ok;
+use futures::{Future, Stream};
+use ring::digest;
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{lines, write_all, AsyncRead};
+use tokio::net::TcpStream;
+use tokio::timer::{Interval, Timeout};
+
+const VNODES: u32 = 64;
+/// How often a node looks for channels it no longer owns.
+const REBALANCE: Duration = Duration::from_secs(1);
+const MIGRATE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where a channel id falls on the ring: the first 8 bytes of its SHA-256,
+/// read big-endian.
+pub fn hash(key: &str) -> u64 {
+    let digest = digest::digest(&digest::SHA256, key.as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest.as_ref()[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Consistent hashing over the nodes in `cluster.nodes`. Each node is put
+/// on the ring `vnodes` times, at the hash of `<addr>#<n>`, and owns the
+/// channels that hash to just before each of its points, wrapping round.
+/// Adding or removing a node only moves the channels next to its points.
+#[derive(Debug)]
+pub struct HashRing {
+    nodes: Vec<String>,
+    vnodes: u32,
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    pub fn new(nodes: Vec<String>, vnodes: u32) -> HashRing {
+        let mut points: Vec<(u64, usize)> = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, node)| (0..vnodes).map(move |n| (hash(&format!("{}#{}", node, n)), i)))
+            .collect();
+        points.sort_unstable();
+        HashRing {
+            nodes,
+            vnodes,
+            points,
+        }
+    }
+
+    pub fn owner(&self, channel_id: &str) -> &str {
+        let at = hash(channel_id);
+        let next = self.points.partition_point(|(point, _)| *point < at);
+        let (_, node) = self.points[next % self.points.len()];
+        &self.nodes[node]
+    }
+
+    /// What `CLUSTER SLOTS` reports: the ranges of hashes, inclusive, and
+    /// the node owning each, in order from 0 to `u64::MAX`.
+    pub fn slots(&self) -> Value {
+        let mut slots: Vec<(u64, u64, usize)> = Vec::new();
+        let mut start = 0;
+        for &(point, node) in &self.points {
+            match slots.last_mut() {
+                Some(last) if last.2 == node => last.1 = point,
+                _ => slots.push((start, point, node)),
+            }
+            start = point.wrapping_add(1);
+        }
+        // Past the last point, hashes wrap round to the first point's node.
+        if let Some(&(last, _)) = self.points.last() {
+            if last < u64::MAX {
+                match slots.last_mut() {
+                    Some(slot) if slot.2 == self.points[0].1 => slot.1 = u64::MAX,
+                    _ => slots.push((last + 1, u64::MAX, self.points[0].1)),
+                }
+            }
+        }
+        let slots: Vec<Value> = slots
+            .into_iter()
+            .map(|(start, end, node)| json!({"start": start, "end": end, "node": self.nodes[node]}))
+            .collect();
+        json!({
+            "hash": "sha256",
+            "nodes": self.nodes,
+            "vnodes": self.vnodes,
+            "slots": slots,
+        })
+    }
+}
+
+/// The ring for the current `cluster.nodes`, kept until they change.
+pub struct Rings {
+    current: Mutex<Option<Arc<HashRing>>>,
+}
+
+pub static RINGS: Rings = Rings::new();
+
+impl Rings {
+    pub const fn new() -> Rings {
+        Rings {
+            current: Mutex::new(None),
+        }
+    }
+
+    /// The ring, or `None` when cluster mode is off.
+    pub fn get(&self, conf: &config::Config) -> Option<Arc<HashRing>> {
+        if !conf.get::<bool>("cluster.enabled").unwrap_or(false) {
+            return None;
+        }
+        let nodes = conf.get::<Vec<String>>("cluster.nodes").unwrap_or_default();
+        let vnodes = conf.get::<u32>("cluster.vnodes").unwrap_or(VNODES);
+        let mut current = self.current.lock().unwrap();
+        match *current {
+            Some(ref ring) if ring.nodes == nodes && ring.vnodes == vnodes => Some(ring.clone()),
+            _ if nodes.is_empty() => None,
+            _ => {
+                let ring = Arc::new(HashRing::new(nodes, vnodes));
+                *current = Some(ring.clone());
+                Some(ring)
+            }
+        }
+    }
+}
+
+/// Channels on their way to another node. Requests for them are refused
+/// with `TRYAGAIN` until the move is confirmed or undone, so the new owner
+/// gets none of their writes before it has taken the channel.
+static MOVING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+fn moving(channel_id: &str) -> Option<String> {
+    match MOVING.lock().unwrap().contains(channel_id) {
+        true => Some(format!("TRYAGAIN {} is moving to another node", channel_id)),
+        false => None,
+    }
+}
+
+/// This node's address as it appears in `cluster.nodes`.
+pub fn address(conf: &config::Config) -> String {
+    conf.get::<String>("cluster.address")
+        .or_else(|_| conf.get::<String>("network.address"))
+        .unwrap_or_default()
+}
+
+/// The node to send requests for `channel_id` to instead, if it isn't this
+/// one. A channel still held here is served here until it has moved.
+fn moved(db: &state::Database, conf: &config::Config, channel_id: &str) -> Option<String> {
+    let ring = RINGS.get(conf)?;
+    let owner = ring.owner(channel_id);
+    if owner == address(conf) || db.channels.lock().unwrap().contains_key(channel_id) {
+        return None;
+    }
+    Some(owner.to_string())
+}
+
+/// Refuses requests for channels owned by another node with `MOVED <addr>`,
+/// so clients can retry there, and for channels being moved with `TRYAGAIN`.
+pub fn check(
+    db: &state::Database,
+    conf: &config::Config,
+    request: &types::Request,
+) -> Result<(), String> {
+    let channel_id = match request.channel_id() {
+        Some("*") | None => return Ok(()),
+        Some(channel_id) => channel_id,
+    };
+    // Look for the channel before checking whether it is moving: it is
+    // only taken out once marked, and only unmarked once put back or gone.
+    let owner = moved(db, conf, channel_id);
+    if let Some(refused) = moving(channel_id) {
+        return Err(refused);
+    }
+    if let Some(owner) = owner {
+        return Err(format!("MOVED {}", owner));
+    }
+    match *request {
+        types::Request::Rename { ref target_id, .. }
+        | types::Request::Copy { ref target_id, .. } => {
+            let owner = moved(db, conf, target_id);
+            match (moving(target_id), owner) {
+                (Some(refused), _) => Err(refused),
+                (None, Some(owner)) => Err(format!("CROSSNODE {} belongs to {}", target_id, owner)),
+                (None, None) => Ok(()),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn slots(conf: &config::Config) -> types::Response {
+    match RINGS.get(conf) {
+        Some(ring) => types::Response::Stats {
+            stats: ring.slots(),
+        },
+        None => types::Response::Error {
+            message: "cluster mode is not enabled".to_string(),
+        },
+    }
+}
+
+fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Sends `line` to `owner`, authenticating first with `cluster.token` when
+/// it is set, and returns the answer to it. Only failing to get one is an
+/// error; an `ER` answer is returned like any other.
+fn ask(
+    owner: &str,
+    token: Option<String>,
+    line: String,
+) -> impl Future<Item = String, Error = io::Error> {
+    let mut request = String::new();
+    if let Some(token) = token {
+        request.push_str(&format!("AUTH {}\n", token));
+    }
+    let expected = request.lines().count() + 1;
+    request.push_str(&line);
+    request.push('\n');
+    let sent = future::result(owner.parse::<SocketAddr>().map_err(invalid))
+        .and_then(|addr| TcpStream::connect(&addr))
+        .and_then(move |stream| write_all(stream, request.into_bytes()))
+        .and_then(move |(stream, _)| {
+            let (reader, _) = stream.split();
+            lines(BufReader::new(reader))
+                .take(expected as u64)
+                .collect()
+        })
+        .and_then(move |mut answers: Vec<String>| {
+            if answers.len() < expected {
+                return Err(invalid("connection closed"));
+            }
+            let answer = answers.pop().unwrap();
+            match answers.into_iter().find(|answer| !answer.starts_with("OK")) {
+                Some(refused) => Err(invalid(refused)),
+                None => Ok(answer),
+            }
+        });
+    Timeout::new(sent, MIGRATE_TIMEOUT).map_err(|e| {
+        e.into_inner()
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+    })
+}
+
+/// Asks `owner` whether an `IMPORT` whose answer was lost arrived, by
+/// looking for the newest message it carried.
+fn imported(
+    owner: &str,
+    token: Option<String>,
+    channel_id: &str,
+    export: &state::Export,
+) -> impl Future<Item = bool, Error = ()> {
+    let newest = match export.messages.last() {
+        Some(message) => message.uid.clone(),
+        None => return future::Either::A(future::ok(false)),
+    };
+    let line = format!("{} RETRIEVE {}", channel_id, newest);
+    let channel_id = channel_id.to_string();
+    future::Either::B(ask(owner, token, line).then(move |answer| match answer {
+        Ok(answer) => Ok(answer.starts_with("OK")),
+        Err(e) => {
+            warn!("Unable to ask whether {} arrived: {}", channel_id, e);
+            Ok(false)
+        }
+    }))
+}
+
+/// Moves one channel this node holds but no longer owns, in three steps.
+/// It is taken out and sent with `IMPORT`, and requests for it are refused
+/// with `TRYAGAIN` meanwhile. The move is confirmed by the owner's answer,
+/// or, when that is lost, by asking the owner for the channel's newest
+/// message. Only then is the backup here flushed and requests sent to the
+/// owner; a move that wasn't confirmed puts the channel back, and nothing
+/// was written to the owner in between.
+fn migrate(
+    db: &Arc<state::Database>,
+    shared: &conf::Shared,
+    channel_id: String,
+    owner: String,
+) -> impl Future<Item = (), Error = ()> {
+    MOVING.lock().unwrap().insert(channel_id.clone());
+    let export = operations::take_channel(db, &channel_id).unwrap_or_default();
+    let token = shared.read().unwrap().get::<String>("cluster.token").ok();
+    let db = db.clone();
+    let shared = shared.clone();
+    let line = format!(
+        "{} IMPORT {}",
+        channel_id,
+        serde_json::to_string(&export).unwrap()
+    );
+    ask(&owner, token.clone(), line)
+        .then({
+            let (owner, channel_id, export) = (owner.clone(), channel_id.clone(), export.clone());
+            move |answer| match answer {
+                Ok(ref answer) if answer.starts_with("OK") => future::Either::A(future::ok(true)),
+                Ok(answer) => {
+                    warn!("{} refused {}: {}", owner, channel_id, answer);
+                    future::Either::A(future::ok(false))
+                }
+                Err(e) => {
+                    warn!("No answer moving {} to {}: {}", channel_id, owner, e);
+                    future::Either::B(imported(&owner, token, &channel_id, &export))
+                }
+            }
+        })
+        .map(move |confirmed| {
+            let conf = shared.read().unwrap();
+            if !confirmed {
+                warn!("Keeping {}; it will be moved to {} later", channel_id, owner);
+                operations::restore_channel(&db, &channel_id, export);
+                MOVING.lock().unwrap().remove(&channel_id);
+                return;
+            }
+            info!(
+                "Moved {} ({} messages) to {}",
+                channel_id,
+                export.messages.len(),
+                owner
+            );
+            let change = replication::Change::Flush {
+                channel: channel_id.clone(),
+                archive: false,
+            };
+            if let types::Response::Error { message } = operations::apply(&db, &conf, change) {
+                error!("unable to remove moved channel: {}", message);
+            }
+            MOVING.lock().unwrap().remove(&channel_id);
+        })
+}
+
+/// Runs on every node: moves channels held here that the ring now gives to
+/// another node, one at a time, while the rest keep being served.
+/// Replication followers leave this to their leader.
+pub fn rebalancer(
+    db: &Arc<state::Database>,
+    shared: &conf::Shared,
+) -> impl Future<Item = (), Error = ()> {
+    let db = db.clone();
+    let shared = shared.clone();
+    Interval::new_interval(REBALANCE)
+        .map_err(|e| error!("cluster rebalancing failed: {}", e))
+        .for_each(move |_| {
+            let conf = shared.read().unwrap();
+            let ring = match RINGS.get(&conf) {
+                Some(ring) if db.replication.lock().unwrap().is_leader() => ring,
+                _ => return future::Either::A(future::ok(())),
+            };
+            let here = address(&conf);
+            let leaving: Vec<(String, String)> = db
+                .channels
+                .lock()
+                .unwrap()
+                .keys()
+                .map(|channel_id| (channel_id.clone(), ring.owner(channel_id).to_string()))
+                .filter(|(_, owner)| *owner != here)
+                .collect();
+            let db = db.clone();
+            let shared = shared.clone();
+            let moves = iter_ok(leaving)
+                .for_each(move |(channel_id, owner)| migrate(&db, &shared, channel_id, owner));
+            future::Either::B(moves)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(count: usize) -> Vec<String> {
+        (0..count)
+            .map(|i| format!("127.0.0.1:{}", 7000 + i))
+            .collect()
+    }
+
+    #[test]
+    fn ring_spreads_channels_and_moves_few_when_a_node_joins() {
+        let three = HashRing::new(nodes(3), VNODES);
+        let four = HashRing::new(nodes(4), VNODES);
+        let channels: Vec<String> = (0..1000).map(|i| format!("room-{}", i)).collect();
+        for node in nodes(3) {
+            let owned = channels.iter().filter(|c| three.owner(c) == node).count();
+            assert!(owned > 200 && owned < 470, "{} owns {}", node, owned);
+        }
+        let moved: Vec<&String> = channels
+            .iter()
+            .filter(|c| three.owner(c) != four.owner(c))
+            .collect();
+        assert!(moved.len() < 400, "{} moved", moved.len());
+        assert!(moved.iter().all(|c| four.owner(c) == "127.0.0.1:7003"));
+    }
+
+    #[test]
+    fn moving_channels_are_refused_until_the_move_settles() {
+        let db = crate::lib::testing::make_db();
+        let conf = config::Config::default();
+        let push = types::Request::Push {
+            channel_id: "moving-room".to_string(),
+            value: "hello".to_string(),
+            key: None,
+        };
+        let copy = types::Request::Copy {
+            channel_id: "other-room".to_string(),
+            target_id: "moving-room".to_string(),
+        };
+        MOVING.lock().unwrap().insert("moving-room".to_string());
+        assert!(check(&db, &conf, &push).unwrap_err().starts_with("TRYAGAIN"));
+        assert!(check(&db, &conf, &copy).unwrap_err().starts_with("TRYAGAIN"));
+        MOVING.lock().unwrap().remove("moving-room");
+        assert!(check(&db, &conf, &push).is_ok());
+    }
+
+    #[test]
+    fn slots_cover_every_hash_once() {
+        let ring = HashRing::new(nodes(3), 8);
+        let slots = ring.slots();
+        let slots = slots["slots"].as_array().unwrap();
+        assert_eq!(slots[0]["start"], 0);
+        assert_eq!(slots[slots.len() - 1]["end"], u64::MAX);
+        for pair in slots.windows(2) {
+            let end = pair[0]["end"].as_u64().unwrap();
+            assert_eq!(pair[1]["start"].as_u64().unwrap(), end + 1);
+            assert_ne!(pair[0]["node"], pair[1]["node"]);
+        }
+        for channel in &["alpha", "beta", "gamma", "delta"] {
+            let at = hash(channel);
+            let slot = slots
+                .iter()
+                .find(|slot| {
+                    slot["start"].as_u64().unwrap() <= at && at <= slot["end"].as_u64().unwrap()
+                })
+                .unwrap();
+            assert_eq!(slot["node"], ring.owner(channel));
+        }
+    }
+}
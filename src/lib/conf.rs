@@ -1,14 +1,254 @@
+use crate::lib::logging;
+use crate::lib::settings::Settings;
 use config;
+use futures::{Future, Stream};
+use std::sync::{Arc, RwLock};
+use tokio_signal::unix::{Signal, SIGHUP};
 
-pub fn get_conf(mrkvconf: String) -> config::Config {
-    let mut settings = config::Config::default();
-    let settings = settings
-        // Add in `./Settings.toml`
-        .merge(config::File::with_name(&mrkvconf))
-        .unwrap()
-        // Add in settings from the environment (with a prefix of MRKV)
-        // Eg.. `MRKV_DEBUG=1 ./target/MRKV` would set the `debug` key
-        .merge(config::Environment::with_prefix("MRKV"))
+/// The live configuration, shared by every connection so reloads and
+/// `CONFIG SET` reach requests already being served.
+pub type Shared = Arc<RwLock<config::Config>>;
+
+/// Keys `CONFIG SET` can change. A reload takes these, `[auth]` and
+/// `cluster.nodes` from the file; everything else, such as listener
+/// addresses, TLS and `persistence.path`, keeps its startup value.
+pub const RUNTIME_KEYS: &[&str] = &[
+    "logging.verbosity",
+    "persistence.interval",
+    "push.idempotency_window",
+    "shutdown.timeout",
+    "slowlog.threshold",
+    "slowlog.max_len",
+    "limits.recent",
+    "limits.list",
+    "limits.changes",
+    "retention.max_len",
+    "retention.max_age",
+];
+
+/// Where the configuration comes from: a file, which may be optional when
+/// the path is only the default, plus overrides from the command line.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub path: String,
+    pub required: bool,
+    pub overrides: Vec<(String, config::Value)>,
+}
+
+impl Source {
+    pub fn new(path: &str) -> Source {
+        Source {
+            path: path.to_string(),
+            required: true,
+            overrides: Vec::new(),
+        }
+    }
+
+    pub fn set<V: Into<config::Value>>(&mut self, key: &str, value: V) {
+        self.overrides.push((key.to_string(), value.into()));
+    }
+
+    fn merged(&self) -> Result<config::Config, String> {
+        let mut settings = config::Config::default();
+        settings
+            // Add in `./Settings.toml`
+            .merge(config::File::with_name(&self.path).required(self.required))
+            .map_err(|e| format!("{}: {}", self.path, e))?
+            // Add in settings from the environment (with a prefix of MRKV)
+            // Eg.. `MRKV_LOGGING__VERBOSITY=1 ./target/MRKV` would set `logging.verbosity`
+            .merge(config::Environment::with_prefix("MRKV").separator("__"))
+            .map_err(|e| format!("environment: {}", e))?;
+        for (key, value) in &self.overrides {
+            settings
+                .set(key, value.clone())
+                .map_err(|e| format!("command line: {}", e))?;
+        }
+        Ok(settings)
+    }
+
+    /// Reads and validates the file merged with any `MRKV_*` overrides and
+    /// then the command line's.
+    pub fn load_settings(&self) -> Result<Settings, String> {
+        Settings::from_config(&self.merged()?, &self.path)
+    }
+
+    #[cfg(test)]
+    pub fn load(&self) -> Result<config::Config, String> {
+        self.load_settings()?.to_config()
+    }
+}
+
+pub fn shared(conf: config::Config) -> Shared {
+    Arc::new(RwLock::new(conf))
+}
+
+fn apply(conf: &config::Config) {
+    if let Ok(verbosity) = conf.get::<u64>("logging.verbosity") {
+        logging::set_verbosity(verbosity);
+    }
+}
+
+/// Re-reads the file and takes its `RUNTIME_KEYS`, `[auth]` and
+/// `cluster.nodes`, keeping the current configuration if it can't be
+/// loaded. Values set with `CONFIG SET` are replaced by the file's.
+pub fn reload(shared: &Shared, source: &Source) -> Result<(), String> {
+    let loaded = source.load_settings()?;
+    let fresh = loaded.to_config()?;
+    let mut conf = shared.write().unwrap();
+    let mut settings = Settings::from_config(&conf, "current configuration")?;
+    settings.auth = loaded.auth;
+    settings.cluster.nodes = loaded.cluster.nodes;
+    let mut changed = settings.to_config()?;
+    for key in RUNTIME_KEYS {
+        let value = fresh.get::<config::Value>(key).map_err(|e| e.to_string())?;
+        changed.set(key, value).map_err(|e| e.to_string())?;
+    }
+    Settings::from_config(&changed, &source.path)?;
+    apply(&changed);
+    *conf = changed;
+    Ok(())
+}
+
+pub fn get(conf: &config::Config, key: &str) -> Result<String, String> {
+    if !RUNTIME_KEYS.contains(&key) {
+        return Err(format!("{} is not a runtime setting", key));
+    }
+    conf.get::<String>(key)
+        .map_err(|_| format!("{} is not set", key))
+}
+
+/// Changes one of `RUNTIME_KEYS`. They are all whole numbers, so anything
+/// else is rejected, as is a value the settings wouldn't load with.
+pub fn set(shared: &Shared, key: &str, value: &str) -> Result<(), String> {
+    if !RUNTIME_KEYS.contains(&key) {
+        return Err(format!("{} is not a runtime setting", key));
+    }
+    let value = value
+        .parse::<u64>()
+        .map_err(|_| format!("{} must be a whole number", key))?;
+    let mut conf = shared.write().unwrap();
+    let mut changed = conf.clone();
+    changed.set(key, value as i64).map_err(|e| e.to_string())?;
+    Settings::from_config(&changed, "CONFIG SET")?;
+    *conf = changed;
+    apply(&conf);
+    Ok(())
+}
+
+/// Reloads the file every time the process gets SIGHUP.
+pub fn reload_on_hangup(shared: &Shared, source: &Source) -> impl Future<Item = (), Error = ()> {
+    let shared = shared.clone();
+    let source = source.clone();
+    Signal::new(SIGHUP)
+        .flatten_stream()
+        .for_each(move |_| {
+            match reload(&shared, &source) {
+                Ok(_) => info!("Reloaded {}", source.path),
+                Err(e) => error!("Unable to reload {}: {}", source.path, e),
+            }
+            Ok(())
+        })
+        .map_err(|e| error!("failed to listen for SIGHUP; error = {:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write_conf(name: &str, body: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("merkava-conf-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, body).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn set_only_runtime_keys() {
+        let shared = shared(config::Config::default());
+        assert!(set(&shared, "persistence.interval", "30").is_ok());
+        assert_eq!(
+            get(&shared.read().unwrap(), "persistence.interval").unwrap(),
+            "30"
+        );
+        assert!(set(&shared, "persistence.interval", "soon").is_err());
+        assert!(set(&shared, "network.address", "0.0.0.0:1").is_err());
+        assert!(set(&shared, "limits.recent", "0").is_err());
+        assert!(set(&shared, "limits.recent", "50").is_ok());
+        assert!(get(&shared.read().unwrap(), "auth.tokens").is_err());
+    }
+
+    #[test]
+    fn reload_replaces_and_keeps_on_error() {
+        let path = write_conf("reload", "[persistence]\ninterval = 5\n");
+        let source = Source::new(&path);
+        let shared = shared(source.load().unwrap());
+        set(&shared, "persistence.interval", "30").unwrap();
+
+        fs::write(&path, "[persistence]\ninterval = 60\n").unwrap();
+        reload(&shared, &source).unwrap();
+        assert_eq!(
+            shared
+                .read()
+                .unwrap()
+                .get::<u64>("persistence.interval")
+                .unwrap(),
+            60
+        );
+
+        fs::write(&path, "[persistence\n").unwrap();
+        assert!(reload(&shared, &source).is_err());
+        assert_eq!(
+            shared
+                .read()
+                .unwrap()
+                .get::<u64>("persistence.interval")
+                .unwrap(),
+            60
+        );
+    }
+
+    #[test]
+    fn reload_keeps_startup_only_keys() {
+        let path = write_conf(
+            "reload-startup",
+            "[persistence]\npath = \"/tmp/merkava-before\"\ninterval = 5\n",
+        );
+        let source = Source::new(&path);
+        let shared = shared(source.load().unwrap());
+
+        fs::write(
+            &path,
+            "[persistence]\npath = \"/tmp/merkava-after\"\ninterval = 60\n\
+             [auth]\nenabled = true\n[auth.tokens.ops]\ntoken = \"secret\"\nadmin = [\"*\"]\n",
+        )
         .unwrap();
-    settings.clone()
+        reload(&shared, &source).unwrap();
+        let conf = shared.read().unwrap();
+        assert_eq!(
+            conf.get::<String>("persistence.path").unwrap(),
+            "/tmp/merkava-before"
+        );
+        assert_eq!(conf.get::<u64>("persistence.interval").unwrap(), 60);
+        assert!(conf.get::<bool>("auth.enabled").unwrap());
+        assert_eq!(
+            conf.get::<String>("auth.tokens.ops.token").unwrap(),
+            "secret"
+        );
+    }
+
+    #[test]
+    fn source_overrides_and_optional_file() {
+        let mut source = Source::new("/nonexistent/mrkvconf.toml");
+        assert!(source.load_settings().is_err());
+        source.required = false;
+        source.set("network.address", "0.0.0.0:7000");
+        source.set("logging.verbosity", 3);
+        let settings = source.load_settings().unwrap();
+        assert_eq!(settings.network.address, "0.0.0.0:7000");
+        assert_eq!(settings.logging.verbosity, 3);
+
+        source.set("network.address", "nowhere");
+        assert!(source.load_settings().unwrap_err().contains("network.address"));
+    }
 }
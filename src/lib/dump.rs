@@ -0,0 +1,162 @@
+use crate::lib::{operations, state, types};
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// One line of a dump: a message and the channel it belongs to. Lines for a
+/// channel are written oldest first, so restoring keeps their order.
+#[derive(Serialize, Deserialize, Debug)]
+struct Record {
+    channel: String,
+    #[serde(flatten)]
+    message: state::Message,
+}
+
+/// Writes every message in channels matching `pattern` as JSON lines,
+/// returning how many were written.
+pub fn dump<W: Write>(
+    db: &Arc<state::Database>,
+    pattern: &Pattern,
+    mut out: W,
+) -> io::Result<usize> {
+    let channels = db.channels.lock().unwrap();
+    let mut written = 0;
+    for (channel_id, channel) in channels.iter() {
+        if !pattern.matches(channel_id) {
+            continue;
+        }
+        for message in channel.data.lock().unwrap().iter() {
+            let record = Record {
+                channel: channel_id.clone(),
+                message: message.clone(),
+            };
+            serde_json::to_writer(&mut out, &record)?;
+            out.write_all(b"\n")?;
+            written += 1;
+        }
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+/// Rebuilds channels from a dump and writes them under `persistence.path`,
+/// returning how many channels were restored. Channels that already exist
+/// on disk are left alone unless `force` is set, and nothing is written if
+/// any line can't be read.
+pub fn restore<R: BufRead>(conf: &config::Config, input: R, force: bool) -> Result<usize, String> {
+    let mut channels: BTreeMap<String, Vec<state::Message>> = BTreeMap::new();
+    for (number, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| format!("line {}: {}", number + 1, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record =
+            serde_json::from_str(&line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+        types::check_channel_id(&record.channel)
+            .map_err(|e| format!("line {}: {}", number + 1, e))?;
+        channels
+            .entry(record.channel)
+            .or_default()
+            .push(record.message);
+    }
+
+    if !force {
+        let existing: Vec<&str> = channels
+            .keys()
+            .filter(|channel_id| match operations::channel_path(conf, channel_id) {
+                Ok(path) => Path::new(&path).exists(),
+                Err(_) => false,
+            })
+            .map(|channel_id| channel_id.as_str())
+            .collect();
+        if !existing.is_empty() {
+            return Err(format!(
+                "channels already exist, use --force to overwrite: {}",
+                existing.join(", ")
+            ));
+        }
+    }
+
+    for (channel_id, data) in &channels {
+        let index: HashMap<String, usize> = data
+            .iter()
+            .enumerate()
+            .map(|(position, message)| (message.uid.clone(), position))
+            .collect();
+        let channel = state::Channel::new(index, data.clone());
+        operations::channel_path(conf, channel_id)
+            .and_then(|path| operations::write_channel(&channel, &path))
+            .map_err(|e| format!("{}: {}", channel_id, e))?;
+    }
+    Ok(channels.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::testing::{make_conf, make_db};
+
+    fn make_dump() -> Vec<u8> {
+        let db = make_db();
+        let conf = config::Config::default();
+        let mut session = crate::lib::session::Session::new();
+        let conf = crate::lib::conf::shared(conf);
+        for line in &["foo PUSH one", "foo PUSH two", "bar PUSH three"] {
+            operations::handle_request(&db, &conf, &mut session, line.to_string());
+        }
+        let mut out = Vec::new();
+        assert_eq!(dump(&db, &Pattern::new("*").unwrap(), &mut out).unwrap(), 3);
+        out
+    }
+
+    #[test]
+    fn dump_and_restore_round_trip() {
+        let out = make_dump();
+        let conf = make_conf("dump-round-trip");
+        assert_eq!(restore(&conf, &out[..], false).unwrap(), 2);
+
+        let db = state::create_db(conf.get::<String>("persistence.path").unwrap());
+        let channels = db.channels.lock().unwrap();
+        let foo = channels.get("foo").unwrap();
+        let data = foo.data.lock().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[1].value, "two");
+        assert_eq!(foo.index.lock().unwrap()[&data[1].uid], 1);
+    }
+
+    #[test]
+    fn restore_refuses_existing_channels() {
+        let out = make_dump();
+        let conf = make_conf("dump-existing");
+        restore(&conf, &out[..], false).unwrap();
+        let error = restore(&conf, &out[..], false).unwrap_err();
+        assert!(error.contains("bar, foo"), "{}", error);
+        assert_eq!(restore(&conf, &out[..], true).unwrap(), 2);
+    }
+
+    #[test]
+    fn restore_reports_bad_lines() {
+        let conf = make_conf("dump-bad-line");
+        let error = restore(&conf, &b"{\"channel\": \"foo\"}\n"[..], false).unwrap_err();
+        assert!(error.starts_with("line 1:"), "{}", error);
+        assert!(!Path::new(&operations::channel_path(&conf, "foo").unwrap()).exists());
+    }
+
+    #[test]
+    fn restore_refuses_unsafe_channel_ids() {
+        let conf = make_conf("dump-unsafe");
+        let path = conf.get::<String>("persistence.path").unwrap();
+        let dump = format!(
+            "{}\n{}\n",
+            r#"{"channel":"foo","uid":"a","created":"2020-01-01T00:00:00Z","value":"x"}"#,
+            r#"{"channel":"../escaped","uid":"b","created":"2020-01-01T00:00:00Z","value":"y"}"#
+        );
+        let error = restore(&conf, dump.as_bytes(), false).unwrap_err();
+        assert_eq!(error, "line 2: invalid channel_id: \"../escaped\"");
+        assert!(!Path::new(&path).exists());
+        assert!(!Path::new(&path).join("../escaped").exists());
+    }
+}
@@ -0,0 +1,599 @@
+use crate::lib::{auth, conf, operations, session, state, types};
+use futures::{future, Future, Stream};
+use hyper::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// The largest request body the gateway reads, answered with `413` past it.
+const MAX_BODY_LENGTH: usize = 8 * 1024 * 1024;
+
+/// The parts of an HTTP request the gateway routes on, so routing can be
+/// exercised without a socket.
+pub struct Call<'a> {
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+    pub authorization: Option<&'a str>,
+    pub json: bool,
+    pub body: &'a [u8],
+    pub peer: Option<SocketAddr>,
+}
+
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = input.get(i + 1..i + 3)?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query?
+        .split('&')
+        .filter_map(|pair| {
+            let mut pair = pair.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(key), Some(value)) if key == name => percent_decode(value),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+fn query_number(query: Option<&str>, name: &str, default: usize) -> Result<usize, String> {
+    match query_param(query, name) {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| format!("{} must be a number", name)),
+        None => Ok(default),
+    }
+}
+
+fn body_value(call: &Call) -> Result<String, String> {
+    let body =
+        String::from_utf8(call.body.to_vec()).map_err(|_| "body must be UTF-8".to_string())?;
+    if !call.json {
+        return Ok(body);
+    }
+    match serde_json::from_str::<Value>(&body) {
+        Ok(Value::Object(ref object)) => match object.get("value") {
+            Some(Value::String(value)) => Ok(value.clone()),
+            _ => Err("JSON body needs a string \"value\"".to_string()),
+        },
+        _ => Err("body is not a JSON object".to_string()),
+    }
+}
+
+fn error(status: StatusCode, message: String) -> (StatusCode, Option<Value>) {
+    (status, Some(serde_json::json!({ "error": message })))
+}
+
+fn error_status(message: &str, fallback: StatusCode) -> StatusCode {
+    if message.starts_with("NOAUTH") {
+        StatusCode::UNAUTHORIZED
+    } else if message.starts_with("NOPERM") {
+        StatusCode::FORBIDDEN
+    } else if message == "No messages found" || message == "uid not found" {
+        StatusCode::NOT_FOUND
+    } else if message.starts_with("MOVED ") {
+        StatusCode::MISDIRECTED_REQUEST
+    } else if message.starts_with("READONLY")
+        || message.starts_with("NOTACKED")
+        || message.starts_with("TRYAGAIN")
+        || message == "server is shutting down"
+    {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        fallback
+    }
+}
+
+fn to_http(response: types::Response, fallback: StatusCode) -> (StatusCode, Option<Value>) {
+    match response {
+        types::Response::Push { message } => {
+            (StatusCode::CREATED, serde_json::to_value(message).ok())
+        }
+        types::Response::Recent { messages } => {
+            (StatusCode::OK, serde_json::to_value(messages).ok())
+        }
+        types::Response::Retrieve { message } => {
+            (StatusCode::OK, serde_json::to_value(message).ok())
+        }
+        types::Response::Stats { stats } => (StatusCode::OK, Some(stats)),
+        types::Response::List { cursor, channels } => (
+            StatusCode::OK,
+            Some(serde_json::json!({ "cursor": cursor, "channels": channels })),
+        ),
+        types::Response::Value { value } => (StatusCode::OK, Some(Value::String(value))),
+        types::Response::Slowlog { entries } => {
+            (StatusCode::OK, serde_json::to_value(entries).ok())
+        }
+        types::Response::Clients { clients } => {
+            (StatusCode::OK, serde_json::to_value(clients).ok())
+        }
+        types::Response::Changes { changes, next } => (
+            StatusCode::OK,
+            Some(serde_json::json!({ "changes": changes, "next": next })),
+        ),
+        types::Response::Killed { killed: true, .. } => (StatusCode::NO_CONTENT, None),
+        types::Response::Killed { id, killed: false } => {
+            error(StatusCode::NOT_FOUND, format!("no such client: {}", id))
+        }
+        types::Response::Done {} => (StatusCode::NO_CONTENT, None),
+        types::Response::Error { message } => match message.strip_prefix("MOVED ") {
+            Some(owner) => (
+                error_status(&message, fallback),
+                Some(serde_json::json!({ "error": message, "owner": owner })),
+            ),
+            None => error(error_status(&message, fallback), message),
+        },
+    }
+}
+
+fn route(call: &Call) -> Result<(types::Request, StatusCode), (StatusCode, Option<Value>)> {
+    let segments: Vec<&str> = call.path.trim_matches('/').split('/').collect();
+    let decode = |segment: &str| {
+        percent_decode(segment).ok_or_else(|| {
+            error(
+                StatusCode::BAD_REQUEST,
+                format!("invalid path segment {}", segment),
+            )
+        })
+    };
+    let bad_request = |message: String| error(StatusCode::BAD_REQUEST, message);
+    // Checked after decoding, since `%2F` and `.` decode to a path.
+    let channel = |segment: &str| {
+        let channel_id = decode(segment)?;
+        types::check_channel_id(&channel_id).map_err(bad_request)?;
+        Ok(channel_id)
+    };
+
+    let request = match (call.method, segments.as_slice()) {
+        (&Method::POST, ["channels", channel_id, "messages"]) => types::Request::Push {
+            channel_id: channel(channel_id)?,
+            value: body_value(call).map_err(bad_request)?,
+            key: None,
+        },
+        (&Method::GET, ["channels", channel_id, "messages"]) => types::Request::Recent {
+            channel_id: channel(channel_id)?,
+            count: query_number(call.query, "count", 5).map_err(bad_request)?,
+            offset: query_number(call.query, "offset", 0).map_err(bad_request)?,
+        },
+        (&Method::GET, ["channels", channel_id, "messages", uid]) => types::Request::Retrieve {
+            channel_id: channel(channel_id)?,
+            uid: decode(uid)?,
+        },
+        (&Method::PUT, ["channels", channel_id, "messages", uid]) => types::Request::Update {
+            channel_id: channel(channel_id)?,
+            uid: decode(uid)?,
+            value: body_value(call).map_err(bad_request)?,
+        },
+        (&Method::DELETE, ["channels", channel_id]) => {
+            let request = types::Request::Flush {
+                channel_id: channel(channel_id)?,
+                archive: query_param(call.query, "archive").is_some_and(|a| a == "true"),
+            };
+            return Ok((request, StatusCode::INTERNAL_SERVER_ERROR));
+        }
+        (&Method::GET, ["channels", channel_id, "stats"]) => types::Request::Stats {
+            channel_id: channel(channel_id)?,
+        },
+        (_, ["channels", ..]) => {
+            return Err(error(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method not allowed".to_string(),
+            ));
+        }
+        _ => return Err(error(StatusCode::NOT_FOUND, "not found".to_string())),
+    };
+    Ok((request, StatusCode::BAD_REQUEST))
+}
+
+/// Maps one HTTP call onto the same operations as the line protocol,
+/// returning the status and JSON body to send back.
+pub fn respond(
+    db: &Arc<state::Database>,
+    conf: &conf::Shared,
+    call: &Call,
+) -> (StatusCode, Option<Value>) {
+    let mut session = session::Session::new();
+    session.addr = call.peer.map(|peer| peer.to_string());
+    if let Some(authorization) = call.authorization {
+        let token = match authorization.starts_with("Bearer ") {
+            true => &authorization[7..],
+            false => {
+                return error(
+                    StatusCode::UNAUTHORIZED,
+                    "expected a Bearer token".to_string(),
+                )
+            }
+        };
+        let authenticated = auth::authenticate(&conf.read().unwrap(), token);
+        match authenticated {
+            Ok(grants) => session.grants = Some(grants),
+            Err(message) => return error(StatusCode::UNAUTHORIZED, message),
+        }
+    }
+
+    let (request, fallback) = match route(call) {
+        Ok(routed) => routed,
+        Err(response) => return response,
+    };
+    to_http(
+        operations::dispatch(db, conf, &mut session, request),
+        fallback,
+    )
+}
+
+fn handle(
+    db: &Arc<state::Database>,
+    conf: &conf::Shared,
+    peer: SocketAddr,
+    req: Request<Body>,
+) -> Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+    let (parts, body) = req.into_parts();
+    let length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if length.is_some_and(|length| length > MAX_BODY_LENGTH as u64) {
+        return Box::new(future::ok(too_large()));
+    }
+    let db = db.clone();
+    let conf = conf.clone();
+    // `None` stops reading once the body passes `MAX_BODY_LENGTH`.
+    let read = body.map_err(Some).fold(Vec::new(), |mut body, chunk| {
+        if body.len() + chunk.len() > MAX_BODY_LENGTH {
+            return Err(None);
+        }
+        body.extend_from_slice(&chunk);
+        Ok(body)
+    });
+    Box::new(read.then(move |read| {
+        let body = match read {
+            Ok(body) => body,
+            Err(None) => return Ok(too_large()),
+            Err(Some(e)) => return Err(e),
+        };
+        let header = |name| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value: &hyper::header::HeaderValue| value.to_str().ok())
+        };
+        let call = Call {
+            method: &parts.method,
+            path: parts.uri.path(),
+            query: parts.uri.query(),
+            authorization: header(AUTHORIZATION),
+            json: header(CONTENT_TYPE).is_some_and(|t| t.starts_with("application/json")),
+            body: &body,
+            peer: Some(peer),
+        };
+        debug!("http request: {} {}", call.method, call.path);
+        let (status, json) = respond(&db, &conf, &call);
+        Ok(reply(status, json))
+    }))
+}
+
+fn reply(status: StatusCode, json: Option<Value>) -> Response<Body> {
+    let mut response = Response::builder();
+    response.status(status);
+    match json {
+        Some(json) => response
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json.to_string())),
+        None => response.body(Body::empty()),
+    }
+    .unwrap()
+}
+
+fn too_large() -> Response<Body> {
+    let message = format!("body is larger than {} bytes", MAX_BODY_LENGTH);
+    let (status, json) = error(StatusCode::PAYLOAD_TOO_LARGE, message);
+    reply(status, json)
+}
+
+/// Builds the optional HTTP gateway from the `[http]` section, or `None`
+/// when it is disabled.
+pub fn server(
+    db: &Arc<state::Database>,
+    shared: &conf::Shared,
+) -> Result<Option<Box<dyn Future<Item = (), Error = ()> + Send>>, String> {
+    let conf = shared.read().unwrap();
+    if !conf.get::<bool>("http.enabled").unwrap_or(false) {
+        return Ok(None);
+    }
+    let addr: SocketAddr = conf
+        .get::<String>("http.address")
+        .map_err(|_| "http.address is required when http.enabled is set".to_string())?
+        .parse()
+        .map_err(|e| format!("http.address is invalid: {}", e))?;
+    let builder = Server::try_bind(&addr).map_err(|e| format!("unable to bind {}: {}", addr, e))?;
+    info!("HTTP gateway listening on: {}", addr);
+
+    let db = db.clone();
+    let conf = shared.clone();
+    let server = builder
+        .serve(make_service_fn(move |socket: &AddrStream| {
+            let db = db.clone();
+            let conf = conf.clone();
+            let peer = socket.remote_addr();
+            future::ok::<_, hyper::Error>(service_fn(move |req| handle(&db, &conf, peer, req)))
+        }))
+        .map_err(|e| error!("http gateway error: {}", e));
+    Ok(Some(Box::new(server)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::testing::{make_conf, make_db};
+
+    fn call<'a>(
+        method: &'a Method,
+        path: &'a str,
+        query: Option<&'a str>,
+        body: &'a [u8],
+    ) -> Call<'a> {
+        Call {
+            method,
+            path,
+            query,
+            authorization: None,
+            json: false,
+            body,
+            peer: None,
+        }
+    }
+
+    #[test]
+    fn push_then_recent() {
+        let db = make_db();
+        let conf = conf::shared(make_conf("gateway-recent"));
+        let (status, json) = respond(
+            &db,
+            &conf,
+            &call(&Method::POST, "/channels/foo/messages", None, b"hello"),
+        );
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(json.unwrap()["value"], "hello");
+
+        let (status, json) = respond(
+            &db,
+            &conf,
+            &call(
+                &Method::GET,
+                "/channels/foo/messages",
+                Some("count=10"),
+                b"",
+            ),
+        );
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json.unwrap().as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn push_json_body() {
+        let db = make_db();
+        let conf = conf::shared(make_conf("gateway-json"));
+        let mut request = call(
+            &Method::POST,
+            "/channels/foo/messages",
+            None,
+            br#"{"value": "hi there"}"#,
+        );
+        request.json = true;
+        let (status, json) = respond(&db, &conf, &request);
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(json.unwrap()["value"], "hi there");
+
+        let mut request = call(&Method::POST, "/channels/foo/messages", None, b"[1, 2]");
+        request.json = true;
+        let (status, _) = respond(&db, &conf, &request);
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn retrieve_and_update() {
+        let db = make_db();
+        let conf = conf::shared(make_conf("gateway-update"));
+        let (_, json) = respond(
+            &db,
+            &conf,
+            &call(&Method::POST, "/channels/foo/messages", None, b"hello"),
+        );
+        let uid = json.unwrap()["uid"].as_str().unwrap().to_string();
+        let path = format!("/channels/foo/messages/{}", uid);
+
+        let (status, _) = respond(&db, &conf, &call(&Method::PUT, &path, None, b"changed"));
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, json) = respond(&db, &conf, &call(&Method::GET, &path, None, b""));
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json.unwrap()["value"], "changed");
+
+        let (status, _) = respond(
+            &db,
+            &conf,
+            &call(&Method::GET, "/channels/foo/messages/nope", None, b""),
+        );
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = respond(
+            &db,
+            &conf,
+            &call(&Method::PUT, "/channels/bar/messages/nope", None, b"x"),
+        );
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn stats_and_delete() {
+        let db = make_db();
+        let conf = conf::shared(make_conf("gateway-delete"));
+        respond(
+            &db,
+            &conf,
+            &call(&Method::POST, "/channels/room%201/messages", None, b"hello"),
+        );
+
+        let (status, json) = respond(
+            &db,
+            &conf,
+            &call(&Method::GET, "/channels/room%201/stats", None, b""),
+        );
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json.unwrap()["messages"], 1);
+
+        let (status, _) = respond(
+            &db,
+            &conf,
+            &call(&Method::DELETE, "/channels/room%201", None, b""),
+        );
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(db.channels.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unsafe_channel_ids_are_bad_requests() {
+        let db = make_db();
+        let conf = conf::shared(make_conf("gateway-unsafe"));
+        for path in &["/channels/..%2F..", "/channels/%2E%2E", "/channels/.", "/channels/a%5Cb"] {
+            let (status, json) = respond(&db, &conf, &call(&Method::DELETE, path, None, b""));
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", path);
+            let message = json.unwrap()["error"].as_str().unwrap().to_string();
+            assert!(message.starts_with("invalid channel_id"), "{}", message);
+        }
+        let (status, _) = respond(
+            &db,
+            &conf,
+            &call(&Method::POST, "/channels/..%2Fx/messages", None, b"hi"),
+        );
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn error_statuses() {
+        let db = make_db();
+        let conf = conf::shared(make_conf("gateway-errors"));
+        let (status, _) = respond(
+            &db,
+            &conf,
+            &call(&Method::GET, "/channels/foo/messages", None, b""),
+        );
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = respond(
+            &db,
+            &conf,
+            &call(&Method::POST, "/channels/foo/messages", None, b""),
+        );
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = respond(
+            &db,
+            &conf,
+            &call(&Method::GET, "/channels/foo/messages", Some("count=x"), b""),
+        );
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = respond(
+            &db,
+            &conf,
+            &call(&Method::PATCH, "/channels/foo", None, b""),
+        );
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        let (status, _) = respond(&db, &conf, &call(&Method::GET, "/elsewhere", None, b""));
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn cluster_and_availability_statuses() {
+        let fallback = StatusCode::BAD_REQUEST;
+        assert_eq!(
+            error_status("MOVED 10.0.0.2:6363", fallback),
+            StatusCode::MISDIRECTED_REQUEST
+        );
+        for message in &[
+            "READONLY this node is not the leader",
+            "NOTACKED a majority of nodes did not apply the change in time",
+            "server is shutting down",
+            "TRYAGAIN room is moving to another node",
+        ] {
+            assert_eq!(error_status(message, fallback), StatusCode::SERVICE_UNAVAILABLE);
+        }
+        let moved = types::Response::Error {
+            message: "MOVED 10.0.0.2:6363".to_string(),
+        };
+        let (status, json) = to_http(moved, fallback);
+        assert_eq!(status, StatusCode::MISDIRECTED_REQUEST);
+        assert_eq!(json.unwrap()["owner"], "10.0.0.2:6363");
+    }
+
+    #[test]
+    fn oversized_bodies_get_413() {
+        let db = make_db();
+        let conf = conf::shared(make_conf("gateway-too-large"));
+        let peer: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let send = |request: Request<Body>| handle(&db, &conf, peer, request).wait().unwrap();
+
+        let request = Request::post("/channels/foo/messages")
+            .header(CONTENT_LENGTH, (MAX_BODY_LENGTH + 1).to_string())
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(request).status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let chunks = vec![vec![b'a'; MAX_BODY_LENGTH / 2]; 3];
+        let chunked = futures::stream::iter_ok::<_, std::io::Error>(chunks);
+        let request = Request::post("/channels/foo/messages")
+            .body(Body::wrap_stream(chunked))
+            .unwrap();
+        assert_eq!(send(request).status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let request = Request::post("/channels/foo/messages")
+            .body(Body::from("small"))
+            .unwrap();
+        assert_eq!(send(request).status(), StatusCode::CREATED);
+    }
+
+    #[test]
+    fn auth_statuses() {
+        let db = make_db();
+        let mut conf = make_conf("gateway-auth");
+        conf.set("auth.enabled", true).unwrap();
+        conf.set("auth.tokens.reader.token", "reader-token")
+            .unwrap();
+        conf.set("auth.tokens.reader.read", vec!["*"]).unwrap();
+        let conf = conf::shared(conf);
+
+        let (status, _) = respond(
+            &db,
+            &conf,
+            &call(&Method::POST, "/channels/foo/messages", None, b"hi"),
+        );
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let mut request = call(&Method::POST, "/channels/foo/messages", None, b"hi");
+        request.authorization = Some("Bearer reader-token");
+        let (status, _) = respond(&db, &conf, &request);
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        request.authorization = Some("Bearer wrong");
+        let (status, _) = respond(&db, &conf, &request);
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,55 @@
+use crate::lib::{clients, metrics, operations, state};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+
+struct Started {
+    at: DateTime<Utc>,
+    config: Option<String>,
+}
+
+static STARTED: Mutex<Option<Started>> = Mutex::new(None);
+
+/// Records when the server started and which config file it read, for
+/// `INFO`.
+pub fn started(config: Option<String>) {
+    *STARTED.lock().unwrap() = Some(Started {
+        at: Utc::now(),
+        config,
+    });
+}
+
+/// What `INFO` reports: the server, its connections, what is held in
+/// memory and how far behind the disk is.
+pub fn report(db: &Arc<state::Database>, conf: &config::Config) -> Value {
+    let (started, config) = match *STARTED.lock().unwrap() {
+        Some(ref started) => (Some(started.at), started.config.clone()),
+        None => (None, None),
+    };
+    let stats = operations::server_stats(db);
+    json!({
+        "server": {
+            "version": env!("CARGO_PKG_VERSION"),
+            "started": started,
+            "uptime": started.map_or(0, |at| (Utc::now() - at).num_seconds()),
+            "config": config,
+        },
+        "connections": {
+            "connected": clients::CLIENTS.connected(),
+            "total": clients::CLIENTS.total(),
+            "by_kind": clients::CLIENTS.counts(),
+        },
+        "memory": {
+            "channels": stats.channels,
+            "messages": stats.messages,
+            "bytes": stats.bytes,
+        },
+        "persistence": {
+            "path": conf.get::<String>("persistence.path").ok(),
+            "interval": conf.get::<u64>("persistence.interval").unwrap_or(0),
+            "last_backup": stats.last_backup,
+            "last_snapshot": metrics::METRICS.last_snapshot(),
+            "dirty_channels": stats.dirty_channels,
+        },
+    })
+}
@@ -1,9 +1,193 @@
+use crate::lib::settings::{LogFormat, Logging};
 use fern::colors::{Color, ColoredLevelConfig};
-use std::io;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-pub fn setup_logging(verbosity: u64) -> Result<(), fern::InitError> {
-    let mut base_config = fern::Dispatch::new();
+static VERBOSITY: AtomicUsize = AtomicUsize::new(0);
 
+pub fn set_verbosity(verbosity: u64) {
+    VERBOSITY.store(verbosity as usize, Ordering::Relaxed);
+}
+
+fn allowed(verbosity: usize, metadata: &log::Metadata) -> bool {
+    let ours = metadata.target() == "merkava" || metadata.target().starts_with("merkava::");
+    let level = metadata.level();
+    match verbosity {
+        0 => level <= log::Level::Info,
+        1 => level <= log::Level::Info || (ours && level <= log::Level::Debug),
+        2 => level <= log::Level::Debug,
+        3 => level <= log::Level::Info || ours,
+        _4_or_more => true,
+    }
+}
+
+/// What a request log line carries besides its message, kept here while the
+/// line is being written so the JSON format can add them as fields.
+#[derive(Serialize, Debug, Clone)]
+pub struct Fields {
+    pub conn: u64,
+    pub channel: Option<String>,
+    pub command: &'static str,
+    pub latency_us: u64,
+    pub ok: bool,
+}
+
+thread_local! {
+    static FIELDS: RefCell<Option<Fields>> = const { RefCell::new(None) };
+}
+
+/// Logs one line per finished command at debug level, under the
+/// `merkava::request` target.
+pub fn request(fields: Fields) {
+    if !log_enabled!(target: "merkava::request", log::Level::Debug) {
+        return;
+    }
+    let message = format!(
+        "conn={} channel={} command={} latency={}us ok={}",
+        fields.conn,
+        fields.channel.as_deref().unwrap_or("-"),
+        fields.command,
+        fields.latency_us,
+        fields.ok
+    );
+    FIELDS.with(|current| *current.borrow_mut() = Some(fields));
+    debug!(target: "merkava::request", "{}", message);
+    FIELDS.with(|current| current.borrow_mut().take());
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    ts: String,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+    #[serde(flatten)]
+    fields: Option<Fields>,
+}
+
+fn json_line(message: &fmt::Arguments, record: &log::Record) -> String {
+    let line = JsonLine {
+        ts: chrono::Utc::now().to_rfc3339(),
+        level: record.level().as_str(),
+        target: record.target(),
+        message: message.to_string(),
+        fields: FIELDS.with(|current| current.borrow().clone()),
+    };
+    serde_json::to_string(&line).unwrap_or_default()
+}
+
+/// A log file that is moved aside to `<file>.1`, `<file>.2` and so on once
+/// it grows past `max_size` bytes or has been written to for `max_age`.
+/// Lines are held until fern flushes them so a rotation never splits one.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: fs::File,
+    pending: Vec<u8>,
+    size: u64,
+    opened: Instant,
+    max_size: u64,
+    max_age: Option<Duration>,
+    keep: usize,
+}
+
+impl RotatingFile {
+    pub fn open(settings: &Logging) -> io::Result<RotatingFile> {
+        let path = PathBuf::from(&settings.file);
+        let file = RotatingFile::append(&path)?;
+        Ok(RotatingFile {
+            size: file.metadata()?.len(),
+            path,
+            file,
+            pending: Vec::new(),
+            opened: Instant::now(),
+            max_size: settings.rotate_size,
+            max_age: match settings.rotate_age {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds)),
+            },
+            keep: settings.rotate_keep,
+        })
+    }
+
+    fn append(path: &Path) -> io::Result<fs::File> {
+        fs::OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn rotated(&self, number: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", number));
+        PathBuf::from(name)
+    }
+
+    fn due(&self, incoming: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let too_big = self.max_size > 0 && self.size + incoming as u64 > self.max_size;
+        let too_old = self.max_age.is_some_and(|age| self.opened.elapsed() >= age);
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for number in (1..self.keep).rev() {
+                match fs::rename(self.rotated(number), self.rotated(number + 1)) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                    moved => moved?,
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = RotatingFile::append(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if self.due(self.pending.len()) {
+            self.rotate()?;
+        }
+        self.file.write_all(&self.pending)?;
+        self.size += self.pending.len() as u64;
+        self.pending.clear();
+        self.file.flush()
+    }
+}
+
+fn plain(out: fern::FormatCallback, message: &fmt::Arguments, record: &log::Record) {
+    out.finish(format_args!(
+        "{}[{}][{}] {}",
+        chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+        record.target(),
+        record.level(),
+        message
+    ))
+}
+
+fn json(out: fern::FormatCallback, message: &fmt::Arguments, record: &log::Record) {
+    out.finish(format_args!("{}", json_line(message, record)))
+}
+
+fn colored() -> fern::Dispatch {
     let colors_line = ColoredLevelConfig::new()
         .error(Color::Red)
         .warn(Color::Yellow)
@@ -11,61 +195,131 @@ pub fn setup_logging(verbosity: u64) -> Result<(), fern::InitError> {
         .debug(Color::Blue)
         .trace(Color::BrightBlack);
 
-    base_config = match verbosity {
-        0 => base_config.level(log::LevelFilter::Info),
-        // .level_for("overly-verbose-target", log::LevelFilter::Warn),
-        1 => base_config
-            .level(log::LevelFilter::Info)
-            .level_for("merkava", log::LevelFilter::Debug),
-        // .level_for("overly-verbose-target", log::LevelFilter::Info),
-        2 => base_config.level(log::LevelFilter::Debug),
-        // _3_or_more => base_config.level(log::LevelFilter::Trace),
-        3 => base_config
-            .level(log::LevelFilter::Info)
-            .level_for("merkava", log::LevelFilter::Trace),
-        _4_or_more => base_config.level(log::LevelFilter::Trace),
-    };
-
-    let file_config = fern::Dispatch::new()
-        .format(|out, message, record| {
+    fern::Dispatch::new().format(move |out, message, record| {
+        if record.level() > log::LevelFilter::Info && record.target() == "cmd_program" {
             out.finish(format_args!(
-                "{}[{}][{}] {}",
-                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                record.target(),
-                record.level(),
+                "---\nDEBUG: {}: {}\n---",
+                chrono::Local::now().format("%H:%M:%S"),
                 message
             ))
-        })
-        .chain(fern::log_file("program.log")?);
-
-    let stdout_config = fern::Dispatch::new()
-        .format(move |out, message, record| {
-            if record.level() > log::LevelFilter::Info && record.target() == "cmd_program" {
-                out.finish(format_args!(
-                    "---\nDEBUG: {}: {}\n---",
-                    chrono::Local::now().format("%H:%M:%S"),
-                    message
-                ))
-            } else {
-                out.finish(format_args!(
-                    "{color_line}[{date}][{target}][{level}{color_line}] {message}\x1B[0m",
-                    color_line = format_args!(
-                        "\x1B[{}m",
-                        colors_line.get_color(&record.level()).to_fg_str()
-                    ),
-                    date = chrono::Local::now().format("%H:%M:%S"),
-                    target = record.target(),
-                    level = colors_line.color(record.level()),
-                    message = message,
-                ))
-            }
-        })
-        .chain(io::stdout());
+        } else {
+            out.finish(format_args!(
+                "{color_line}[{date}][{target}][{level}{color_line}] {message}\x1B[0m",
+                color_line = format_args!(
+                    "\x1B[{}m",
+                    colors_line.get_color(&record.level()).to_fg_str()
+                ),
+                date = chrono::Local::now().format("%H:%M:%S"),
+                target = record.target(),
+                level = colors_line.color(record.level()),
+                message = message,
+            ))
+        }
+    })
+}
+
+pub fn setup_logging(settings: &Logging) -> Result<(), fern::InitError> {
+    set_verbosity(settings.verbosity);
+    // Everything is let through to the filter so the verbosity can be
+    // changed without rebuilding the dispatch.
+    let mut base_config = fern::Dispatch::new()
+        .level(log::LevelFilter::Trace)
+        .filter(|metadata| allowed(VERBOSITY.load(Ordering::Relaxed), metadata));
+
+    if !settings.file.is_empty() {
+        let file: Box<dyn Write + Send> = Box::new(RotatingFile::open(settings)?);
+        let file_config = match settings.format {
+            LogFormat::Text => fern::Dispatch::new().format(plain),
+            LogFormat::Json => fern::Dispatch::new().format(json),
+        };
+        base_config = base_config.chain(file_config.chain(file));
+    }
 
-    base_config
-        .chain(file_config)
-        .chain(stdout_config)
-        .apply()?;
+    if settings.stdout {
+        let stdout_config = match (settings.format, settings.color) {
+            (LogFormat::Json, _) => fern::Dispatch::new().format(json),
+            (LogFormat::Text, false) => fern::Dispatch::new().format(plain),
+            (LogFormat::Text, true) => colored(),
+        };
+        base_config = base_config.chain(stdout_config.chain(io::stdout()));
+    }
+
+    base_config.apply()?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(target: &str, level: log::Level) -> log::Metadata<'_> {
+        log::Metadata::builder().target(target).level(level).build()
+    }
+
+    #[test]
+    fn allowed_follows_verbosity() {
+        let debug = metadata("merkava::lib::operations", log::Level::Debug);
+        let other_debug = metadata("hyper::proto", log::Level::Debug);
+        let trace = metadata("merkava", log::Level::Trace);
+        assert!(!allowed(0, &debug));
+        assert!(allowed(1, &debug));
+        assert!(!allowed(1, &other_debug));
+        assert!(allowed(2, &other_debug));
+        assert!(!allowed(2, &trace));
+        assert!(allowed(3, &trace));
+        assert!(!allowed(3, &other_debug));
+    }
+
+    #[test]
+    fn json_line_includes_request_fields() {
+        let record = log::Record::builder()
+            .target("merkava::request")
+            .level(log::Level::Debug)
+            .build();
+        let fields = Fields {
+            conn: 7,
+            channel: Some("foo".to_string()),
+            command: "PUSH",
+            latency_us: 42,
+            ok: true,
+        };
+        FIELDS.with(|current| *current.borrow_mut() = Some(fields));
+        let line = json_line(&format_args!("pushed"), &record);
+        FIELDS.with(|current| current.borrow_mut().take());
+
+        let line: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(line["message"], "pushed");
+        assert_eq!(line["level"], "DEBUG");
+        assert_eq!(line["conn"], 7);
+        assert_eq!(line["channel"], "foo");
+        assert_eq!(line["command"], "PUSH");
+        assert_eq!(line["latency_us"], 42);
+
+        let line = json_line(&format_args!("plain"), &record);
+        assert!(!line.contains("conn"), "{}", line);
+    }
+
+    #[test]
+    fn rotating_file_moves_full_files_aside() {
+        let dir = std::env::temp_dir().join(format!("merkava-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("merkava.log");
+        let settings = Logging {
+            file: path.to_str().unwrap().to_string(),
+            rotate_size: 10,
+            rotate_keep: 2,
+            ..Logging::default()
+        };
+        let mut file = RotatingFile::open(&settings).unwrap();
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+            file.flush().unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(dir.join("merkava.log.1")).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(dir.join("merkava.log.2")).unwrap(), "second\n");
+        assert!(!dir.join("merkava.log.3").exists());
+    }
+}
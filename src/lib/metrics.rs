@@ -0,0 +1,268 @@
+use crate::lib::{clients, conf, operations, state};
+use chrono::{DateTime, Utc};
+use futures::Future;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::service_fn_ok;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds, in seconds, of the histogram buckets.
+const BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+pub struct Histogram {
+    counts: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub const fn new() -> Histogram {
+        Histogram {
+            counts: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        for (bound, count) in BUCKETS.iter().zip(self.counts.iter()) {
+            if seconds <= *bound {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, count) in BUCKETS.iter().zip(self.counts.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                count.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Counters kept for the whole process; gauges about the data itself are
+/// read from the database when scraped.
+pub struct Metrics {
+    /// `[OK, ER]` counts for each command name.
+    commands: Mutex<BTreeMap<&'static str, [u64; 2]>>,
+    latency: Histogram,
+    snapshots: Histogram,
+    last_snapshot: Mutex<Option<DateTime<Utc>>>,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    pub const fn new() -> Metrics {
+        Metrics {
+            commands: Mutex::new(BTreeMap::new()),
+            latency: Histogram::new(),
+            snapshots: Histogram::new(),
+            last_snapshot: Mutex::new(None),
+        }
+    }
+
+    pub fn command(&self, command: &'static str, ok: bool, elapsed: Duration) {
+        let mut commands = self.commands.lock().unwrap();
+        commands.entry(command).or_insert([0, 0])[!ok as usize] += 1;
+        self.latency.observe(elapsed);
+    }
+
+    pub fn last_snapshot(&self) -> Option<DateTime<Utc>> {
+        *self.last_snapshot.lock().unwrap()
+    }
+
+    pub fn snapshot(&self, elapsed: Duration) {
+        self.snapshots.observe(elapsed);
+        *self.last_snapshot.lock().unwrap() = Some(Utc::now());
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render(&self, db: &Arc<state::Database>) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP merkava_commands_total Commands run, by command and result.\n");
+        out.push_str("# TYPE merkava_commands_total counter\n");
+        for (command, counts) in self.commands.lock().unwrap().iter() {
+            for (result, count) in ["OK", "ER"].iter().zip(counts.iter()) {
+                let _ = writeln!(
+                    out,
+                    "merkava_commands_total{{command=\"{}\",result=\"{}\"}} {}",
+                    command, result, count
+                );
+            }
+        }
+        self.latency.render(
+            &mut out,
+            "merkava_command_duration_seconds",
+            "Time taken to run a command.",
+        );
+
+        let stats = operations::server_stats(db);
+        let gauges: [(&str, &str, i64); 4] = [
+            (
+                "merkava_connected_clients",
+                "Open client connections.",
+                clients::CLIENTS.connected() as i64,
+            ),
+            (
+                "merkava_channels",
+                "Channels in memory.",
+                stats.channels as i64,
+            ),
+            (
+                "merkava_messages",
+                "Messages in memory.",
+                stats.messages as i64,
+            ),
+            (
+                "merkava_bytes",
+                "Approximate bytes used by messages in memory.",
+                stats.bytes as i64,
+            ),
+        ];
+        for (name, help, value) in gauges.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        self.snapshots.render(
+            &mut out,
+            "merkava_snapshot_duration_seconds",
+            "Time taken to write changed channels to disk.",
+        );
+        if let Some(last) = *self.last_snapshot.lock().unwrap() {
+            let age = (Utc::now() - last).num_milliseconds() as f64 / 1000.0;
+            out.push_str(
+                "# HELP merkava_last_snapshot_age_seconds Time since the last snapshot.\n",
+            );
+            out.push_str("# TYPE merkava_last_snapshot_age_seconds gauge\n");
+            let _ = writeln!(out, "merkava_last_snapshot_age_seconds {}", age);
+        }
+        out
+    }
+}
+
+fn handle(db: &Arc<state::Database>, req: Request<Body>) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    if req.uri().path() != "/metrics" {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+    } else if req.method() != Method::GET {
+        *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+    } else {
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+        *response.body_mut() = Body::from(METRICS.render(db));
+    }
+    response
+}
+
+/// Builds the optional `/metrics` listener from the `[metrics]` section, or
+/// `None` when it is disabled.
+pub fn server(
+    db: &Arc<state::Database>,
+    shared: &conf::Shared,
+) -> Result<Option<Box<dyn Future<Item = (), Error = ()> + Send>>, String> {
+    let conf = shared.read().unwrap();
+    if !conf.get::<bool>("metrics.enabled").unwrap_or(false) {
+        return Ok(None);
+    }
+    let addr: SocketAddr = conf
+        .get::<String>("metrics.address")
+        .map_err(|_| "metrics.address is required when metrics.enabled is set".to_string())?
+        .parse()
+        .map_err(|e| format!("metrics.address is invalid: {}", e))?;
+    let builder = Server::try_bind(&addr).map_err(|e| format!("unable to bind {}: {}", addr, e))?;
+    info!("Metrics listening on: {}", addr);
+
+    let db = db.clone();
+    let server = builder
+        .serve(move || {
+            let db = db.clone();
+            service_fn_ok(move |req| handle(&db, req))
+        })
+        .map_err(|e| error!("metrics listener error: {}", e));
+    Ok(Some(Box::new(server)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::testing;
+
+    #[test]
+    fn histogram_counts_into_every_larger_bucket() {
+        let histogram = Histogram::new();
+        histogram.observe(Duration::from_micros(300));
+        histogram.observe(Duration::from_secs(2));
+        let mut out = String::new();
+        histogram.render(&mut out, "h", "help");
+        assert!(out.contains("h_bucket{le=\"0.00025\"} 0\n"), "{}", out);
+        assert!(out.contains("h_bucket{le=\"0.0005\"} 1\n"), "{}", out);
+        assert!(out.contains("h_bucket{le=\"1\"} 1\n"), "{}", out);
+        assert!(out.contains("h_bucket{le=\"+Inf\"} 2\n"), "{}", out);
+        assert!(out.contains("h_sum 2.0003\n"), "{}", out);
+        assert!(out.contains("h_count 2\n"), "{}", out);
+    }
+
+    #[test]
+    fn render_reports_commands_and_data() {
+        let db = testing::make_db();
+        let shared = conf::shared(config::Config::default());
+        let mut session = crate::lib::session::Session::new();
+        let metrics = Metrics::new();
+        operations::handle_request(&db, &shared, &mut session, "foo PUSH hello".to_string());
+        metrics.command("PUSH", true, Duration::from_millis(1));
+        metrics.command("PUSH", false, Duration::from_millis(1));
+        metrics.command("PUSH", true, Duration::from_millis(1));
+
+        let out = metrics.render(&db);
+        assert!(
+            out.contains("merkava_commands_total{command=\"PUSH\",result=\"OK\"} 2\n"),
+            "{}",
+            out
+        );
+        assert!(
+            out.contains("merkava_commands_total{command=\"PUSH\",result=\"ER\"} 1\n"),
+            "{}",
+            out
+        );
+        assert!(out.contains("merkava_channels 1\n"), "{}", out);
+        assert!(out.contains("merkava_messages 1\n"), "{}", out);
+        assert!(out.contains("\nmerkava_bytes "), "{}", out);
+        assert!(
+            !out.contains("merkava_last_snapshot_age_seconds"),
+            "{}",
+            out
+        );
+
+        metrics.snapshot(Duration::from_millis(3));
+        assert!(metrics
+            .render(&db)
+            .contains("merkava_last_snapshot_age_seconds "));
+    }
+}
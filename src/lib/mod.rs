@@ -1,5 +1,25 @@
+pub mod audit;
+pub mod auth;
+pub mod changes;
+pub mod clients;
+pub mod cluster;
 pub mod conf;
+pub mod dump;
+pub mod gateway;
+pub mod info;
 pub mod logging;
+pub mod metrics;
 pub mod operations;
+pub mod replication;
+pub mod resp;
+pub mod session;
+pub mod settings;
+pub mod shutdown;
+pub mod slowlog;
 pub mod state;
+#[cfg(test)]
+pub mod testing;
+pub mod tls;
 pub mod types;
+pub mod webhooks;
+pub mod websocket;
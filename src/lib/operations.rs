@@ -1,36 +1,42 @@
-use crate::lib::{state, types};
+use crate::lib::{
+    audit, auth, changes, clients, cluster, conf, info, logging, metrics, replication, session,
+    shutdown, slowlog, state, types, webhooks,
+};
 use bincode::serialize_into;
+use glob::Pattern;
 use blob_uuid;
-use chrono::Utc;
+use chrono::{Duration, Utc};
 use std::cmp;
-use std::collections::HashMap;
-use std::fs::{create_dir_all, File};
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, create_dir_all, File};
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
 use uuid::Uuid;
 
-const MAXIMUM: usize = 10;
+pub const MAXIMUM: usize = 10;
+const IDEMPOTENCY_WINDOW: i64 = 300;
+const LIST_MAXIMUM: usize = 1000;
+const CHANGES_MAXIMUM: usize = 1000;
+const SLOWLOG_THRESHOLD: u64 = 10;
+const SLOWLOG_MAX_LEN: usize = 128;
 
-fn do_push(db: &Arc<state::Database>, channel_id: String, value: String) -> types::Response {
-    if value.chars().count() == 0 {
-        return types::Response::Error {
-            message: "Cannot push empty message".to_string(),
-        };
-    }
-
-    let mut channels = db.channels.lock().unwrap();
-    if !channels.contains_key(&channel_id) {
+fn get_or_create_channel<'a>(
+    channels: &'a mut BTreeMap<String, state::Channel>,
+    channel_id: &String,
+) -> &'a state::Channel {
+    if !channels.contains_key(channel_id) {
         channels.insert(
             channel_id.clone(),
-            state::Channel {
-                index: Mutex::new(HashMap::new()),
-                data: Mutex::new(Vec::new()),
-            },
+            state::Channel::new(HashMap::new(), Vec::new()),
         );
     }
-    let _channel = channels.get(&channel_id);
-    let channel = _channel.unwrap();
-    let mut data = channel.data.lock().unwrap();
-    let mut index = channel.index.lock().unwrap();
+    channels.get(channel_id).unwrap()
+}
+
+fn append_message(channel: &state::Channel, channel_id: &String, value: String) -> state::Message {
     let now = Utc::now();
     let uuid = Uuid::new_v5(
         &Uuid::NAMESPACE_DNS,
@@ -42,9 +48,174 @@ fn do_push(db: &Arc<state::Database>, channel_id: String, value: String) -> type
         created: now,
         value,
     };
+    insert_message(channel, message.clone());
+    message
+}
+
+fn insert_message(channel: &state::Channel, message: state::Message) {
+    let mut data = channel.data.lock().unwrap();
+    let mut index = channel.index.lock().unwrap();
     let length = data.len();
-    data.push(message.clone());
-    index.insert(uid.clone(), length);
+    index.insert(message.uid.clone(), length);
+    data.push(message);
+    let mut counters = channel.counters.lock().unwrap();
+    counters.pushes += 1;
+    counters.dirty = true;
+}
+
+fn do_push(db: &Arc<state::Database>, channel_id: String, value: String) -> types::Response {
+    if value.chars().count() == 0 {
+        return types::Response::Error {
+            message: "Cannot push empty message".to_string(),
+        };
+    }
+
+    let mut channels = db.channels.lock().unwrap();
+    let channel = get_or_create_channel(&mut channels, &channel_id);
+    let message = append_message(channel, &channel_id, value);
+    db.publish(&channel_id, &message);
+    webhooks::notify(db, "push", &channel_id, &message);
+    db.record(replication::Change::Push {
+        channel: channel_id,
+        message: message.clone(),
+        key: None,
+    });
+    types::Response::Push { message }
+}
+
+/// Adds a message pushed on the leader, keeping its uid and timestamp, and
+/// the idempotency key it was pushed with.
+fn do_insert(
+    db: &Arc<state::Database>,
+    channel_id: String,
+    message: state::Message,
+    key: Option<String>,
+) -> types::Response {
+    let mut channels = db.channels.lock().unwrap();
+    let channel = get_or_create_channel(&mut channels, &channel_id);
+    insert_message(channel, message.clone());
+    if let Some(ref key) = key {
+        let seen = state::IdempotencyKey {
+            uid: message.uid.clone(),
+            seen: message.created,
+        };
+        channel.keys.lock().unwrap().insert(key.clone(), seen);
+    }
+    db.publish(&channel_id, &message);
+    db.record(replication::Change::Push {
+        channel: channel_id,
+        message: message.clone(),
+        key,
+    });
+    types::Response::Push { message }
+}
+
+/// Puts messages from another node in front of the channel's own, skipping
+/// any it already has, and returns how many were added.
+fn merge_messages(channel: &state::Channel, messages: Vec<state::Message>) -> usize {
+    let mut data = channel.data.lock().unwrap();
+    let mut index = channel.index.lock().unwrap();
+    let mut merged: Vec<state::Message> = messages
+        .into_iter()
+        .filter(|message| !index.contains_key(&message.uid))
+        .collect();
+    let added = merged.len();
+    merged.append(&mut data);
+    *index = merged
+        .iter()
+        .enumerate()
+        .map(|(position, message)| (message.uid.clone(), position))
+        .collect();
+    *data = merged;
+    channel.counters.lock().unwrap().dirty = true;
+    added
+}
+
+/// Merges an exported channel into `channel`: its messages, the newer of
+/// each idempotency key, and its counts. Returns how many messages were
+/// added.
+fn merge_export(channel: &state::Channel, export: state::Export) -> usize {
+    let mut keys = channel.keys.lock().unwrap();
+    for (key, seen) in export.keys {
+        match keys.get(&key) {
+            Some(kept) if kept.seen >= seen.seen => {}
+            _ => {
+                keys.insert(key, seen);
+            }
+        }
+    }
+    let mut counters = channel.counters.lock().unwrap();
+    counters.pushes += export.pushes;
+    counters.updates += export.updates;
+    drop(counters);
+    merge_messages(channel, export.messages)
+}
+
+fn do_import(
+    db: &Arc<state::Database>,
+    channel_id: String,
+    export: state::Export,
+) -> types::Response {
+    let mut channels = db.channels.lock().unwrap();
+    let channel = get_or_create_channel(&mut channels, &channel_id);
+    let added = merge_export(channel, export.clone());
+    db.record(replication::Change::Import {
+        channel: channel_id,
+        export,
+    });
+    types::Response::Value {
+        value: added.to_string(),
+    }
+}
+
+fn do_push_with_key(
+    db: &Arc<state::Database>,
+    channel_id: String,
+    key: String,
+    value: String,
+    window: i64,
+) -> types::Response {
+    if value.chars().count() == 0 {
+        return types::Response::Error {
+            message: "Cannot push empty message".to_string(),
+        };
+    }
+
+    let mut channels = db.channels.lock().unwrap();
+    let channel = get_or_create_channel(&mut channels, &channel_id);
+    let mut keys = channel.keys.lock().unwrap();
+    let now = Utc::now();
+    // `Duration::seconds` panics past `i64::MAX / 1000`, and a panic here
+    // would poison the channels lock.
+    let cutoff = now.checked_sub_signed(Duration::seconds(window.clamp(0, i64::MAX / 1000)));
+    keys.retain(|_, seen| cutoff.is_none_or(|cutoff| seen.seen > cutoff));
+
+    if let Some(seen) = keys.get(&key) {
+        let data = channel.data.lock().unwrap();
+        let index = channel.index.lock().unwrap();
+        if let Some(message_index) = index.get(&seen.uid) {
+            debug!("replayed push for key {:?}", key);
+            return types::Response::Push {
+                message: data[*message_index].clone(),
+            };
+        }
+    }
+
+    let message = append_message(channel, &channel_id, value);
+    db.publish(&channel_id, &message);
+    webhooks::notify(db, "push", &channel_id, &message);
+    db.record(replication::Change::Push {
+        channel: channel_id,
+        message: message.clone(),
+        key: Some(key.clone()),
+    });
+    keys.insert(
+        key,
+        state::IdempotencyKey {
+            uid: message.uid.clone(),
+            seen: now,
+        },
+    );
     types::Response::Push { message }
 }
 
@@ -53,6 +224,7 @@ fn do_recent(
     channel_id: String,
     count: usize,
     offset: usize,
+    maximum: usize,
 ) -> types::Response {
     debug!("doing recent");
     let channels = db.channels.lock().unwrap();
@@ -68,10 +240,10 @@ fn do_recent(
     // let channel = _channel.unwrap();
     let data = channel.data.lock().unwrap();
     let index: usize = {
-        if data.len() < cmp::min(count, MAXIMUM) {
+        if data.len() < cmp::min(count, maximum) {
             0
         } else {
-            data.len() - cmp::min(count, MAXIMUM)
+            data.len() - cmp::min(count, maximum)
         }
     };
     let end: usize = {
@@ -93,7 +265,7 @@ fn do_recent(
         // _ => &data[index..],
         _ => &data[(index - offset)..end],
     };
-    if messages.len() == 0 {
+    if messages.is_empty() {
         return types::Response::Error {
             message: "No messages found".to_string(),
         };
@@ -111,14 +283,30 @@ fn do_update(
 ) -> types::Response {
     let channels = db.channels.lock().unwrap();
     let _channel = channels.get(&channel_id);
-    let channel = _channel.unwrap();
+    let channel: &state::Channel = match _channel {
+        Some(_) => _channel.unwrap(),
+        None => {
+            return types::Response::Error {
+                message: "No messages found".to_string(),
+            };
+        }
+    };
     let mut data = channel.data.lock().unwrap();
     let index = channel.index.lock().unwrap();
     let message = &index.get(&uid);
-    if let Some(_) = message {
+    if message.is_some() {
         let message_index = message.unwrap();
-        let mut message = &mut data[*message_index];
-        message.value = value;
+        let message = &mut data[*message_index];
+        message.value = value.clone();
+        webhooks::notify(db, "update", &channel_id, message);
+        let mut counters = channel.counters.lock().unwrap();
+        counters.updates += 1;
+        counters.dirty = true;
+        db.record(replication::Change::Update {
+            channel: channel_id,
+            uid,
+            value,
+        });
         return types::Response::Done {};
     }
     types::Response::Error {
@@ -140,7 +328,7 @@ fn do_retrieve(db: &Arc<state::Database>, channel_id: String, uid: String) -> ty
     let data = channel.data.lock().unwrap();
     let index = channel.index.lock().unwrap();
     let message = &index.get(&uid);
-    if let Some(_) = message {
+    if message.is_some() {
         let message_index = message.unwrap();
         let message = &data[*message_index];
         return types::Response::Retrieve {
@@ -156,133 +344,855 @@ fn do_connect(_db: &Arc<state::Database>, _channel_id: String) -> types::Respons
     types::Response::Done {}
 }
 
-fn do_flush(db: &Arc<state::Database>, channel_id: String) -> types::Response {
+fn archive_path(conf: &config::Config, channel_id: &str) -> String {
+    let archive = conf.get::<String>("persistence.archive_path").unwrap_or(format!(
+        "{}-archive",
+        conf.get::<String>("persistence.path").unwrap()
+    ));
+    format!(
+        "{}/{}-{}",
+        archive,
+        channel_id,
+        Utc::now().format("%Y%m%d%H%M%S%f")
+    )
+}
+
+fn do_flush(
+    db: &Arc<state::Database>,
+    conf: &config::Config,
+    channel_id: String,
+    archive: bool,
+) -> types::Response {
     let mut channels = db.channels.lock().unwrap();
+    let path = match channel_path(conf, &channel_id) {
+        Ok(path) => path,
+        Err(e) => {
+            return types::Response::Error {
+                message: e.to_string(),
+            };
+        }
+    };
+
+    let flushed = match (archive, channels.get(&channel_id)) {
+        (true, Some(channel)) => {
+            // Snapshot first so the archive holds what was in memory, not
+            // just whatever was last backed up.
+            let target = archive_path(conf, &channel_id);
+            info!("Archiving {} to {}", path, target);
+            write_channel(channel, &path)
+                .and_then(|_| create_dir_all(Path::new(&target).parent().unwrap()))
+                .and_then(|_| fs::rename(&path, &target))
+        }
+        (true, None) if Path::new(&path).exists() => {
+            let target = archive_path(conf, &channel_id);
+            info!("Archiving {} to {}", path, target);
+            create_dir_all(Path::new(&target).parent().unwrap())
+                .and_then(|_| fs::rename(&path, &target))
+        }
+        _ => {
+            debug!("Removing {}", path);
+            remove_dir(&path)
+        }
+    };
+    if let Err(e) = flushed {
+        return types::Response::Error {
+            message: e.to_string(),
+        };
+    }
+
     channels.remove(&channel_id);
+    db.record(replication::Change::Flush {
+        channel: channel_id,
+        archive,
+    });
     types::Response::Done {}
 }
 
-fn do_backup(db: &Arc<state::Database>, conf: &config::Config, channel_id: String) -> types::Response {
-    let channels = db.channels.lock().unwrap();
-    let _channel = channels.get(&channel_id);
-    let channel = _channel.unwrap();
-    let data = channel.data.lock().unwrap();
-    let index = channel.index.lock().unwrap();
+/// Where a channel is backed up. Fails for an id `types::check_channel_id`
+/// refuses, so nothing outside `persistence.path` is written or removed.
+pub fn channel_path(conf: &config::Config, channel_id: &str) -> io::Result<String> {
+    types::check_channel_id(channel_id)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
     let backup_path = conf.get::<String>("persistence.path").unwrap();
-    let path = format!("{}/{}", backup_path, channel_id);
-    info!("{}", format!("Backing up to {}", path));
+    Ok(format!("{}/{}", backup_path, channel_id))
+}
+
+fn remove_dir(path: &str) -> io::Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Removes a backup left at a `RENAME` or `COPY` target that has no
+/// channel in memory. Only a directory directly under `persistence.path` is
+/// removed, never a symlink or anything it points to.
+fn remove_stale(conf: &config::Config, path: &str) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        metadata => metadata?,
+    };
+    let data_dir = fs::canonicalize(conf.get::<String>("persistence.path").unwrap())?;
+    let parent = match Path::new(path).parent() {
+        Some(parent) => Some(fs::canonicalize(parent)?),
+        None => None,
+    };
+    if !metadata.is_dir() || parent.as_ref() != Some(&data_dir) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a channel directory in {}", path, data_dir.display()),
+        ));
+    }
+    fs::remove_dir_all(path)
+}
+
+fn copy_dir(source: &str, target: &str) -> io::Result<()> {
+    create_dir_all(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        fs::copy(entry.path(), Path::new(target).join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+fn do_rename(
+    db: &Arc<state::Database>,
+    conf: &config::Config,
+    channel_id: String,
+    target_id: String,
+) -> types::Response {
+    let mut channels = db.channels.lock().unwrap();
+    if !channels.contains_key(&channel_id) {
+        return types::Response::Error {
+            message: "No messages found".to_string(),
+        };
+    }
+    if channels.contains_key(&target_id) {
+        return types::Response::Error {
+            message: format!("channel {} already exists", target_id),
+        };
+    }
+
+    let paths = channel_path(conf, &channel_id)
+        .and_then(|source| channel_path(conf, &target_id).map(|target| (source, target)));
+    let (source, target) = match paths {
+        Ok(paths) => paths,
+        Err(e) => {
+            return types::Response::Error {
+                message: e.to_string(),
+            };
+        }
+    };
+    info!("Renaming {} to {}", source, target);
+    let moved = remove_stale(conf, &target).and_then(|_| match Path::new(&source).exists() {
+        true => fs::rename(&source, &target),
+        false => Ok(()),
+    });
+    if let Err(e) = moved {
+        return types::Response::Error {
+            message: e.to_string(),
+        };
+    }
+
+    let channel = channels.remove(&channel_id).unwrap();
+    channels.insert(target_id.clone(), channel);
+    db.record(replication::Change::Rename {
+        channel: channel_id,
+        target: target_id,
+    });
+    types::Response::Done {}
+}
+
+fn do_copy(
+    db: &Arc<state::Database>,
+    conf: &config::Config,
+    channel_id: String,
+    target_id: String,
+) -> types::Response {
+    let mut channels = db.channels.lock().unwrap();
+    if channels.contains_key(&target_id) {
+        return types::Response::Error {
+            message: format!("channel {} already exists", target_id),
+        };
+    }
+    let channel = match channels.get(&channel_id) {
+        Some(channel) => {
+            let copy = state::Channel::new(
+                channel.index.lock().unwrap().clone(),
+                channel.data.lock().unwrap().clone(),
+            );
+            *copy.counters.lock().unwrap() = channel.counters.lock().unwrap().clone();
+            copy
+        }
+        None => {
+            return types::Response::Error {
+                message: "No messages found".to_string(),
+            };
+        }
+    };
 
-    match create_dir_all(path.clone()) {
+    let paths = channel_path(conf, &channel_id)
+        .and_then(|source| channel_path(conf, &target_id).map(|target| (source, target)));
+    let (source, target) = match paths {
+        Ok(paths) => paths,
         Err(e) => {
             return types::Response::Error {
                 message: e.to_string(),
             };
         }
-        _ => (),
+    };
+    info!("Copying {} to {}", source, target);
+    let copied = remove_stale(conf, &target).and_then(|_| match Path::new(&source).exists() {
+        true => copy_dir(&source, &target),
+        false => Ok(()),
+    });
+    if let Err(e) = copied {
+        return types::Response::Error {
+            message: e.to_string(),
+        };
     }
 
+    channels.insert(target_id.clone(), channel);
+    db.record(replication::Change::Copy {
+        channel: channel_id,
+        target: target_id,
+    });
+    types::Response::Done {}
+}
+
+pub fn write_channel(channel: &state::Channel, path: &str) -> io::Result<()> {
+    let data = channel.data.lock().unwrap();
+    let index = channel.index.lock().unwrap();
+    create_dir_all(path)?;
+
     let data_file = format!("{}/data.mrkv", path);
-    let writer = File::create(data_file).unwrap();
-    serialize_into(writer, &data.clone()).expect("Unable to write to file");
+    let writer = File::create(data_file)?;
+    serialize_into(writer, &data.clone()).map_err(io::Error::other)?;
 
     let index_file = format!("{}/index.mrkv", path);
-    let writer = File::create(index_file).unwrap();
-    serialize_into(writer, &index.clone()).expect("Unable to write to file");
+    let writer = File::create(index_file)?;
+    serialize_into(writer, &index.clone()).map_err(io::Error::other)?;
 
-    types::Response::Done {}
+    let mut counters = channel.counters.lock().unwrap();
+    counters.last_backup = Some(Utc::now());
+    counters.dirty = false;
+    Ok(())
 }
 
-fn do_stats(db: &Arc<state::Database>, channel_id: String) -> types::Response {
+fn do_backup(db: &Arc<state::Database>, conf: &config::Config, channel_id: String) -> types::Response {
     let channels = db.channels.lock().unwrap();
-    let _channel = channels.get(&channel_id);
-    if _channel.is_none() {
-        return types::Response::Stats {
-            message: "Messages: -".to_string(),
+    let channel = match channels.get(&channel_id) {
+        Some(channel) => channel,
+        None => {
+            return types::Response::Error {
+                message: "No messages found".to_string(),
+            };
+        }
+    };
+    let path = match channel_path(conf, &channel_id) {
+        Ok(path) => path,
+        Err(e) => {
+            return types::Response::Error {
+                message: e.to_string(),
+            };
         }
+    };
+    info!("Backing up to {}", path);
+
+    match write_channel(channel, &path) {
+        Err(e) => types::Response::Error {
+            message: e.to_string(),
+        },
+        Ok(_) => types::Response::Done {},
     }
-    let channel = _channel.unwrap();
-    let data = channel.data.lock().unwrap();
-    types::Response::Stats {
-        message: format!("Messages: {:?}", data.len()),
+}
+
+/// Writes every channel changed since its last backup, the same way
+/// `BACKUP` does. Returns how many were written and the errors for any
+/// that could not be.
+pub fn backup_dirty(db: &Arc<state::Database>, conf: &config::Config) -> (usize, Vec<String>) {
+    let started = Instant::now();
+    let channels = db.channels.lock().unwrap();
+    let mut written = 0;
+    let mut errors = Vec::new();
+    for (channel_id, channel) in channels.iter() {
+        if !channel.counters.lock().unwrap().dirty {
+            continue;
+        }
+        match channel_path(conf, channel_id).and_then(|path| write_channel(channel, &path)) {
+            Ok(_) => written += 1,
+            Err(e) => errors.push(format!("{}: {}", channel_id, e)),
+        }
     }
+    metrics::METRICS.snapshot(started.elapsed());
+    (written, errors)
 }
 
-pub fn handle_request(db: &Arc<state::Database>, conf: &config::Config, line: String) -> types::Response {
-    debug!("incoming request: {:?}", line);
-    let request = match types::Request::parse(&line) {
-        Ok(req) => req,
-        Err(e) => return types::Response::Error { message: e },
+/// Drops the oldest messages that `retention.max_len` or
+/// `retention.max_age` no longer keep, returning how many were dropped.
+pub fn apply_retention(db: &Arc<state::Database>, conf: &config::Config) -> usize {
+    let max_len = conf.get::<usize>("retention.max_len").unwrap_or(0);
+    let max_age = conf.get::<i64>("retention.max_age").unwrap_or(0);
+    let cutoff = match max_age {
+        0 => None,
+        // `Duration::seconds` panics past this.
+        max_age => Utc::now().checked_sub_signed(Duration::seconds(max_age.min(i64::MAX / 1000))),
     };
+    if max_len == 0 && cutoff.is_none() {
+        return 0;
+    }
+    let channels = db.channels.lock().unwrap();
+    let mut dropped = 0;
+    for channel in channels.values() {
+        let mut data = channel.data.lock().unwrap();
+        let mut cut = match max_len {
+            0 => 0,
+            max_len => data.len().saturating_sub(max_len),
+        };
+        if let Some(cutoff) = cutoff {
+            cut = cmp::max(cut, data.partition_point(|message| message.created < cutoff));
+        }
+        if cut == 0 {
+            continue;
+        }
+        data.drain(..cut);
+        let mut index = channel.index.lock().unwrap();
+        index.retain(|_, position| *position >= cut);
+        for position in index.values_mut() {
+            *position -= cut;
+        }
+        channel.counters.lock().unwrap().dirty = true;
+        dropped += cut;
+    }
+    dropped
+}
 
-    match request {
-        types::Request::Push { channel_id, value } => do_push(&db, channel_id, value),
-        types::Request::Recent {
-            channel_id,
-            count,
-            offset,
-        } => do_recent(&db, channel_id, count, offset),
-        types::Request::Retrieve { channel_id, uid } => do_retrieve(&db, channel_id, uid),
-        types::Request::Update {
-            channel_id,
+/// Replays a change received from the leader. It is recorded again, so
+/// this node's own followers receive it too.
+pub fn apply(
+    db: &Arc<state::Database>,
+    conf: &config::Config,
+    change: replication::Change,
+) -> types::Response {
+    match change {
+        replication::Change::Push {
+            channel,
+            message,
+            key,
+        } => do_insert(db, channel, message, key),
+        replication::Change::Update {
+            channel,
             uid,
             value,
-        } => do_update(&db, channel_id, uid, value),
-        types::Request::Connect { channel_id } => do_connect(&db, channel_id),
-        types::Request::Flush { channel_id } => do_flush(&db, channel_id),
-        types::Request::Backup { channel_id } => do_backup(&db, &conf, channel_id),
-        types::Request::Stats { channel_id } => do_stats(&db, channel_id),
+        } => do_update(db, channel, uid, value),
+        replication::Change::Flush { channel, archive } => do_flush(db, conf, channel, archive),
+        replication::Change::Rename { channel, target } => do_rename(db, conf, channel, target),
+        replication::Change::Copy { channel, target } => do_copy(db, conf, channel, target),
+        replication::Change::Import { channel, export } => do_import(db, channel, export),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::lib::state;
-    use serde_json::Value;
-    use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
+/// Takes a channel out of the database for moving to another node,
+/// returning everything the new owner needs. Its backup stays until the
+/// move is done.
+pub fn take_channel(db: &Arc<state::Database>, channel_id: &str) -> Option<state::Export> {
+    let channel = db.channels.lock().unwrap().remove(channel_id)?;
+    let counters = channel.counters.into_inner().unwrap();
+    Some(state::Export {
+        messages: channel.data.into_inner().unwrap(),
+        keys: channel.keys.into_inner().unwrap(),
+        pushes: counters.pushes,
+        updates: counters.updates,
+    })
+}
 
-    fn make_db() -> std::sync::Arc<state::Database> {
-        let channels = HashMap::new();
-        Arc::new(state::Database {
-            channels: Arc::new(Mutex::new(channels)),
-        })
-    }
+/// Puts back a channel that couldn't be moved.
+pub fn restore_channel(db: &Arc<state::Database>, channel_id: &str, export: state::Export) {
+    let mut channels = db.channels.lock().unwrap();
+    let channel = get_or_create_channel(&mut channels, &channel_id.to_string());
+    merge_export(channel, export);
+}
 
-    fn make_pushes(db: &std::sync::Arc<state::Database>, channel_id: String, number: u16) {
-        for x in 0..number {
-            do_push(&db, channel_id.to_string(), format!("{:?}", x));
+/// Swaps every channel for a snapshot from the leader, removing the backups
+/// of channels the leader doesn't have.
+pub fn replace_channels(
+    db: &Arc<state::Database>,
+    conf: &config::Config,
+    snapshot: BTreeMap<String, state::Channel>,
+) -> io::Result<()> {
+    let mut channels = db.channels.lock().unwrap();
+    for channel_id in channels.keys() {
+        if !snapshot.contains_key(channel_id) {
+            remove_dir(&channel_path(conf, channel_id)?)?;
         }
     }
+    *channels = snapshot;
+    Ok(())
+}
 
-    ////////////////
-    // PUSH TESTS //
-    ////////////////
-
-    #[test]
-    fn do_push_receive_ok_response() {
-        let db = make_db();
-        let response = do_push(&db, String::from("foobar"), String::from("something"));
-        let message = response.serialize();
-        assert_eq!(&message[..2], "OK");
+fn do_replication(db: &Arc<state::Database>) -> types::Response {
+    types::Response::Stats {
+        stats: db.replication.lock().unwrap().status(),
     }
+}
 
-    #[test]
-    fn do_push_receive_er_response() {
-        let db = make_db();
-        let response = do_push(&db, String::from("foobar"), String::from(""));
-        let message = response.serialize();
-        assert_eq!(&message[..2], "ER");
+fn do_promote(db: &Arc<state::Database>) -> types::Response {
+    match db.replication.lock().unwrap().promote() {
+        Ok(()) => types::Response::Done {},
+        Err(message) => types::Response::Error { message },
     }
+}
 
-    #[test]
-    fn do_push_message_stored() {
-        let db = make_db();
-        let text = String::from("something");
-        let response = do_push(&db, String::from("foobar"), text.clone());
-        let mut message = response.serialize();
-        let uid = &mut message[3..].to_string();
-        uid.pop();
+fn channel_stats(channel_id: &str, channel: Option<&state::Channel>) -> types::ChannelStats {
+    let mut stats = types::ChannelStats {
+        channel: channel_id.to_string(),
+        ..Default::default()
+    };
+    let channel = match channel {
+        Some(channel) => channel,
+        None => return stats,
+    };
+    let data = channel.data.lock().unwrap();
+    let counters = channel.counters.lock().unwrap();
+    stats.messages = data.len();
+    stats.bytes = data.iter().map(message_bytes).sum();
+    stats.first_created = data.first().map(|message| message.created);
+    stats.last_created = data.last().map(|message| message.created);
+    stats.pushes = counters.pushes;
+    stats.updates = counters.updates;
+    stats.last_backup = counters.last_backup;
+    stats.dirty = counters.dirty;
+    stats
+}
+
+fn message_bytes(message: &state::Message) -> usize {
+    message.uid.len() + message.value.len() + mem::size_of::<state::Message>()
+}
+
+/// Totals across every channel, as reported by `STATS *`.
+pub fn server_stats(db: &Arc<state::Database>) -> types::ServerStats {
+    let channels = db.channels.lock().unwrap();
+    let mut server = types::ServerStats::default();
+    for (channel_id, channel) in channels.iter() {
+        let stats = channel_stats(channel_id, Some(channel));
+        server.channels += 1;
+        server.messages += stats.messages;
+        server.bytes += stats.bytes;
+        server.pushes += stats.pushes;
+        server.updates += stats.updates;
+        server.dirty_channels += stats.dirty as usize;
+        server.last_backup = cmp::max(server.last_backup, stats.last_backup);
+    }
+    server
+}
+
+fn do_stats(db: &Arc<state::Database>, channel_id: String) -> types::Response {
+    if channel_id == "*" {
+        return types::Response::Stats {
+            stats: serde_json::to_value(server_stats(db)).unwrap(),
+        };
+    }
+    let channels = db.channels.lock().unwrap();
+    let stats = channel_stats(&channel_id, channels.get(&channel_id));
+    types::Response::Stats {
+        stats: serde_json::to_value(stats).unwrap(),
+    }
+}
+
+fn encode_cursor(channel_id: &str) -> String {
+    channel_id.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_cursor(cursor: &str) -> Option<String> {
+    if !cursor.len().is_multiple_of(2) {
+        return None;
+    }
+    let bytes: Option<Vec<u8>> = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(cursor.get(i..i + 2)?, 16).ok())
+        .collect();
+    String::from_utf8(bytes?).ok()
+}
+
+fn do_list(
+    db: &Arc<state::Database>,
+    pattern: String,
+    cursor: String,
+    count: usize,
+    maximum: usize,
+) -> types::Response {
+    let matcher = match Pattern::new(&pattern) {
+        Ok(matcher) => matcher,
+        Err(e) => {
+            return types::Response::Error {
+                message: format!("invalid pattern: {}", e.msg),
+            };
+        }
+    };
+    let start = match cursor.as_str() {
+        "0" => String::new(),
+        _ => match decode_cursor(&cursor) {
+            Some(start) => start,
+            None => {
+                return types::Response::Error {
+                    message: "invalid cursor".to_string(),
+                };
+            }
+        },
+    };
+    let count = count.clamp(1, maximum);
+
+    // Like Redis SCAN, `count` bounds the number of channels examined rather
+    // than the number returned, so a selective pattern may yield short pages.
+    let channels = db.channels.lock().unwrap();
+    let mut scanned = channels.range(start..);
+    let mut summaries = Vec::new();
+    for (channel_id, channel) in scanned.by_ref().take(count) {
+        if matcher.matches(channel_id) {
+            summaries.push(types::ChannelSummary {
+                channel: channel_id.clone(),
+                messages: channel.data.lock().unwrap().len(),
+            });
+        }
+    }
+    let cursor = match scanned.next() {
+        Some((channel_id, _)) => encode_cursor(channel_id),
+        None => "0".to_string(),
+    };
+    types::Response::List {
+        cursor,
+        channels: summaries,
+    }
+}
+
+fn do_subscribe(db: &Arc<state::Database>, session: &session::Session, channel_id: String) -> types::Response {
+    match session.feed {
+        Some(ref feed) => {
+            db.subscribe(&channel_id, session.id, feed.clone());
+            types::Response::Done {}
+        }
+        None => types::Response::Error {
+            message: "SUBSCRIBE needs a connection that can receive pushes, such as a WebSocket".to_string(),
+        },
+    }
+}
+
+fn do_unsubscribe(db: &Arc<state::Database>, session: &session::Session, channel_id: String) -> types::Response {
+    db.unsubscribe(&channel_id, session.id);
+    types::Response::Done {}
+}
+
+fn do_auth(conf: &config::Config, session: &mut session::Session, token: String) -> types::Response {
+    match auth::authenticate(conf, &token) {
+        Ok(grants) => {
+            info!("authenticated as {}", grants.identity);
+            session.grants = Some(grants);
+            types::Response::Done {}
+        }
+        Err(message) => {
+            session.grants = None;
+            types::Response::Error { message }
+        }
+    }
+}
+
+fn do_config_get(conf: &config::Config, key: String) -> types::Response {
+    match conf::get(conf, &key) {
+        Ok(value) => types::Response::Value { value },
+        Err(message) => types::Response::Error { message },
+    }
+}
+
+fn do_slowlog_get(count: usize) -> types::Response {
+    types::Response::Slowlog {
+        entries: slowlog::SLOWLOG.get(count),
+    }
+}
+
+fn do_changes(from: u64, count: usize, maximum: usize) -> types::Response {
+    match changes::read(from, count.clamp(1, maximum)) {
+        Ok((changes, next)) => types::Response::Changes { changes, next },
+        Err(message) => types::Response::Error { message },
+    }
+}
+
+fn do_slowlog_reset() -> types::Response {
+    slowlog::SLOWLOG.reset();
+    types::Response::Done {}
+}
+
+fn do_info(db: &Arc<state::Database>, conf: &config::Config) -> types::Response {
+    types::Response::Stats {
+        stats: info::report(db, conf),
+    }
+}
+
+fn do_client_list() -> types::Response {
+    types::Response::Clients {
+        clients: clients::CLIENTS.list(),
+    }
+}
+
+fn do_client_kill(id: u64) -> types::Response {
+    let killed = clients::CLIENTS.kill(id);
+    if killed {
+        info!("CLIENT KILL {}", id);
+    }
+    types::Response::Killed { id, killed }
+}
+
+fn do_config_set(shared: &conf::Shared, key: String, value: String) -> types::Response {
+    match conf::set(shared, &key, &value) {
+        Ok(_) => {
+            info!("CONFIG SET {} {}", key, value);
+            types::Response::Done {}
+        }
+        Err(message) => types::Response::Error { message },
+    }
+}
+
+pub fn handle_request(
+    db: &Arc<state::Database>,
+    conf: &conf::Shared,
+    session: &mut session::Session,
+    line: String,
+) -> types::Response {
+    debug!("incoming request: {:?}", line);
+    let request = match types::Request::parse(&line) {
+        Ok(req) => req,
+        Err(e) => {
+            metrics::METRICS.command("UNKNOWN", false, Default::default());
+            return types::Response::Error { message: e };
+        }
+    };
+    dispatch(db, conf, session, request)
+}
+
+/// Runs an already parsed request, for front ends that don't speak the line
+/// protocol. Permissions are checked exactly as for `handle_request`.
+pub fn dispatch(
+    db: &Arc<state::Database>,
+    shared: &conf::Shared,
+    session: &mut session::Session,
+    request: types::Request,
+) -> types::Response {
+    let started = Instant::now();
+    let command = request.command();
+    let channel = request.channel_id().map(str::to_string);
+    let mutates = request.mutates();
+    let writes = request.writes();
+    let updated = match request {
+        types::Request::Update { ref uid, .. } => Some(uid.clone()),
+        _ => None,
+    };
+    clients::CLIENTS.touch(session.id, channel.as_deref());
+    let response = execute(db, shared, session, request);
+    let elapsed = started.elapsed();
+    let ok = !response.is_error();
+    metrics::METRICS.command(command, ok, elapsed);
+    session.written = if writes && ok {
+        Some(db.replication.lock().unwrap().seq())
+    } else {
+        None
+    };
+
+    let (threshold, max_len) = {
+        let conf = shared.read().unwrap();
+        (
+            conf.get::<u64>("slowlog.threshold").unwrap_or(SLOWLOG_THRESHOLD),
+            conf.get::<usize>("slowlog.max_len").unwrap_or(SLOWLOG_MAX_LEN),
+        )
+    };
+    if elapsed.as_millis() >= u128::from(threshold) {
+        let entry = slowlog::Entry {
+            id: 0,
+            started: Utc::now() - Duration::from_std(elapsed).unwrap_or_else(|_| Duration::zero()),
+            duration_us: elapsed.as_micros() as u64,
+            conn: session.id,
+            addr: session.addr.clone(),
+            command,
+            channel: channel.clone(),
+        };
+        slowlog::SLOWLOG.record(entry, max_len);
+    }
+
+    if mutates {
+        let uid = match response {
+            types::Response::Push { ref message } => Some(message.uid.as_str()),
+            _ => updated.as_deref(),
+        };
+        audit::record(audit::Event {
+            addr: session.addr.as_deref(),
+            identity: session.grants.as_ref().map(|grants| grants.identity.as_str()),
+            command,
+            channel: channel.as_deref(),
+            uid,
+            error: match response {
+                types::Response::Error { ref message } => Some(message),
+                _ => None,
+            },
+        });
+    }
+
+    logging::request(logging::Fields {
+        conn: session.id,
+        channel,
+        command,
+        latency_us: elapsed.as_micros() as u64,
+        ok,
+    });
+    response
+}
+
+fn execute(
+    db: &Arc<state::Database>,
+    shared: &conf::Shared,
+    session: &mut session::Session,
+    request: types::Request,
+) -> types::Response {
+    let _in_flight = match shutdown::TRACKER.begin() {
+        Some(in_flight) => in_flight,
+        None => {
+            return types::Response::Error {
+                message: "server is shutting down".to_string(),
+            };
+        }
+    };
+    if let Err(message) = request.check_ids() {
+        return types::Response::Error { message };
+    }
+    let conf = shared.read().unwrap();
+    if let Err(message) = auth::check(&conf, session.grants.as_ref(), &request) {
+        return types::Response::Error { message };
+    }
+    if request.writes() {
+        if let Err(message) = db.replication.lock().unwrap().writable(session.acknowledged) {
+            return types::Response::Error { message };
+        }
+    }
+    if let Err(message) = cluster::check(db, &conf, &request) {
+        return types::Response::Error { message };
+    }
+
+    match request {
+        types::Request::Auth { token } => do_auth(&conf, session, token),
+        types::Request::Push {
+            channel_id,
+            value,
+            key: None,
+        } => do_push(db, channel_id, value),
+        types::Request::Push {
+            channel_id,
+            value,
+            key: Some(key),
+        } => {
+            let window = conf
+                .get::<i64>("push.idempotency_window")
+                .unwrap_or(IDEMPOTENCY_WINDOW);
+            do_push_with_key(db, channel_id, key, value, window)
+        }
+        types::Request::Recent {
+            channel_id,
+            count,
+            offset,
+        } => {
+            let maximum = conf.get::<usize>("limits.recent").unwrap_or(MAXIMUM);
+            do_recent(db, channel_id, count, offset, maximum)
+        }
+        types::Request::Retrieve { channel_id, uid } => do_retrieve(db, channel_id, uid),
+        types::Request::Update {
+            channel_id,
+            uid,
+            value,
+        } => do_update(db, channel_id, uid, value),
+        types::Request::Connect { channel_id } => do_connect(db, channel_id),
+        types::Request::Flush {
+            channel_id,
+            archive,
+        } => do_flush(db, &conf, channel_id, archive),
+        types::Request::Backup { channel_id } => do_backup(db, &conf, channel_id),
+        types::Request::Stats { channel_id } => do_stats(db, channel_id),
+        types::Request::Subscribe { channel_id } => do_subscribe(db, session, channel_id),
+        types::Request::Unsubscribe { channel_id } => do_unsubscribe(db, session, channel_id),
+        types::Request::Rename {
+            channel_id,
+            target_id,
+        } => do_rename(db, &conf, channel_id, target_id),
+        types::Request::Copy {
+            channel_id,
+            target_id,
+        } => do_copy(db, &conf, channel_id, target_id),
+        types::Request::List {
+            pattern,
+            cursor,
+            count,
+        } => {
+            let maximum = conf.get::<usize>("limits.list").unwrap_or(LIST_MAXIMUM);
+            do_list(db, pattern, cursor, count, maximum)
+        }
+        types::Request::ConfigGet { key } => do_config_get(&conf, key),
+        types::Request::ConfigSet { key, value } => {
+            drop(conf);
+            do_config_set(shared, key, value)
+        }
+        types::Request::SlowlogGet { count } => do_slowlog_get(count),
+        types::Request::SlowlogReset => do_slowlog_reset(),
+        types::Request::Info => do_info(db, &conf),
+        types::Request::ClientList => do_client_list(),
+        types::Request::ClientKill { id } => do_client_kill(id),
+        types::Request::Replication => do_replication(db),
+        types::Request::Promote => do_promote(db),
+        types::Request::Import { channel_id, export } => do_import(db, channel_id, export),
+        types::Request::ClusterSlots => cluster::slots(&conf),
+        types::Request::Changes { from, count } => {
+            let maximum = conf.get::<usize>("limits.changes").unwrap_or(CHANGES_MAXIMUM);
+            do_changes(from, count, maximum)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::state;
+    use crate::lib::testing::{make_conf, make_db};
+    use serde_json::Value;
+    use futures::{Future, Stream};
+
+    fn make_pushes(db: &std::sync::Arc<state::Database>, channel_id: String, number: u16) {
+        for x in 0..number {
+            do_push(db, channel_id.to_string(), format!("{:?}", x));
+        }
+    }
+
+    ////////////////
+    // PUSH TESTS //
+    ////////////////
+
+    #[test]
+    fn do_push_receive_ok_response() {
+        let db = make_db();
+        let response = do_push(&db, String::from("foobar"), String::from("something"));
+        let message = response.serialize();
+        assert_eq!(&message[..2], "OK");
+    }
+
+    #[test]
+    fn do_push_receive_er_response() {
+        let db = make_db();
+        let response = do_push(&db, String::from("foobar"), String::from(""));
+        let message = response.serialize();
+        assert_eq!(&message[..2], "ER");
+    }
+
+    #[test]
+    fn do_push_message_stored() {
+        let db = make_db();
+        let text = String::from("something");
+        let response = do_push(&db, String::from("foobar"), text.clone());
+        let message = response.serialize();
+        let uid = &mut message[3..].to_string();
+        uid.pop();
 
         let channels = db.channels.lock().unwrap();
         let index = &channels
@@ -317,6 +1227,84 @@ mod tests {
         }
     }
 
+    #[test]
+    fn do_push_with_key_replay_returns_original_uid() {
+        let db = make_db();
+        let first = do_push_with_key(
+            &db,
+            String::from("foobar"),
+            String::from("abc"),
+            String::from("something"),
+            300,
+        );
+        let second = do_push_with_key(
+            &db,
+            String::from("foobar"),
+            String::from("abc"),
+            String::from("something"),
+            300,
+        );
+        assert_eq!(first.serialize(), second.serialize());
+
+        let channels = db.channels.lock().unwrap();
+        let data = channels.get("foobar").unwrap().data.lock().unwrap();
+        assert_eq!(data.len(), 1);
+    }
+
+    #[test]
+    fn do_push_with_key_distinct_keys_stored() {
+        let db = make_db();
+        let first = do_push_with_key(
+            &db,
+            String::from("foobar"),
+            String::from("abc"),
+            String::from("something"),
+            300,
+        );
+        let second = do_push_with_key(
+            &db,
+            String::from("foobar"),
+            String::from("xyz"),
+            String::from("something"),
+            300,
+        );
+        assert_ne!(first.serialize(), second.serialize());
+    }
+
+    #[test]
+    fn do_push_with_key_expired_window() {
+        let db = make_db();
+        do_push_with_key(
+            &db,
+            String::from("foobar"),
+            String::from("abc"),
+            String::from("something"),
+            0,
+        );
+        do_push_with_key(
+            &db,
+            String::from("foobar"),
+            String::from("abc"),
+            String::from("something"),
+            0,
+        );
+
+        let channels = db.channels.lock().unwrap();
+        let data = channels.get("foobar").unwrap().data.lock().unwrap();
+        assert_eq!(data.len(), 2);
+    }
+
+    #[test]
+    fn do_push_with_key_survives_a_huge_window() {
+        let db = make_db();
+        let push = || {
+            let (channel, key) = (String::from("foobar"), String::from("abc"));
+            do_push_with_key(&db, channel, key, String::from("something"), i64::MAX)
+        };
+        assert_eq!(push().serialize(), push().serialize());
+        assert!(db.channels.lock().is_ok());
+    }
+
     //////////////////
     // RECENT TESTS //
     //////////////////
@@ -325,7 +1313,7 @@ mod tests {
     fn do_recent_receive_ok_response() {
         let db = make_db();
         do_push(&db, String::from("foobar"), String::from("hello"));
-        let response = do_recent(&db, String::from("foobar"), 1, 0);
+        let response = do_recent(&db, String::from("foobar"), 1, 0, MAXIMUM);
         let message = response.serialize();
         assert_eq!(&message[..2], "OK");
     }
@@ -334,46 +1322,46 @@ mod tests {
     fn do_recent_proper_length() {
         let db = make_db();
 
-        let response = do_recent(&db, String::from("foobar"), 10, 0);
-        let mut message = response.serialize();
+        let response = do_recent(&db, String::from("foobar"), 10, 0, MAXIMUM);
+        let message = response.serialize();
         let json_string = &mut message[3..].to_string();
         json_string.pop();
         let messages: Value = serde_json::json!(json_string);
         assert_eq!(messages, String::from("No messages found"));
 
         make_pushes(&db, String::from("foobar"), 1);
-        let response = do_recent(&db, String::from("foobar"), 10, 0);
-        let mut message = response.serialize();
+        let response = do_recent(&db, String::from("foobar"), 10, 0, MAXIMUM);
+        let message = response.serialize();
         let json_string = &mut message[3..].to_string();
         json_string.pop();
         let messages: Value = serde_json::from_str(json_string).unwrap();
         assert_eq!(messages.as_array().unwrap().len(), 1);
 
         make_pushes(&db, String::from("foobar"), 1);
-        let response = do_recent(&db, String::from("foobar"), 10, 0);
-        let mut message = response.serialize();
+        let response = do_recent(&db, String::from("foobar"), 10, 0, MAXIMUM);
+        let message = response.serialize();
         let json_string = &mut message[3..].to_string();
         json_string.pop();
         let messages: Value = serde_json::from_str(json_string).unwrap();
         assert_eq!(messages.as_array().unwrap().len(), 2);
 
         make_pushes(&db, String::from("somethingelse"), 9);
-        let response = do_recent(&db, String::from("somethingelse"), 10, 0);
-        let mut message = response.serialize();
+        let response = do_recent(&db, String::from("somethingelse"), 10, 0, MAXIMUM);
+        let message = response.serialize();
         let json_string = &mut message[3..].to_string();
         json_string.pop();
         let messages: Value = serde_json::from_str(json_string).unwrap();
         assert_eq!(messages.as_array().unwrap().len(), 9);
-        let response = do_recent(&db, String::from("foobar"), 10, 0);
-        let mut message = response.serialize();
+        let response = do_recent(&db, String::from("foobar"), 10, 0, MAXIMUM);
+        let message = response.serialize();
         let json_string = &mut message[3..].to_string();
         json_string.pop();
         let messages: Value = serde_json::from_str(json_string).unwrap();
         assert_eq!(messages.as_array().unwrap().len(), 2);
 
         make_pushes(&db, String::from("foobar"), 9);
-        let response = do_recent(&db, String::from("foobar"), 10, 0);
-        let mut message = response.serialize();
+        let response = do_recent(&db, String::from("foobar"), 10, 0, MAXIMUM);
+        let message = response.serialize();
         let json_string = &mut message[3..].to_string();
         json_string.pop();
         let messages: Value = serde_json::from_str(json_string).unwrap();
@@ -384,13 +1372,13 @@ mod tests {
     fn do_recent_receive_er_response() {
         let db = make_db();
 
-        let response = do_recent(&db, String::from("foobar"), 1, 0);
+        let response = do_recent(&db, String::from("foobar"), 1, 0, MAXIMUM);
         let message = response.serialize();
         assert_eq!(&message[..2], "ER");
 
         make_pushes(&db, String::from("foobar"), 2);
 
-        let response = do_recent(&db, String::from("foobar"), 2, 1);
+        let response = do_recent(&db, String::from("foobar"), 2, 1, MAXIMUM);
         let message = response.serialize();
         assert_eq!(&message[..2], "ER");
     }
@@ -409,8 +1397,10 @@ mod tests {
 
         let json_string = &mut message[3..].to_string();
         json_string.pop();
-        let messages: Value = serde_json::json!(json_string);
-        assert_eq!(messages, String::from("Messages: -"));
+        let stats: Value = serde_json::from_str(json_string).unwrap();
+        assert_eq!(stats["channel"], "foobar");
+        assert_eq!(stats["messages"], 0);
+        assert_eq!(stats["first_created"], Value::Null);
     }
 
     #[test]
@@ -425,8 +1415,638 @@ mod tests {
 
         let json_string = &mut message[3..].to_string();
         json_string.pop();
-        let messages: Value = serde_json::json!(json_string);
-        assert_eq!(messages, String::from("Messages: 1"));
+        let stats: Value = serde_json::from_str(json_string).unwrap();
+        assert_eq!(stats["messages"], 1);
+        assert_eq!(stats["pushes"], 1);
+        assert_eq!(stats["dirty"], true);
+        assert!(stats["bytes"].as_u64().unwrap() > 0);
+        assert_eq!(stats["first_created"], stats["last_created"]);
+    }
+
+    #[test]
+    fn do_stats_tracks_updates_and_backups() {
+        let db = make_db();
+        let conf = make_conf("stats");
+        let response = do_push(&db, String::from("foobar"), String::from("something"));
+        let mut uid = response.serialize()[3..].to_string();
+        uid.pop();
+        do_update(&db, String::from("foobar"), uid, String::from("else"));
+        do_backup(&db, &conf, String::from("foobar"));
+
+        let response = do_stats(&db, String::from("foobar"));
+        let message = response.serialize();
+        let json_string = &mut message[3..].to_string();
+        json_string.pop();
+        let stats: Value = serde_json::from_str(json_string).unwrap();
+        assert_eq!(stats["updates"], 1);
+        assert_eq!(stats["dirty"], false);
+        assert!(stats["last_backup"].is_string());
+    }
+
+    #[test]
+    fn do_stats_server_wide() {
+        let db = make_db();
+
+        make_pushes(&db, String::from("foobar"), 2);
+        make_pushes(&db, String::from("somethingelse"), 3);
+
+        let response = do_stats(&db, String::from("*"));
+        let message = response.serialize();
+        assert_eq!(&message[..2], "OK");
+
+        let json_string = &mut message[3..].to_string();
+        json_string.pop();
+        let stats: Value = serde_json::from_str(json_string).unwrap();
+        assert_eq!(stats["channels"], 2);
+        assert_eq!(stats["messages"], 5);
+        assert_eq!(stats["dirty_channels"], 2);
+    }
+
+    ////////////////
+    // LIST TESTS //
+    ////////////////
+
+    #[test]
+    fn do_list_receive_ok_response() {
+        let db = make_db();
+        let response = do_list(&db, String::from("*"), String::from("0"), 10, LIST_MAXIMUM);
+        let message = response.serialize();
+        assert_eq!(&message[..2], "OK");
+    }
+
+    #[test]
+    fn do_list_matches_pattern() {
+        let db = make_db();
+        make_pushes(&db, String::from("room-1"), 2);
+        make_pushes(&db, String::from("room-2"), 1);
+        make_pushes(&db, String::from("feed"), 1);
+
+        let response = do_list(&db, String::from("room-*"), String::from("0"), 10, LIST_MAXIMUM);
+        let message = response.serialize();
+        let json_string = &mut message[3..].to_string();
+        json_string.pop();
+        let listing: Value = serde_json::from_str(json_string).unwrap();
+        let channels = listing["channels"].as_array().unwrap();
+        assert_eq!(listing["cursor"], "0");
+        assert_eq!(channels.len(), 2);
+        assert_eq!(channels[0]["channel"], "room-1");
+        assert_eq!(channels[0]["messages"], 2);
+        assert_eq!(channels[1]["channel"], "room-2");
+    }
+
+    #[test]
+    fn do_list_paginates() {
+        let db = make_db();
+        for x in 0..5 {
+            make_pushes(&db, format!("room-{}", x), 1);
+        }
+
+        let mut cursor = String::from("0");
+        let mut seen = Vec::new();
+        loop {
+            let response = do_list(&db, String::from("*"), cursor.clone(), 2, LIST_MAXIMUM);
+            let message = response.serialize();
+            let json_string = &mut message[3..].to_string();
+            json_string.pop();
+            let listing: Value = serde_json::from_str(json_string).unwrap();
+            for channel in listing["channels"].as_array().unwrap() {
+                seen.push(channel["channel"].as_str().unwrap().to_string());
+            }
+            cursor = listing["cursor"].as_str().unwrap().to_string();
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(seen, vec!["room-0", "room-1", "room-2", "room-3", "room-4"]);
+    }
+
+    #[test]
+    fn do_list_invalid_cursor() {
+        let db = make_db();
+        let response = do_list(&db, String::from("*"), String::from("zz"), 10, LIST_MAXIMUM);
+        let message = response.serialize();
+        assert_eq!(&message[..2], "ER");
+    }
+
+    /////////////////
+    // FLUSH TESTS //
+    /////////////////
+
+    #[test]
+    fn do_flush_removes_persisted_channel() {
+        let db = make_db();
+        let conf = make_conf("flush");
+        make_pushes(&db, String::from("foobar"), 2);
+        do_backup(&db, &conf, String::from("foobar"));
+        assert!(Path::new(&channel_path(&conf, "foobar").unwrap()).exists());
+
+        let response = do_flush(&db, &conf, String::from("foobar"), false);
+        assert_eq!(&response.serialize()[..2], "OK");
+        assert!(!db.channels.lock().unwrap().contains_key("foobar"));
+        assert!(!Path::new(&channel_path(&conf, "foobar").unwrap()).exists());
+    }
+
+    #[test]
+    fn do_flush_archive_moves_persisted_channel() {
+        let db = make_db();
+        let conf = make_conf("flush-archive");
+        make_pushes(&db, String::from("foobar"), 2);
+
+        let response = do_flush(&db, &conf, String::from("foobar"), true);
+        assert_eq!(&response.serialize()[..2], "OK");
+        assert!(!db.channels.lock().unwrap().contains_key("foobar"));
+        assert!(!Path::new(&channel_path(&conf, "foobar").unwrap()).exists());
+
+        let archive = format!("{}-archive", conf.get::<String>("persistence.path").unwrap());
+        let archived: Vec<_> = fs::read_dir(&archive).unwrap().collect();
+        assert_eq!(archived.len(), 1);
+        let archived = archived[0].as_ref().unwrap().path();
+        assert!(archived.join("data.mrkv").exists());
+        let _ = fs::remove_dir_all(&archive);
+    }
+
+    #[test]
+    fn handle_request_refuses_unsafe_channel_ids() {
+        let db = make_db();
+        let conf = make_conf("flush-unsafe");
+        let path = conf.get::<String>("persistence.path").unwrap();
+        make_pushes(&db, String::from("foobar"), 1);
+        do_backup(&db, &conf, String::from("foobar"));
+
+        let response = do_flush(&db, &conf, String::from(".."), false);
+        assert_eq!(&response.serialize()[..2], "ER");
+        let shared = conf::shared(conf);
+        let mut session = session::Session::new();
+        for line in &[" FLUSH", ".. FLUSH", ". FLUSH", "a/.. FLUSH", "..\\x PUSH hi"] {
+            let response = handle_request(&db, &shared, &mut session, line.to_string());
+            let channel_id = line.split(' ').next().unwrap();
+            let expected = format!("ER invalid channel_id: {:?}\n", channel_id);
+            assert_eq!(response.serialize(), expected);
+        }
+        assert!(Path::new(&path).join("foobar").join("data.mrkv").exists());
+        let _ = fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn create_db_skips_directories_without_a_backup() {
+        let conf = make_conf("create-db");
+        let path = conf.get::<String>("persistence.path").unwrap();
+        let db = make_db();
+        make_pushes(&db, String::from("foobar"), 2);
+        do_backup(&db, &conf, String::from("foobar"));
+        create_dir_all(Path::new(&path).join("stray")).unwrap();
+
+        let loaded = state::create_db(path.clone());
+        let channels = loaded.channels.lock().unwrap();
+        assert_eq!(channels.keys().collect::<Vec<_>>(), vec!["foobar"]);
+        let _ = fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn handle_request_refuses_reserved_channel_ids() {
+        let db = make_db();
+        let conf = conf::shared(make_conf("reserved"));
+        let mut session = session::Session::new();
+        for channel_id in types::RESERVED_CHANNEL_IDS {
+            let rename = format!("foo RENAME {}", channel_id);
+            let response = handle_request(&db, &conf, &mut session, rename);
+            let expected = format!("ER invalid channel_id: {:?}\n", channel_id);
+            assert_eq!(response.serialize(), expected);
+        }
+        assert!(types::check_channel_id("stats").is_ok());
+    }
+
+    ///////////////////////
+    // RENAME/COPY TESTS //
+    ///////////////////////
+
+    #[test]
+    fn do_rename_moves_channel() {
+        let db = make_db();
+        let conf = make_conf("rename");
+        make_pushes(&db, String::from("foobar"), 3);
+        do_backup(&db, &conf, String::from("foobar"));
+
+        let response = do_rename(&db, &conf, String::from("foobar"), String::from("archived"));
+        assert_eq!(&response.serialize()[..2], "OK");
+
+        let channels = db.channels.lock().unwrap();
+        assert!(!channels.contains_key("foobar"));
+        assert_eq!(channels.get("archived").unwrap().data.lock().unwrap().len(), 3);
+        assert!(!Path::new(&channel_path(&conf, "foobar").unwrap()).exists());
+        assert!(Path::new(&channel_path(&conf, "archived").unwrap()).join("data.mrkv").exists());
+        let _ = fs::remove_dir_all(conf.get::<String>("persistence.path").unwrap());
+    }
+
+    #[test]
+    fn do_rename_receive_er_response() {
+        let db = make_db();
+        let conf = make_conf("rename-er");
+        make_pushes(&db, String::from("foobar"), 1);
+        make_pushes(&db, String::from("other"), 1);
+
+        let response = do_rename(&db, &conf, String::from("missing"), String::from("new"));
+        assert_eq!(&response.serialize()[..2], "ER");
+
+        let response = do_rename(&db, &conf, String::from("foobar"), String::from("other"));
+        assert_eq!(&response.serialize()[..2], "ER");
+
+        let response = do_rename(&db, &conf, String::from("foobar"), String::from("../x"));
+        assert_eq!(&response.serialize()[..2], "ER");
+        assert!(db.channels.lock().unwrap().contains_key("foobar"));
+        let _ = fs::remove_dir_all(conf.get::<String>("persistence.path").unwrap());
+    }
+
+    #[test]
+    fn handle_request_refuses_unsafe_targets() {
+        let db = make_db();
+        let conf = make_conf("rename-unsafe");
+        let path = conf.get::<String>("persistence.path").unwrap();
+        let outside = format!("{}-outside", path);
+        fs::create_dir_all(&outside).unwrap();
+        make_pushes(&db, String::from("foobar"), 1);
+        let shared = conf::shared(conf);
+        let mut session = session::Session::new();
+        let target = format!("../{}", Path::new(&outside).file_name().unwrap().to_str().unwrap());
+        for command in &["RENAME", "COPY"] {
+            let line = format!("foobar {} {}", command, target);
+            let response = handle_request(&db, &shared, &mut session, line);
+            let expected = format!("ER invalid channel_id: {:?}\n", target);
+            assert_eq!(response.serialize(), expected);
+        }
+        assert!(Path::new(&outside).exists());
+        let _ = fs::remove_dir_all(&outside);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn do_rename_keeps_symlinked_targets() {
+        let db = make_db();
+        let conf = make_conf("rename-symlink");
+        let path = conf.get::<String>("persistence.path").unwrap();
+        let outside = format!("{}-outside", path);
+        fs::create_dir_all(&outside).unwrap();
+        fs::create_dir_all(&path).unwrap();
+        std::os::unix::fs::symlink(&outside, Path::new(&path).join("linked")).unwrap();
+        make_pushes(&db, String::from("foobar"), 1);
+
+        let response = do_rename(&db, &conf, String::from("foobar"), String::from("linked"));
+        assert_eq!(&response.serialize()[..2], "ER");
+        assert!(Path::new(&outside).exists());
+        let _ = fs::remove_dir_all(&outside);
+        let _ = fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn do_copy_duplicates_channel() {
+        let db = make_db();
+        let conf = make_conf("copy");
+        make_pushes(&db, String::from("foobar"), 2);
+        do_backup(&db, &conf, String::from("foobar"));
+
+        let response = do_copy(&db, &conf, String::from("foobar"), String::from("snapshot"));
+        assert_eq!(&response.serialize()[..2], "OK");
+        make_pushes(&db, String::from("foobar"), 1);
+
+        let channels = db.channels.lock().unwrap();
+        assert_eq!(channels.get("foobar").unwrap().data.lock().unwrap().len(), 3);
+        assert_eq!(channels.get("snapshot").unwrap().data.lock().unwrap().len(), 2);
+        assert!(Path::new(&channel_path(&conf, "foobar").unwrap()).join("data.mrkv").exists());
+        assert!(Path::new(&channel_path(&conf, "snapshot").unwrap()).join("data.mrkv").exists());
+        let _ = fs::remove_dir_all(conf.get::<String>("persistence.path").unwrap());
+    }
+
+    #[test]
+    fn backup_dirty_writes_changed_channels() {
+        let db = make_db();
+        let conf = make_conf("backup-dirty");
+        do_push(&db, String::from("clean"), String::from("one"));
+        do_push(&db, String::from("dirty"), String::from("one"));
+        do_backup(&db, &conf, String::from("clean"));
+        do_push(&db, String::from("dirty"), String::from("two"));
+
+        let (written, errors) = backup_dirty(&db, &conf);
+        assert_eq!(written, 1);
+        assert!(errors.is_empty());
+        assert!(Path::new(&channel_path(&conf, "dirty").unwrap()).join("data.mrkv").exists());
+        assert_eq!(backup_dirty(&db, &conf).0, 0);
+    }
+
+    #[test]
+    fn apply_retention_drops_the_oldest_messages() {
+        let db = make_db();
+        let mut conf = make_conf("retention");
+        make_pushes(&db, String::from("foobar"), 5);
+        assert_eq!(apply_retention(&db, &conf), 0);
+
+        conf.set("retention.max_len", 3).unwrap();
+        assert_eq!(apply_retention(&db, &conf), 2);
+        let response = do_recent(&db, String::from("foobar"), 10, 0, MAXIMUM);
+        let messages = match response {
+            types::Response::Recent { messages } => messages,
+            _ => panic!("expected messages"),
+        };
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].value, "2");
+        let response = do_retrieve(&db, String::from("foobar"), messages[2].uid.clone());
+        assert!(response.serialize().contains("\"value\":\"4\""));
+
+        conf.set("retention.max_len", 0).unwrap();
+        conf.set("retention.max_age", 3600).unwrap();
+        assert_eq!(apply_retention(&db, &conf), 0);
+        {
+            let channels = db.channels.lock().unwrap();
+            let mut data = channels["foobar"].data.lock().unwrap();
+            data[0].created = Utc::now() - Duration::hours(2);
+        }
+        assert_eq!(apply_retention(&db, &conf), 1);
+        assert_eq!(db.channels.lock().unwrap()["foobar"].data.lock().unwrap().len(), 2);
+    }
+
+    /////////////////////
+    // SUBSCRIBE TESTS //
+    /////////////////////
+
+    #[test]
+    fn do_subscribe_receives_pushes() {
+        let db = make_db();
+        let (feed, mut receiver) = futures::sync::mpsc::unbounded();
+        let mut session = session::Session::new();
+        session.feed = Some(feed);
+
+        let response = do_subscribe(&db, &session, String::from("foobar"));
+        assert_eq!(&response.serialize()[..2], "OK");
+        do_push(&db, String::from("foobar"), String::from("hello"));
+        do_push(&db, String::from("other"), String::from("ignored"));
+
+        match receiver.poll() {
+            Ok(futures::Async::Ready(Some(message))) => assert_eq!(message.value, "hello"),
+            _ => panic!("expected a pushed message"),
+        }
+
+        do_unsubscribe(&db, &session, String::from("foobar"));
+        do_push(&db, String::from("foobar"), String::from("missed"));
+        assert!(db.subscribers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn do_subscribe_needs_feed() {
+        let db = make_db();
+        let session = session::Session::new();
+        let response = do_subscribe(&db, &session, String::from("foobar"));
+        assert_eq!(&response.serialize()[..2], "ER");
+    }
+
+    ////////////////
+    // AUTH TESTS //
+    ////////////////
+
+    fn make_auth_conf(name: &str) -> config::Config {
+        let mut conf = make_conf(name);
+        conf.set("auth.enabled", true).unwrap();
+        conf.set("auth.tokens.chat.token", "chat-token").unwrap();
+        conf.set("auth.tokens.chat.read", vec!["room-*"]).unwrap();
+        conf.set("auth.tokens.chat.write", vec!["room-*"]).unwrap();
+        conf.set("auth.tokens.ops.token", "ops-token").unwrap();
+        conf.set("auth.tokens.ops.admin", vec!["*"]).unwrap();
+        conf
+    }
+
+    #[test]
+    fn handle_request_without_auth_config() {
+        let db = make_db();
+        let conf = conf::shared(make_conf("auth-disabled"));
+        let mut session = session::Session::new();
+        let response = handle_request(&db, &conf, &mut session, String::from("foo PUSH hello"));
+        assert_eq!(&response.serialize()[..2], "OK");
+    }
+
+    #[test]
+    fn handle_request_pushes_values_starting_with_key_as_written() {
+        let db = make_db();
+        let conf = conf::shared(make_conf("push-key-value"));
+        let mut session = session::Session::new();
+        for line in &["foo PUSH KEY lime", "foo PUSHKEY lime KEY", "foo PUSHKEY lime again"] {
+            let response = handle_request(&db, &conf, &mut session, line.to_string());
+            assert_eq!(&response.serialize()[..2], "OK");
+        }
+
+        let channels = db.channels.lock().unwrap();
+        let data = channels.get("foo").unwrap().data.lock().unwrap();
+        let values: Vec<&str> = data.iter().map(|message| message.value.as_str()).collect();
+        assert_eq!(values, vec!["KEY lime", "KEY"]);
+    }
+
+    #[test]
+    fn handle_request_requires_auth() {
+        let db = make_db();
+        let conf = conf::shared(make_auth_conf("auth-required"));
+        let mut session = session::Session::new();
+        let response = handle_request(&db, &conf, &mut session, String::from("room-1 PUSH hello"));
+        assert_eq!(response.serialize(), "ER NOAUTH authentication required\n");
+
+        let response = handle_request(&db, &conf, &mut session, String::from("AUTH wrong"));
+        assert_eq!(&response.serialize()[..2], "ER");
+        assert!(session.grants.is_none());
+    }
+
+    #[test]
+    fn handle_request_enforces_channel_acls() {
+        let db = make_db();
+        let conf = conf::shared(make_auth_conf("auth-acl"));
+        let mut session = session::Session::new();
+        let response = handle_request(&db, &conf, &mut session, String::from("AUTH chat-token"));
+        assert_eq!(&response.serialize()[..2], "OK");
+
+        let response = handle_request(&db, &conf, &mut session, String::from("room-1 PUSH hello"));
+        assert_eq!(&response.serialize()[..2], "OK");
+        let response = handle_request(&db, &conf, &mut session, String::from("room-1 RECENT"));
+        assert_eq!(&response.serialize()[..2], "OK");
+
+        let response = handle_request(&db, &conf, &mut session, String::from("feed PUSH hello"));
+        assert!(response.serialize().starts_with("ER NOPERM"));
+        let response = handle_request(&db, &conf, &mut session, String::from("room-1 FLUSH"));
+        assert!(response.serialize().starts_with("ER NOPERM"));
+        assert!(db.channels.lock().unwrap().contains_key("room-1"));
+
+        let response = handle_request(&db, &conf, &mut session, String::from("AUTH ops-token"));
+        assert_eq!(&response.serialize()[..2], "OK");
+        let response = handle_request(&db, &conf, &mut session, String::from("room-1 FLUSH"));
+        assert_eq!(&response.serialize()[..2], "OK");
+    }
+
+    #[test]
+    fn handle_request_config_get_and_set() {
+        let db = make_db();
+        let conf = conf::shared(make_auth_conf("config"));
+        let mut session = session::Session::new();
+        handle_request(&db, &conf, &mut session, String::from("AUTH chat-token"));
+        let response = handle_request(
+            &db,
+            &conf,
+            &mut session,
+            String::from("CONFIG SET persistence.interval 30"),
+        );
+        assert!(response.serialize().starts_with("ER NOPERM"));
+
+        handle_request(&db, &conf, &mut session, String::from("AUTH ops-token"));
+        let response = handle_request(
+            &db,
+            &conf,
+            &mut session,
+            String::from("CONFIG SET persistence.interval 30"),
+        );
+        assert_eq!(response.serialize(), "OK Done.\n");
+        let response = handle_request(
+            &db,
+            &conf,
+            &mut session,
+            String::from("CONFIG GET persistence.interval"),
+        );
+        assert_eq!(response.serialize(), "OK 30\n");
+        let response = handle_request(
+            &db,
+            &conf,
+            &mut session,
+            String::from("CONFIG SET persistence.path /tmp"),
+        );
+        assert_eq!(&response.serialize()[..2], "ER");
+        let response = handle_request(
+            &db,
+            &conf,
+            &mut session,
+            String::from("CONFIG GET auth.enabled"),
+        );
+        assert_eq!(&response.serialize()[..2], "ER");
+    }
+
+    #[test]
+    fn handle_request_slowlog_get_and_reset() {
+        let db = make_db();
+        let mut conf = make_conf("slowlog");
+        conf.set("slowlog.threshold", 0).unwrap();
+        let conf = conf::shared(conf);
+        let mut session = session::Session::new();
+        session.addr = Some("127.0.0.1:50000".to_string());
+        handle_request(&db, &conf, &mut session, String::from("slowlog-test PUSH one"));
+
+        let response = handle_request(&db, &conf, &mut session, String::from("SLOWLOG GET 1000"));
+        let message = response.serialize();
+        let entries: Value = serde_json::from_str(&message[3..]).unwrap();
+        let entry = entries
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|entry| entry["channel"] == "slowlog-test")
+            .unwrap();
+        assert_eq!(entry["command"], "PUSH");
+        assert_eq!(entry["addr"], "127.0.0.1:50000");
+        assert_eq!(entry["conn"], session.id);
+
+        let response = handle_request(&db, &conf, &mut session, String::from("SLOWLOG RESET"));
+        assert_eq!(response.serialize(), "OK Done.\n");
+        let response = handle_request(&db, &conf, &mut session, String::from("SLOWLOG GET 1000"));
+        assert!(!response.serialize().contains("slowlog-test"));
+        let response = handle_request(&db, &conf, &mut session, String::from("SLOWLOG GET x"));
+        assert_eq!(&response.serialize()[..2], "ER");
+    }
+
+    #[test]
+    fn handle_request_info_and_client_list_and_kill() {
+        let db = make_db();
+        let conf = conf::shared(make_conf("info"));
+        let addr = Some("127.0.0.1:50001".to_string());
+        let (mut session, killed) = session::Session::connect("tcp", addr);
+        handle_request(&db, &conf, &mut session, String::from("info-test PUSH one"));
+
+        let response = handle_request(&db, &conf, &mut session, String::from("INFO"));
+        let message = response.serialize();
+        let info: Value = serde_json::from_str(&message[3..]).unwrap();
+        assert!(info["connections"]["connected"].as_u64().unwrap() >= 1);
+        assert!(info["memory"]["channels"].as_u64().unwrap() >= 1);
+
+        let response = handle_request(&db, &conf, &mut session, String::from("CLIENT LIST"));
+        let message = response.serialize();
+        let clients: Value = serde_json::from_str(&message[3..]).unwrap();
+        let client = clients
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|client| client["id"] == session.id)
+            .unwrap();
+        assert_eq!(client["addr"], "127.0.0.1:50001");
+        assert_eq!(client["channel"], "info-test");
+        assert_eq!(client["commands"], 3);
+
+        let kill = format!("CLIENT KILL {}", session.id);
+        let response = handle_request(&db, &conf, &mut session, kill);
+        assert_eq!(response.serialize(), "OK Done.\n");
+        assert_eq!(killed.wait(), Ok(()));
+        let response = handle_request(&db, &conf, &mut session, String::from("CLIENT KILL 0"));
+        assert_eq!(&response.serialize()[..2], "ER");
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn handle_request_redirects_and_imports_in_cluster_mode() {
+        let db = make_db();
+        let mut config = make_conf("cluster");
+        let (here, there) = ("127.0.0.1:7001", "127.0.0.1:7002");
+        config.set("network.address", here).unwrap();
+        config.set("cluster.enabled", true).unwrap();
+        config.set("cluster.nodes", vec![here, there]).unwrap();
+        let conf = conf::shared(config);
+        let mut session = session::Session::new();
+        let ring = cluster::RINGS.get(&conf.read().unwrap()).unwrap();
+        let channel = |owner: &str| {
+            (0..)
+                .map(|i| format!("room-{}", i))
+                .find(|channel| ring.owner(channel) == owner)
+                .unwrap()
+        };
+        let (local, remote) = (channel(here), channel(there));
+
+        let push = format!("{} PUSH one", remote);
+        let response = handle_request(&db, &conf, &mut session, push);
+        assert_eq!(response.serialize(), "ER MOVED 127.0.0.1:7002\n");
+        let copy = format!("{} COPY {}", local, remote);
+        let response = handle_request(&db, &conf, &mut session, copy);
+        assert_eq!(&response.serialize()[..12], "ER CROSSNODE");
+
+        // A channel still held here is served until it has moved.
+        let key = || "k1".to_string();
+        let held = do_push_with_key(&db, remote.clone(), key(), "held".to_string(), 60);
+        let recent = format!("{} RECENT", remote);
+        let response = handle_request(&db, &conf, &mut session, recent.clone());
+        assert!(response.serialize().contains("held"));
+        let messages = take_channel(&db, &remote).unwrap();
+        assert_eq!((messages.keys.len(), messages.pushes), (1, 1));
+        let response = handle_request(&db, &conf, &mut session, recent);
+        assert_eq!(response.serialize(), "ER MOVED 127.0.0.1:7002\n");
+
+        handle_request(&db, &conf, &mut session, format!("{} PUSH newer", local));
+        let import = format!("{} IMPORT {}", local, serde_json::to_string(&messages).unwrap());
+        let response = handle_request(&db, &conf, &mut session, import.clone());
+        assert_eq!(response.serialize(), "OK 1\n");
+        let response = handle_request(&db, &conf, &mut session, import);
+        assert_eq!(response.serialize(), "OK 0\n");
+        let recent = format!("{} RECENT", local);
+        let message = handle_request(&db, &conf, &mut session, recent).serialize();
+        let recent: Vec<state::Message> = serde_json::from_str(&message[3..]).unwrap();
+        let values: Vec<&str> = recent.iter().map(|message| message.value.as_str()).collect();
+        assert_eq!(values, vec!["held", "newer"]);
+
+        // A push retried after the move is not stored twice.
+        let retried = do_push_with_key(&db, local.clone(), key(), "held".to_string(), 60);
+        assert_eq!(retried.serialize(), held.serialize());
+        let channels = db.channels.lock().unwrap();
+        assert_eq!(channels[&local].data.lock().unwrap().len(), 2);
+        assert_eq!(channels[&local].counters.lock().unwrap().pushes, 3);
+        drop(channels);
+
+        let response = handle_request(&db, &conf, &mut session, String::from("CLUSTER SLOTS"));
+        let slots: Value = serde_json::from_str(&response.serialize()[3..]).unwrap();
+        assert_eq!(slots["nodes"], serde_json::json!([here, there]));
     }
 
     ////////////////////
@@ -437,7 +2057,7 @@ mod tests {
     fn do_retrieve_receive_ok_response() {
         let db = make_db();
         let response = do_push(&db, String::from("foobar"), String::from("something"));
-        let mut message = response.serialize();
+        let message = response.serialize();
         let uid = &mut message[3..].to_string();
         uid.pop();
 
@@ -450,12 +2070,12 @@ mod tests {
     fn do_retrieve_receive_er_response() {
         let db = make_db();
         let response = do_push(&db, String::from("foobar"), String::from("something"));
-        let mut message = response.serialize();
+        let message = response.serialize();
         let uid = &mut message[3..].to_string();
         uid.pop();
 
         let response = do_retrieve(&db, String::from("oops"), uid.to_string());
-        let mut message = response.serialize();
+        let message = response.serialize();
         assert_eq!(&message[..2], "ER");
         let json_string = &mut message[3..].to_string();
         json_string.pop();
@@ -468,12 +2088,12 @@ mod tests {
         let db = make_db();
         let text = String::from("something");
         let response = do_push(&db, String::from("foobar"), text.clone());
-        let mut message = response.serialize();
+        let message = response.serialize();
         let uid = &mut message[3..].to_string();
         uid.pop();
 
         let response = do_retrieve(&db, String::from("foobar"), uid.to_string());
-        let mut message = response.serialize();
+        let message = response.serialize();
         let json_string = &mut message[3..].to_string();
         let message_value: Value = serde_json::from_str(json_string).unwrap();
         let message = message_value.as_object().unwrap();
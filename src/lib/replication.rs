@@ -0,0 +1,1432 @@
+use crate::lib::{auth, conf, operations, state, types};
+use futures::future::{self, join_all, loop_fn, Either, Loop};
+use futures::stream::iter_ok;
+use futures::sync::mpsc::{channel, Receiver, Sender};
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, BufReader};
+use std::mem;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{lines, write_all, AsyncRead, ReadHalf, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::{Delay, Interval, Timeout};
+
+/// How long a follower waits before reconnecting to its leader.
+const RECONNECT: Duration = Duration::from_millis(500);
+/// How often followers are pinged and, with elections on, how often the
+/// leader announces itself and followers check on it.
+const HEARTBEAT: Duration = Duration::from_millis(250);
+const ELECTION_TIMEOUT: u64 = 1000;
+const ACK_TIMEOUT: u64 = 2000;
+/// Frames a follower can fall behind by before it is disconnected, to
+/// resync from a full copy when it reconnects.
+const BACKLOG: usize = 10_000;
+
+/// A change to channel data, sent to followers in the order it was made.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Change {
+    /// `key` is the idempotency key of a `PUSHKEY`, so a retry after a
+    /// failover isn't stored twice.
+    Push {
+        channel: String,
+        message: state::Message,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    Update {
+        channel: String,
+        uid: String,
+        value: String,
+    },
+    Flush {
+        channel: String,
+        archive: bool,
+    },
+    Rename {
+        channel: String,
+        target: String,
+    },
+    Copy {
+        channel: String,
+        target: String,
+    },
+    Import {
+        channel: String,
+        #[serde(flatten)]
+        export: state::Export,
+    },
+}
+
+/// One line sent by a node to another. A follower is first sent every
+/// channel, then `synced`, then each change as it is made, and answers each
+/// change and `ping` with `ACK <seq>`, the last change it applied.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Frame {
+    Channel {
+        channel: String,
+        messages: Vec<state::Message>,
+        #[serde(default)]
+        keys: HashMap<String, state::IdempotencyKey>,
+    },
+    Synced {
+        seq: u64,
+        epoch: u64,
+        last_epoch: u64,
+    },
+    Change {
+        seq: u64,
+        change: Change,
+    },
+    Ping {
+        seq: u64,
+    },
+    /// Sent instead of a snapshot by a node that can't be followed, naming
+    /// the leader when it knows it.
+    Refused {
+        message: String,
+        leader: Option<String>,
+    },
+    /// The answer to `VOTE`.
+    Vote {
+        epoch: u64,
+        granted: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Leader,
+    Follower,
+    Candidate,
+}
+
+#[derive(Debug)]
+struct Follower {
+    addr: Option<String>,
+    /// The follower's own replication address, when it has one.
+    node: Option<String>,
+    feed: Sender<Frame>,
+    connected: Instant,
+    acked: u64,
+}
+
+#[derive(Debug, Default)]
+struct Upstream {
+    /// The leader's replication address, if this node knows it.
+    addr: Option<String>,
+    connected: bool,
+    /// The leader's position, as of the last frame from it.
+    seq: u64,
+    last_contact: Option<Instant>,
+}
+
+/// The epoch and vote, kept in `.epoch` under `persistence.path` so a
+/// restarted node neither goes back to an old epoch nor votes twice.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Saved {
+    epoch: u64,
+    voted_for: Option<String>,
+}
+
+/// Where changes made on this node are sent and, on a follower, where
+/// they come from. `seq` numbers every change; a follower's matches its
+/// leader's once it has caught up. `epoch` goes up each time a new leader
+/// takes over, and nodes refuse anything from an older one.
+#[derive(Debug)]
+pub struct Hub {
+    role: Role,
+    epoch: u64,
+    voted_for: Option<String>,
+    seq: u64,
+    /// The epoch change `seq` was made in.
+    last_epoch: u64,
+    /// With elections on, the last change a majority of nodes has applied.
+    committed: u64,
+    next_id: u64,
+    followers: BTreeMap<u64, Follower>,
+    waiters: Vec<(u64, oneshot::Sender<()>)>,
+    upstream: Upstream,
+    /// Closes the connection to the leader when it is replaced.
+    cancel: Option<oneshot::Sender<()>>,
+    /// This node's replication address, and the others taking part in
+    /// elections.
+    address: Option<String>,
+    peers: Vec<String>,
+    election_timeout: Duration,
+    /// When this node last heard from a leader or granted a vote, and how
+    /// long until it stands for election, picked at random so that nodes
+    /// rarely stand at once.
+    heard: Instant,
+    timeout: Duration,
+    campaign: bool,
+    /// A former leader still to be told about a `PROMOTE`.
+    fence: Option<String>,
+    saved: Option<PathBuf>,
+    /// `replication.token`, sent with every line to another node and
+    /// required of every node that connects.
+    token: Option<String>,
+    /// `replication.leader`, which with `peers` are the only nodes this
+    /// one follows or votes for.
+    leader: Option<String>,
+}
+
+/// A random duration between `base` and twice `base`.
+fn jitter(base: Duration) -> Duration {
+    let mut bytes = [0; 2];
+    let _ = SystemRandom::new().fill(&mut bytes);
+    base + base * u32::from(u16::from_le_bytes(bytes)) / 65536
+}
+
+impl Hub {
+    pub fn new() -> Hub {
+        let election_timeout = Duration::from_millis(ELECTION_TIMEOUT);
+        Hub {
+            role: Role::Leader,
+            epoch: 0,
+            voted_for: None,
+            seq: 0,
+            last_epoch: 0,
+            committed: 0,
+            next_id: 1,
+            followers: BTreeMap::new(),
+            waiters: Vec::new(),
+            upstream: Upstream::default(),
+            cancel: None,
+            address: None,
+            peers: Vec::new(),
+            election_timeout,
+            heard: Instant::now(),
+            timeout: jitter(election_timeout),
+            campaign: false,
+            fence: None,
+            saved: None,
+            token: None,
+            leader: None,
+        }
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    fn elections(&self) -> bool {
+        !self.peers.is_empty()
+    }
+
+    fn majority(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    /// Whether clients may write here, or the error to send them. With
+    /// elections on, only sessions that wait for a majority to apply their
+    /// writes may make them.
+    pub fn writable(&self, acknowledged: bool) -> Result<(), String> {
+        match (self.role, &self.upstream.addr) {
+            (Role::Leader, _) if self.elections() && !acknowledged => {
+                Err("READONLY writes need the line protocol while elections are on".to_string())
+            }
+            (Role::Leader, _) => Ok(()),
+            (_, Some(leader)) => Err(format!("READONLY this node follows {}", leader)),
+            (_, None) => Err("READONLY this node is not the leader".to_string()),
+        }
+    }
+
+    fn save(&self) {
+        let path = match self.saved {
+            Some(ref path) => path,
+            None => return,
+        };
+        let saved = Saved {
+            epoch: self.epoch,
+            voted_for: self.voted_for.clone(),
+        };
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, serde_json::to_vec(&saved).unwrap()));
+        if let Err(e) = written {
+            error!("unable to save epoch to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Moves to a newer epoch, returning false if `epoch` is not newer.
+    fn adopt(&mut self, epoch: u64) -> bool {
+        if epoch <= self.epoch {
+            return false;
+        }
+        self.epoch = epoch;
+        self.voted_for = None;
+        self.save();
+        true
+    }
+
+    /// Whether `node` is `replication.leader` or one of `replication.peers`.
+    fn trusts(&self, node: &str) -> bool {
+        self.leader.as_deref() == Some(node) || self.peers.iter().any(|peer| peer == node)
+    }
+
+    /// Follows `addr`, or waits to hear from a leader when it is `None`.
+    /// A node that isn't configured is never followed, so it can't replace
+    /// this node's data. A leader stepping down drops its followers and any
+    /// writes still waiting for them.
+    pub fn follow(&mut self, addr: Option<String>) {
+        let addr = match addr {
+            Some(ref leader) if !self.trusts(leader) => {
+                warn!("Not following {}: it isn't a configured leader or peer", leader);
+                None
+            }
+            addr => addr,
+        };
+        if self.role == Role::Leader {
+            self.followers.clear();
+            self.waiters.clear();
+        }
+        self.role = Role::Follower;
+        if self.upstream.addr != addr {
+            self.upstream = Upstream {
+                addr,
+                ..Upstream::default()
+            };
+            self.drop_upstream();
+        }
+    }
+
+    fn drop_upstream(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            let _ = cancel.send(());
+        }
+    }
+
+    fn lead(&mut self) {
+        self.role = Role::Leader;
+        self.committed = 0;
+        self.upstream = Upstream::default();
+        self.drop_upstream();
+    }
+
+    /// Turns a follower into the leader. With elections on, this only asks
+    /// for an election.
+    pub fn promote(&mut self) -> Result<(), String> {
+        if self.role == Role::Leader {
+            return Err("this node is already the leader".to_string());
+        }
+        if self.elections() {
+            self.campaign = true;
+            return Ok(());
+        }
+        self.fence = self.upstream.addr.take();
+        self.epoch += 1;
+        self.voted_for = None;
+        self.save();
+        self.lead();
+        info!("Promoted to leader for epoch {}", self.epoch);
+        Ok(())
+    }
+
+    pub fn record(&mut self, change: Change) {
+        self.seq += 1;
+        self.last_epoch = self.epoch;
+        let seq = self.seq;
+        self.send(Frame::Change { seq, change });
+        self.commit();
+    }
+
+    /// Sends a frame to every follower, disconnecting any that has fallen
+    /// `BACKLOG` frames behind.
+    fn send(&mut self, frame: Frame) {
+        self.followers
+            .retain(|id, follower| match follower.feed.try_send(frame.clone()) {
+                Ok(()) => true,
+                Err(ref e) if e.is_full() => {
+                    warn!("Follower {} fell too far behind, disconnecting it", id);
+                    false
+                }
+                Err(_) => false,
+            });
+    }
+
+    /// Whether `given` is `replication.token`. Nothing is admitted when it
+    /// isn't set.
+    fn admits(&self, given: &str) -> bool {
+        self.token
+            .as_ref()
+            .is_some_and(|token| auth::tokens_match(given, token))
+    }
+
+    fn ping(&mut self) {
+        let seq = self.seq;
+        self.send(Frame::Ping { seq });
+    }
+
+    /// Checks a new follower, whose last epoch was `epoch`, and starts
+    /// sending it changes, or returns why it can't follow this node.
+    fn attach(
+        &mut self,
+        addr: Option<String>,
+        node: Option<String>,
+        epoch: u64,
+        feed: Sender<Frame>,
+    ) -> Result<u64, Box<Frame>> {
+        if epoch > self.epoch {
+            self.adopt(epoch);
+            if self.role == Role::Leader {
+                warn!("A follower has seen epoch {}, stepping down", epoch);
+                self.follow(None);
+            }
+        } else if self.role == Role::Leader || !self.elections() {
+            // Followers from older epochs are sent a full copy, like any other.
+            let id = self.next_id;
+            self.next_id += 1;
+            let follower = Follower {
+                addr,
+                node,
+                feed,
+                connected: Instant::now(),
+                acked: 0,
+            };
+            self.followers.insert(id, follower);
+            return Ok(id);
+        }
+        Err(Box::new(Frame::Refused {
+            message: "this node is not the leader".to_string(),
+            leader: self.upstream.addr.clone(),
+        }))
+    }
+
+    fn detach(&mut self, id: u64) {
+        self.followers.remove(&id);
+    }
+
+    fn ack(&mut self, id: u64, seq: u64) {
+        if let Some(follower) = self.followers.get_mut(&id) {
+            follower.acked = seq;
+        }
+        self.commit();
+    }
+
+    /// Works out how far a majority of the nodes taking part in elections
+    /// has got, and releases the writes waiting for that.
+    fn commit(&mut self) {
+        if self.role != Role::Leader || !self.elections() {
+            return;
+        }
+        let mut acked: Vec<u64> = self
+            .followers
+            .values()
+            .filter(|follower| {
+                follower
+                    .node
+                    .as_ref()
+                    .is_some_and(|node| self.peers.contains(node))
+            })
+            .map(|follower| follower.acked)
+            .collect();
+        acked.push(self.seq);
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        let committed = match acked.get(self.majority() - 1) {
+            Some(committed) => *committed,
+            None => return,
+        };
+        self.committed = self.committed.max(committed);
+        let (done, waiting) = mem::take(&mut self.waiters)
+            .into_iter()
+            .partition(|(seq, _)| *seq <= committed);
+        self.waiters = waiting;
+        for (_, waiter) in done {
+            let _ = waiter.send(());
+        }
+    }
+
+    /// What resolves once a majority has applied change `seq`, or `None`
+    /// if there is nothing to wait for.
+    fn wait(&mut self, seq: u64) -> Option<oneshot::Receiver<()>> {
+        if !self.elections() || seq <= self.committed {
+            return None;
+        }
+        let (sender, receiver) = oneshot::channel();
+        self.waiters.push((seq, sender));
+        Some(receiver)
+    }
+
+    /// Notes a frame from the leader, which was at `seq` when it sent it.
+    fn contact(&mut self, seq: u64) {
+        self.upstream.connected = true;
+        self.upstream.seq = self.upstream.seq.max(seq);
+        self.upstream.last_contact = Some(Instant::now());
+        self.heard = Instant::now();
+    }
+
+    /// Starts again from a new snapshot. Followers of this node were sent
+    /// the old data, so they are dropped and resync on reconnecting.
+    fn synced(&mut self, seq: u64, epoch: u64, last_epoch: u64) {
+        self.adopt(epoch);
+        self.seq = seq;
+        self.last_epoch = last_epoch;
+        self.followers.clear();
+        self.upstream.seq = seq;
+        self.contact(seq);
+    }
+
+    /// Marks the leader as lost, returning whether it had been connected.
+    fn disconnected(&mut self) -> bool {
+        mem::replace(&mut self.upstream.connected, false)
+    }
+
+    /// The leader to connect to, if any, and what resolves when it is
+    /// replaced.
+    fn target(&mut self) -> Option<(String, oneshot::Receiver<()>)> {
+        let leader = match (self.role, &self.upstream.addr) {
+            (Role::Follower, Some(leader)) => leader.clone(),
+            _ => return None,
+        };
+        let (cancel, cancelled) = oneshot::channel();
+        self.cancel = Some(cancel);
+        Some((leader, cancelled))
+    }
+
+    /// The first line a follower sends its leader.
+    fn hello(&self) -> String {
+        let node = self.address.as_deref().unwrap_or("-");
+        format!("FOLLOW {} {} {}\n", self.epoch, node, self.credential())
+    }
+
+    /// The token sent at the end of every line to another node.
+    fn credential(&self) -> &str {
+        self.token.as_deref().unwrap_or("-")
+    }
+
+    /// Answers `VOTE` from `candidate`, as in Raft: one vote per epoch, only
+    /// for a peer that has every change this one has.
+    fn vote(&mut self, epoch: u64, last_epoch: u64, seq: u64, candidate: &str) -> Frame {
+        if !self.peers.iter().any(|peer| peer == candidate) {
+            warn!("Refused a vote to {}: it isn't one of replication.peers", candidate);
+            return Frame::Vote {
+                epoch: self.epoch,
+                granted: false,
+            };
+        }
+        if self.adopt(epoch) && self.role != Role::Follower {
+            self.follow(None);
+        }
+        let granted = epoch == self.epoch
+            && (self.voted_for.is_none() || self.voted_for.as_deref() == Some(candidate))
+            && (last_epoch, seq) >= (self.last_epoch, self.seq);
+        if granted {
+            self.voted_for = Some(candidate.to_string());
+            self.save();
+            self.heard = Instant::now();
+        }
+        Frame::Vote {
+            epoch: self.epoch,
+            granted,
+        }
+    }
+
+    /// Handles `LEADER` from the node that leads `epoch`.
+    fn announced(&mut self, epoch: u64, leader: Option<String>) {
+        if epoch < self.epoch || epoch == self.epoch && self.role == Role::Leader {
+            return;
+        }
+        if self.adopt(epoch) || self.upstream.addr != leader {
+            info!(
+                "{} leads epoch {}",
+                leader.as_deref().unwrap_or("Another node"),
+                epoch
+            );
+        }
+        self.follow(leader);
+        self.heard = Instant::now();
+    }
+
+    /// Stands for election when no leader has been heard from in time,
+    /// returning the epoch and the `VOTE` line to send to each peer.
+    fn stand(&mut self) -> Option<(u64, String)> {
+        let due = self.heard.elapsed() >= self.timeout || self.campaign;
+        if !self.elections() || self.role == Role::Leader || !due {
+            return None;
+        }
+        self.campaign = false;
+        self.epoch += 1;
+        self.voted_for = self.address.clone();
+        self.save();
+        self.follow(None);
+        self.role = Role::Candidate;
+        self.heard = Instant::now();
+        self.timeout = jitter(self.election_timeout);
+        let node = self.address.as_deref().unwrap_or("-");
+        let line = format!(
+            "VOTE {} {} {} {} {}\n",
+            self.epoch,
+            self.last_epoch,
+            self.seq,
+            node,
+            self.credential()
+        );
+        Some((self.epoch, line))
+    }
+
+    /// Counts the votes for this node in `epoch`. `highest` is the newest
+    /// epoch any peer answered with.
+    fn elected(&mut self, epoch: u64, votes: usize, highest: u64) {
+        if self.adopt(highest) {
+            self.follow(None);
+            return;
+        }
+        if self.role != Role::Candidate || self.epoch != epoch {
+            return;
+        }
+        if votes >= self.majority() {
+            info!("Elected leader for epoch {} with {} vote(s)", epoch, votes);
+            self.lead();
+        }
+    }
+
+    /// Who to send `LEADER` to on this heartbeat, and the line to send.
+    fn announcements(&self) -> Option<(Vec<String>, String)> {
+        if self.role != Role::Leader {
+            return None;
+        }
+        let mut targets: Vec<String> = self
+            .peers
+            .iter()
+            .filter(|peer| {
+                !self
+                    .followers
+                    .values()
+                    .any(|follower| follower.node.as_ref() == Some(*peer))
+            })
+            .cloned()
+            .collect();
+        targets.extend(self.fence.clone());
+        let node = self.address.as_deref().unwrap_or("-");
+        let line = format!("LEADER {} {} {}\n", self.epoch, node, self.credential());
+        Some((targets, line))
+    }
+
+    /// What `REPLICATION` reports. `lag` is how many changes behind a
+    /// follower is, and times are in seconds.
+    pub fn status(&self) -> Value {
+        let now = Instant::now();
+        let followers: Vec<Value> = self
+            .followers
+            .iter()
+            .map(|(id, follower)| {
+                json!({
+                    "id": id,
+                    "addr": follower.addr,
+                    "node": follower.node,
+                    "acked": follower.acked,
+                    "lag": self.seq.saturating_sub(follower.acked),
+                    "connected": (now - follower.connected).as_secs(),
+                })
+            })
+            .collect();
+        let mut status = json!({
+            "role": match self.role {
+                Role::Leader => "leader",
+                Role::Follower => "follower",
+                Role::Candidate => "candidate",
+            },
+            "epoch": self.epoch,
+            "seq": self.seq,
+            "followers": followers,
+        });
+        if self.elections() {
+            status["peers"] = json!(self.peers);
+            status["committed"] = json!(self.committed);
+        }
+        if self.role != Role::Leader {
+            let upstream = &self.upstream;
+            status["leader"] = json!({
+                "addr": upstream.addr,
+                "connected": upstream.connected,
+                "seq": upstream.seq,
+                "lag": upstream.seq.saturating_sub(self.seq),
+                "last_contact": upstream.last_contact.map(|at| (now - at).as_secs()),
+            });
+        }
+        status
+    }
+}
+
+/// Sets up replication from the `[replication]` section: who to follow,
+/// which peers take part in elections, and the saved epoch.
+pub fn configure(db: &Arc<state::Database>, shared: &conf::Shared) -> Result<(), String> {
+    let conf = shared.read().unwrap();
+    let saved = Path::new(&conf.get::<String>("persistence.path").unwrap()).join(".epoch");
+    let restored: Saved = match fs::read(&saved) {
+        Ok(body) => serde_json::from_slice(&body)
+            .map_err(|e| format!("{} is invalid: {}", saved.display(), e))?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Saved::default(),
+        Err(e) => return Err(format!("unable to read {}: {}", saved.display(), e)),
+    };
+
+    let mut hub = db.replication.lock().unwrap();
+    hub.epoch = restored.epoch;
+    hub.voted_for = restored.voted_for;
+    hub.saved = Some(saved);
+    hub.address = conf.get::<String>("replication.address").ok();
+    hub.peers = conf
+        .get::<Vec<String>>("replication.peers")
+        .unwrap_or_default();
+    hub.election_timeout = Duration::from_millis(
+        conf.get::<u64>("replication.election_timeout")
+            .unwrap_or(ELECTION_TIMEOUT),
+    );
+    hub.timeout = jitter(hub.election_timeout);
+    hub.token = conf.get::<String>("replication.token").ok();
+    hub.leader = conf.get::<String>("replication.leader").ok();
+    if let Ok(leader) = conf.get::<String>("replication.leader") {
+        info!("Following {}", leader);
+        hub.follow(Some(leader));
+    } else if hub.elections() {
+        info!("Waiting for a leader among {}", hub.peers.join(", "));
+        hub.follow(None);
+    }
+    Ok(())
+}
+
+/// Snapshots every channel and starts sending changes made after it to a
+/// new follower, returning its id, the snapshot and the feed of changes.
+fn attach(
+    db: &Arc<state::Database>,
+    addr: Option<String>,
+    node: Option<String>,
+    epoch: u64,
+) -> Result<(u64, Vec<Frame>, Receiver<Frame>), Box<Frame>> {
+    let channels = db.channels.lock().unwrap();
+    let (sender, feed) = channel(BACKLOG);
+    let mut hub = db.replication.lock().unwrap();
+    let id = hub.attach(addr, node, epoch, sender)?;
+    let mut snapshot: Vec<Frame> = channels
+        .iter()
+        .map(|(channel_id, channel)| Frame::Channel {
+            channel: channel_id.clone(),
+            messages: channel.data.lock().unwrap().clone(),
+            keys: channel.keys.lock().unwrap().clone(),
+        })
+        .collect();
+    snapshot.push(Frame::Synced {
+        seq: hub.seq,
+        epoch: hub.epoch,
+        last_epoch: hub.last_epoch,
+    });
+    Ok((id, snapshot, feed))
+}
+
+type Lines = tokio::io::Lines<BufReader<ReadHalf<TcpStream>>>;
+type Handled = Box<dyn Future<Item = (), Error = io::Error> + Send>;
+
+fn reply(writer: WriteHalf<TcpStream>, frame: &Frame) -> Handled {
+    let mut line = serde_json::to_string(frame).unwrap();
+    line.push('\n');
+    Box::new(write_all(writer, line.into_bytes()).map(|_| ()))
+}
+
+fn serve_follower(
+    db: &Arc<state::Database>,
+    addr: Option<String>,
+    node: Option<String>,
+    epoch: u64,
+    acks: Lines,
+    writer: WriteHalf<TcpStream>,
+) -> Handled {
+    let label = node.clone().or_else(|| addr.clone()).unwrap_or_default();
+    let (id, snapshot, feed) = match attach(db, addr, node, epoch) {
+        Ok(attached) => attached,
+        Err(refused) => return reply(writer, &refused),
+    };
+    info!("Follower {} connected from {}", id, label);
+
+    let writes = iter_ok(snapshot)
+        .chain(feed)
+        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "replication feed closed"))
+        .fold(writer, |writer, frame| {
+            let mut line = serde_json::to_string(&frame).unwrap();
+            line.push('\n');
+            write_all(writer, line.into_bytes()).map(|(writer, _)| writer)
+        })
+        .map(|_| ());
+
+    let acks_db = db.clone();
+    let acks = acks.for_each(move |line| {
+        match line.strip_prefix("ACK ").and_then(|seq| seq.parse().ok()) {
+            Some(seq) => acks_db.replication.lock().unwrap().ack(id, seq),
+            None => warn!("unexpected line from follower {}: {:?}", id, line),
+        }
+        Ok(())
+    });
+
+    let db = db.clone();
+    Box::new(writes.select(acks).then(move |_| {
+        db.replication.lock().unwrap().detach(id);
+        info!("Follower {} disconnected", id);
+        Ok(())
+    }))
+}
+
+fn node(word: &str) -> Option<String> {
+    match word {
+        "-" => None,
+        node => Some(node.to_string()),
+    }
+}
+
+/// Handles a connection to the replication listener. Its first line says
+/// what it is for: `FOLLOW <epoch> <node>` to follow this node, `VOTE
+/// <epoch> <last epoch> <seq> <node>` to ask for its vote, or `LEADER
+/// <epoch> <node>` to announce a new leader. Each ends with
+/// `replication.token`, and nothing is answered until it matches.
+fn serve_peer(db: &Arc<state::Database>, socket: TcpStream) {
+    let addr = socket.peer_addr().ok().map(|addr| addr.to_string());
+    let (reader, writer) = socket.split();
+    let db = db.clone();
+    let handled = lines(BufReader::new(reader))
+        .into_future()
+        .map_err(|(e, _)| e)
+        .and_then(move |(first, rest)| {
+            let first = first.unwrap_or_default();
+            let mut words: Vec<&str> = first.split(' ').collect();
+            let token = words.pop().unwrap_or_default();
+            if words.is_empty() || !db.replication.lock().unwrap().admits(token) {
+                warn!("Refused a replication connection from {:?}: invalid token", addr);
+                let refused = Frame::Refused {
+                    message: "invalid replication token".to_string(),
+                    leader: None,
+                };
+                return reply(writer, &refused);
+            }
+            let numbers: Vec<Option<u64>> = words.iter().map(|word| word.parse().ok()).collect();
+            match (words.as_slice(), numbers.as_slice()) {
+                (["FOLLOW", _, peer], [_, Some(epoch), _]) => {
+                    serve_follower(&db, addr, node(peer), *epoch, rest, writer)
+                }
+                (
+                    ["VOTE", _, _, _, candidate],
+                    [_, Some(epoch), Some(last_epoch), Some(seq), _],
+                ) => {
+                    let mut hub = db.replication.lock().unwrap();
+                    let vote = hub.vote(*epoch, *last_epoch, *seq, candidate);
+                    debug!("vote for {} in epoch {}: {:?}", candidate, epoch, vote);
+                    reply(writer, &vote)
+                }
+                (["LEADER", _, leader], [_, Some(epoch), _]) => {
+                    db.replication
+                        .lock()
+                        .unwrap()
+                        .announced(*epoch, node(leader));
+                    let ok: Handled = Box::new(write_all(writer, b"OK\n").map(|_| ()));
+                    ok
+                }
+                _ => {
+                    let refused = Frame::Refused {
+                        message: format!("unexpected request: {:?}", words.join(" ")),
+                        leader: None,
+                    };
+                    reply(writer, &refused)
+                }
+            }
+        });
+    tokio::spawn(handled.map_err(|e| debug!("replication connection failed: {}", e)));
+}
+
+/// Builds the listener other nodes connect to from `replication.address`,
+/// or `None` when it is not set.
+pub fn server(
+    db: &Arc<state::Database>,
+    shared: &conf::Shared,
+) -> Result<Option<Box<dyn Future<Item = (), Error = ()> + Send>>, String> {
+    let conf = shared.read().unwrap();
+    let addr: SocketAddr = match conf.get::<String>("replication.address") {
+        Ok(addr) => addr
+            .parse()
+            .map_err(|e| format!("replication.address is invalid: {}", e))?,
+        Err(_) => return Ok(None),
+    };
+    let socket = TcpListener::bind(&addr).map_err(|e| format!("unable to bind {}: {}", addr, e))?;
+    info!("Replication listening on: {}", addr);
+
+    let db = db.clone();
+    let accepted = socket
+        .incoming()
+        .map_err(|e| error!("failed to accept replication socket; error = {:?}", e))
+        .for_each(move |socket| {
+            serve_peer(&db, socket);
+            Ok(())
+        });
+    Ok(Some(Box::new(accepted)))
+}
+
+fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Sends one line to another node and reads the one line it answers with.
+fn ask(
+    node: &str,
+    line: String,
+    timeout: Duration,
+) -> impl Future<Item = String, Error = io::Error> {
+    let addr = node.parse::<SocketAddr>().map_err(invalid);
+    let asked = future::result(addr)
+        .and_then(|addr| TcpStream::connect(&addr))
+        .and_then(move |stream| write_all(stream, line.into_bytes()))
+        .and_then(|(stream, _)| {
+            lines(BufReader::new(stream))
+                .into_future()
+                .map_err(|(e, _)| e)
+        })
+        .map(|(answer, _)| answer.unwrap_or_default());
+    Timeout::new(asked, timeout).map_err(|e| {
+        e.into_inner()
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+    })
+}
+
+fn elect(
+    db: &Arc<state::Database>,
+    epoch: u64,
+    line: String,
+) -> impl Future<Item = (), Error = ()> {
+    let (peers, timeout) = {
+        let hub = db.replication.lock().unwrap();
+        (hub.peers.clone(), hub.election_timeout)
+    };
+    info!("Standing for election in epoch {}", epoch);
+    let ballots = peers.into_iter().map(move |peer| {
+        ask(&peer, line.clone(), timeout).then(move |answer| {
+            let vote = match answer.map(|answer| serde_json::from_str(&answer)) {
+                Ok(Ok(Frame::Vote { epoch, granted })) => Some((epoch, granted)),
+                Ok(_) => None,
+                Err(e) => {
+                    debug!("no vote from {}: {}", peer, e);
+                    None
+                }
+            };
+            Ok::<_, ()>(vote)
+        })
+    });
+    let db = db.clone();
+    join_all(ballots).map(move |votes| {
+        let votes: Vec<(u64, bool)> = votes.into_iter().flatten().collect();
+        let granted = 1 + votes
+            .iter()
+            .filter(|(answered, granted)| *answered == epoch && *granted)
+            .count();
+        let highest = votes.iter().map(|(answered, _)| *answered).max();
+        db.replication
+            .lock()
+            .unwrap()
+            .elected(epoch, granted, highest.unwrap_or(epoch));
+    })
+}
+
+fn announce(db: &Arc<state::Database>, target: String, line: String) {
+    let db = db.clone();
+    let announced = ask(&target, line, HEARTBEAT * 4).then(move |answer| {
+        match answer {
+            Ok(ref answer) if answer == "OK" => {
+                let mut hub = db.replication.lock().unwrap();
+                if hub.fence.as_ref() == Some(&target) {
+                    info!("Told former leader {} about epoch {}", target, hub.epoch);
+                    hub.fence = None;
+                }
+            }
+            Ok(answer) => debug!("unexpected answer from {}: {:?}", target, answer),
+            Err(e) => debug!("unable to reach {}: {}", target, e),
+        }
+        Ok(())
+    });
+    tokio::spawn(announced);
+}
+
+/// Runs on every node: pings followers, announces a leader to peers that
+/// aren't following it and to a former leader after `PROMOTE`, and stands
+/// for election when the leader has gone quiet.
+pub fn heartbeat(db: &Arc<state::Database>) -> impl Future<Item = (), Error = ()> {
+    let db = db.clone();
+    Interval::new_interval(HEARTBEAT)
+        .map_err(|e| error!("replication heartbeat failed: {}", e))
+        .for_each(move |_| {
+            let (announcements, election) = {
+                let mut hub = db.replication.lock().unwrap();
+                hub.ping();
+                (hub.announcements(), hub.stand())
+            };
+            if let Some((targets, line)) = announcements {
+                for target in targets {
+                    announce(&db, target, line.clone());
+                }
+            }
+            if let Some((epoch, line)) = election {
+                tokio::spawn(elect(&db, epoch, line));
+            }
+            Ok(())
+        })
+}
+
+/// Handles one frame from the leader, returning the line to answer with.
+/// An error ends the connection, and reconnecting starts a full resync.
+fn receive(
+    db: &Arc<state::Database>,
+    shared: &conf::Shared,
+    snapshot: &mut BTreeMap<String, state::Channel>,
+    frame: Frame,
+) -> io::Result<Option<String>> {
+    match frame {
+        Frame::Channel {
+            channel,
+            messages,
+            keys,
+        } => {
+            let index: HashMap<String, usize> = messages
+                .iter()
+                .enumerate()
+                .map(|(position, message)| (message.uid.clone(), position))
+                .collect();
+            let restored = state::Channel::new(index, messages);
+            *restored.keys.lock().unwrap() = keys;
+            restored.counters.lock().unwrap().dirty = true;
+            snapshot.insert(channel, restored);
+            Ok(None)
+        }
+        Frame::Synced {
+            seq,
+            epoch,
+            last_epoch,
+        } => {
+            let current = db.replication.lock().unwrap().epoch();
+            if epoch < current {
+                return Err(invalid(format!(
+                    "the leader is in epoch {} but this node has seen {}",
+                    epoch, current
+                )));
+            }
+            let channels = mem::take(snapshot);
+            info!(
+                "Synced {} channel(s) from the leader at {} in epoch {}",
+                channels.len(),
+                seq,
+                epoch
+            );
+            operations::replace_channels(db, &shared.read().unwrap(), channels)?;
+            db.replication
+                .lock()
+                .unwrap()
+                .synced(seq, epoch, last_epoch);
+            Ok(Some(format!("ACK {}\n", seq)))
+        }
+        Frame::Change { seq, change } => {
+            let expected = db.replication.lock().unwrap().seq() + 1;
+            if seq != expected {
+                return Err(invalid(format!(
+                    "expected change {} but got {}",
+                    expected, seq
+                )));
+            }
+            if let types::Response::Error { message } =
+                operations::apply(db, &shared.read().unwrap(), change)
+            {
+                return Err(invalid(format!("change {} failed: {}", seq, message)));
+            }
+            db.replication.lock().unwrap().contact(seq);
+            Ok(Some(format!("ACK {}\n", seq)))
+        }
+        Frame::Ping { seq } => {
+            let mut hub = db.replication.lock().unwrap();
+            hub.contact(seq);
+            Ok(Some(format!("ACK {}\n", hub.seq())))
+        }
+        Frame::Refused { message, leader } => {
+            if let Some(leader) = leader {
+                db.replication.lock().unwrap().follow(Some(leader));
+            }
+            Err(invalid(message))
+        }
+        Frame::Vote { .. } => Err(invalid("unexpected vote")),
+    }
+}
+
+fn follow_leader(
+    db: Arc<state::Database>,
+    shared: conf::Shared,
+    stream: TcpStream,
+) -> impl Future<Item = (), Error = io::Error> {
+    let hello = db.replication.lock().unwrap().hello();
+    let (reader, writer) = stream.split();
+    let mut snapshot = BTreeMap::new();
+    write_all(writer, hello.into_bytes()).and_then(move |(writer, _)| {
+        lines(BufReader::new(reader))
+            .and_then(move |line| {
+                let frame: Frame = serde_json::from_str(&line).map_err(invalid)?;
+                receive(&db, &shared, &mut snapshot, frame)
+            })
+            .filter_map(|reply| reply)
+            .fold(writer, |writer, reply| {
+                write_all(writer, reply.into_bytes()).map(|(writer, _)| writer)
+            })
+            .map(|_| ())
+    })
+}
+
+/// Builds the loop that follows the current leader, reconnecting and
+/// resyncing whenever the connection is lost or the leader changes. It
+/// waits while this node is the leader or doesn't know one.
+pub fn follower(
+    db: &Arc<state::Database>,
+    shared: &conf::Shared,
+) -> impl Future<Item = (), Error = ()> {
+    let db = db.clone();
+    let shared = shared.clone();
+    loop_fn((), move |_| {
+        let retry = Delay::new(Instant::now() + RECONNECT)
+            .then(|_| Ok::<_, ()>(Loop::<(), ()>::Continue(())));
+        let (leader, cancelled) = match db.replication.lock().unwrap().target() {
+            Some(target) => target,
+            None => return Either::A(retry),
+        };
+        let lost_db = db.clone();
+        let follow_db = db.clone();
+        let shared = shared.clone();
+        let following = future::result(leader.parse::<SocketAddr>().map_err(invalid))
+            .and_then(|addr| TcpStream::connect(&addr))
+            .and_then(move |stream| follow_leader(follow_db, shared, stream))
+            .select2(cancelled)
+            .then(move |result| {
+                let was_connected = lost_db.replication.lock().unwrap().disconnected();
+                match result {
+                    Err(Either::A((ref e, _))) if was_connected => {
+                        warn!("Lost leader {}: {}", leader, e)
+                    }
+                    Err(Either::A((ref e, _))) => debug!("unable to follow {}: {}", leader, e),
+                    Ok(Either::A(_)) if was_connected => {
+                        warn!("Leader {} closed the connection", leader)
+                    }
+                    _ => (),
+                }
+                retry
+            });
+        Either::B(following)
+    })
+}
+
+/// With elections on, holds back the answer to a write until a majority of
+/// nodes have applied it, so that it survives losing the leader. `written`
+/// is the change the write made.
+pub fn acknowledged(
+    db: &Arc<state::Database>,
+    shared: &conf::Shared,
+    response: types::Response,
+    written: Option<u64>,
+) -> Box<dyn Future<Item = types::Response, Error = io::Error> + Send> {
+    let waiting = written.and_then(|seq| db.replication.lock().unwrap().wait(seq));
+    let committed = match waiting {
+        Some(committed) => committed,
+        None => return Box::new(future::ok(response)),
+    };
+    let timeout = shared
+        .read()
+        .unwrap()
+        .get::<u64>("replication.ack_timeout")
+        .unwrap_or(ACK_TIMEOUT);
+    let acknowledged = Timeout::new(committed, Duration::from_millis(timeout));
+    Box::new(acknowledged.then(move |result| {
+        Ok::<_, io::Error>(match result {
+            Ok(_) => response,
+            Err(_) => types::Response::Error {
+                message: "NOTACKED a majority of nodes did not apply the change in time"
+                    .to_string(),
+            },
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::testing;
+
+    fn make_db(name: &str) -> (Arc<state::Database>, conf::Shared) {
+        let conf = testing::make_conf(&format!("replication-{}", name));
+        (testing::make_db(), conf::shared(conf))
+    }
+
+    fn run(db: &Arc<state::Database>, conf: &conf::Shared, line: &str) -> String {
+        let mut session = crate::lib::session::Session::new();
+        operations::handle_request(db, conf, &mut session, line.to_string()).serialize()
+    }
+
+    #[test]
+    fn follower_copies_the_leader() {
+        let (leader, leader_conf) = make_db("leader");
+        let (follower, follower_conf) = make_db("follower");
+        run(&follower, &follower_conf, "stale PUSH gone");
+        let mut hub = follower.replication.lock().unwrap();
+        hub.leader = Some("127.0.0.1:1".to_string());
+        hub.follow(Some("127.0.0.1:1".to_string()));
+        drop(hub);
+        run(&leader, &leader_conf, "room PUSHKEY k1 one");
+
+        let (_, snapshot, feed) = attach(&leader, None, None, 0).unwrap();
+        let mut pending = BTreeMap::new();
+        for frame in snapshot {
+            receive(&follower, &follower_conf, &mut pending, frame).unwrap();
+        }
+        let pushed = run(&leader, &leader_conf, "room PUSHKEY k2 two");
+        let uid = pushed[3..].trim().to_string();
+        run(&leader, &leader_conf, &format!("room UPDATE {} TWO", uid));
+        run(&leader, &leader_conf, "room COPY lobby");
+        leader.replication.lock().unwrap().ping();
+        let mut acks = Vec::new();
+        for frame in feed.take(4).wait() {
+            let reply = receive(&follower, &follower_conf, &mut pending, frame.unwrap()).unwrap();
+            acks.extend(reply);
+        }
+        assert_eq!(acks, vec!["ACK 2\n", "ACK 3\n", "ACK 4\n", "ACK 4\n"]);
+
+        assert_eq!(
+            run(&follower, &follower_conf, "lobby RECENT 10"),
+            run(&leader, &leader_conf, "lobby RECENT 10")
+        );
+        assert!(run(&follower, &follower_conf, &format!("room RETRIEVE {}", uid)).contains("TWO"));
+        assert_eq!(
+            run(&follower, &follower_conf, "stale RECENT"),
+            "ER No messages found\n"
+        );
+        assert_eq!(
+            run(&follower, &follower_conf, "room PUSH three"),
+            "ER READONLY this node follows 127.0.0.1:1\n"
+        );
+
+        // After a failover, a retried push is answered with the original.
+        follower.replication.lock().unwrap().promote().unwrap();
+        assert_eq!(run(&follower, &follower_conf, "room PUSHKEY k2 two"), pushed);
+        let replayed = run(&follower, &follower_conf, "room PUSHKEY k1 one");
+        assert_eq!(replayed, run(&leader, &leader_conf, "room PUSHKEY k1 one"));
+        follower
+            .replication
+            .lock()
+            .unwrap()
+            .follow(Some("127.0.0.1:1".to_string()));
+
+        let status = follower.replication.lock().unwrap().status();
+        assert_eq!(status["role"], "follower");
+        assert_eq!(status["seq"], 4);
+        assert_eq!(status["leader"]["lag"], 0);
+        let status = leader.replication.lock().unwrap().status();
+        assert_eq!(status["role"], "leader");
+        assert_eq!(status["followers"][0]["lag"], 4);
+    }
+
+    #[test]
+    fn follower_refuses_changes_out_of_order() {
+        let (follower, conf) = make_db("gap");
+        let mut pending = BTreeMap::new();
+        let synced = Frame::Synced {
+            seq: 3,
+            epoch: 0,
+            last_epoch: 0,
+        };
+        receive(&follower, &conf, &mut pending, synced).unwrap();
+        let change = Change::Flush {
+            channel: "room".to_string(),
+            archive: false,
+        };
+        let frame = Frame::Change { seq: 5, change };
+        assert!(receive(&follower, &conf, &mut pending, frame).is_err());
+    }
+
+    #[test]
+    fn promote_fences_the_old_leader() {
+        let (old, old_conf) = make_db("old-leader");
+        let (new, new_conf) = make_db("new-leader");
+        let mut hub = new.replication.lock().unwrap();
+        hub.address = Some("127.0.0.1:2".to_string());
+        hub.leader = Some("127.0.0.1:1".to_string());
+        hub.follow(Some("127.0.0.1:1".to_string()));
+        hub.promote().unwrap();
+        assert_eq!(hub.epoch(), 1);
+        assert!(hub.promote().is_err());
+        let (targets, line) = hub.announcements().unwrap();
+        assert_eq!(targets, vec!["127.0.0.1:1"]);
+        assert_eq!(line, "LEADER 1 127.0.0.1:2 -\n");
+        drop(hub);
+        assert_eq!(&run(&new, &new_conf, "room PUSH one")[..2], "OK");
+
+        // A follower that has seen epoch 1 fences the old leader too.
+        assert!(attach(&old, None, None, 1).is_err());
+        assert_eq!(
+            run(&old, &old_conf, "room PUSH stale"),
+            "ER READONLY this node is not the leader\n"
+        );
+        // It isn't configured to follow the new leader, so it waits.
+        let mut hub = old.replication.lock().unwrap();
+        hub.announced(1, Some("127.0.0.1:2".to_string()));
+        assert_eq!(hub.status()["leader"]["addr"], Value::Null);
+        hub.leader = Some("127.0.0.1:2".to_string());
+        hub.announced(1, Some("127.0.0.1:2".to_string()));
+        drop(hub);
+        assert_eq!(
+            run(&old, &old_conf, "room PUSH stale"),
+            "ER READONLY this node follows 127.0.0.1:2\n"
+        );
+        old.replication.lock().unwrap().announced(0, None);
+        assert_eq!(
+            old.replication.lock().unwrap().status()["leader"]["addr"],
+            "127.0.0.1:2"
+        );
+    }
+
+    #[test]
+    fn elections_grant_one_vote_per_epoch_to_an_up_to_date_node() {
+        let mut hub = Hub::new();
+        hub.peers = vec!["127.0.0.1:2".to_string(), "127.0.0.1:3".to_string()];
+        hub.address = Some("127.0.0.1:1".to_string());
+        hub.follow(None);
+        hub.seq = 5;
+        hub.last_epoch = 1;
+        hub.epoch = 1;
+
+        assert_eq!(
+            hub.vote(9, 1, 5, "127.0.0.1:4"),
+            Frame::Vote {
+                epoch: 1,
+                granted: false
+            }
+        );
+
+        assert_eq!(
+            hub.vote(2, 1, 4, "127.0.0.1:2"),
+            Frame::Vote {
+                epoch: 2,
+                granted: false
+            }
+        );
+        assert_eq!(
+            hub.vote(2, 1, 5, "127.0.0.1:3"),
+            Frame::Vote {
+                epoch: 2,
+                granted: true
+            }
+        );
+        assert_eq!(
+            hub.vote(2, 1, 6, "127.0.0.1:2"),
+            Frame::Vote {
+                epoch: 2,
+                granted: false
+            }
+        );
+        assert_eq!(
+            hub.vote(1, 1, 9, "127.0.0.1:2"),
+            Frame::Vote {
+                epoch: 2,
+                granted: false
+            }
+        );
+
+        hub.campaign = true;
+        let (epoch, line) = hub.stand().unwrap();
+        assert_eq!(epoch, 3);
+        assert_eq!(line, "VOTE 3 1 5 127.0.0.1:1 -\n");
+        hub.elected(3, 1, 3);
+        assert!(!hub.is_leader());
+        hub.elected(3, 2, 3);
+        assert!(hub.is_leader());
+    }
+
+    #[test]
+    fn followers_that_fall_behind_are_disconnected() {
+        let mut hub = Hub::new();
+        let (sender, _feed) = channel(BACKLOG);
+        hub.attach(None, None, 0, sender).unwrap();
+        hub.token = Some("s3cret".to_string());
+        for _ in 0..BACKLOG {
+            hub.ping();
+        }
+        assert_eq!(hub.status()["followers"].as_array().unwrap().len(), 1);
+        hub.ping();
+        hub.ping();
+        assert_eq!(hub.status()["followers"].as_array().unwrap().len(), 0);
+
+        assert!(hub.admits("s3cret"));
+        assert!(!hub.admits("s3cre7"));
+        assert_eq!(hub.hello(), "FOLLOW 0 - s3cret\n");
+    }
+
+    #[test]
+    fn writes_wait_for_a_majority() {
+        let mut hub = Hub::new();
+        hub.peers = vec!["127.0.0.1:2".to_string(), "127.0.0.1:3".to_string()];
+        let (sender, _feed) = channel(BACKLOG);
+        let node = Some("127.0.0.1:2".to_string());
+        let id = hub.attach(None, node, 0, sender).unwrap();
+        hub.record(Change::Flush {
+            channel: "room".to_string(),
+            archive: false,
+        });
+        assert!(hub.writable(true).is_ok());
+        assert!(hub.writable(false).unwrap_err().starts_with("READONLY"));
+        let waiting = hub.wait(1).unwrap();
+        hub.ack(id, 1);
+        assert!(waiting.wait().is_ok());
+        assert!(hub.wait(1).is_none());
+        assert_eq!(hub.status()["committed"], 1);
+    }
+
+    #[test]
+    fn peers_must_send_the_token() {
+        use std::io::{BufRead, Write};
+        let (db, shared) = make_db("token");
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        shared
+            .write()
+            .unwrap()
+            .set("replication.address", addr.to_string())
+            .unwrap();
+        db.replication.lock().unwrap().token = Some("s3cret".to_string());
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(server(&db, &shared).unwrap().unwrap());
+
+        let send = |line: &str| {
+            let stream = std::net::TcpStream::connect(addr).unwrap();
+            (&stream).write_all(line.as_bytes()).unwrap();
+            let mut answer = String::new();
+            std::io::BufReader::new(stream).read_line(&mut answer).unwrap();
+            answer
+        };
+        for line in ["LEADER 5 127.0.0.1:9\n", "LEADER 5 127.0.0.1:9 wrong\n"] {
+            let refused: Frame = serde_json::from_str(&send(line)).unwrap();
+            assert!(matches!(refused, Frame::Refused { .. }), "{:?}", refused);
+        }
+        assert_eq!(db.replication.lock().unwrap().epoch(), 0);
+
+        assert_eq!(send("LEADER 5 127.0.0.1:9 s3cret\n"), "OK\n");
+        let status = db.replication.lock().unwrap().status();
+        assert_eq!(status["epoch"], 5);
+        assert_eq!(status["role"], "follower");
+        assert_eq!(status["leader"]["addr"], Value::Null);
+        runtime.shutdown_now().wait().unwrap();
+    }
+}
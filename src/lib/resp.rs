@@ -0,0 +1,792 @@
+use crate::lib::{clients, conf, operations, session, state, types};
+use bytes::BytesMut;
+use futures::{Future, Sink, Stream};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::codec::{Decoder, Encoder, Framed};
+use tokio::net::TcpListener;
+
+const MAX_ARGUMENTS: usize = 1024 * 1024;
+const MAX_BULK_LENGTH: usize = 512 * 1024 * 1024;
+const MAX_INLINE_LENGTH: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+impl Value {
+    fn bulk(value: &str) -> Value {
+        Value::Bulk(value.as_bytes().to_vec())
+    }
+
+    /// Encodes the value for the given protocol version. RESP2 has no map
+    /// type, so maps fall back to a flat array of keys and values.
+    pub fn encode(&self, protocol: u8, out: &mut Vec<u8>) {
+        match *self {
+            Value::Simple(ref s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Value::Error(ref s) => out.extend_from_slice(format!("-{}\r\n", s).as_bytes()),
+            Value::Integer(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            Value::Bulk(ref b) => {
+                out.extend_from_slice(format!("${}\r\n", b.len()).as_bytes());
+                out.extend_from_slice(b);
+                out.extend_from_slice(b"\r\n");
+            }
+            Value::Array(ref items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(protocol, out);
+                }
+            }
+            Value::Map(ref pairs) => {
+                match protocol {
+                    3 => out.extend_from_slice(format!("%{}\r\n", pairs.len()).as_bytes()),
+                    _ => out.extend_from_slice(format!("*{}\r\n", pairs.len() * 2).as_bytes()),
+                }
+                for (key, value) in pairs {
+                    key.encode(protocol, out);
+                    value.encode(protocol, out);
+                }
+            }
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Finds the `\r\n` terminated line at `start`, refusing lines longer
+/// than `MAX_INLINE_LENGTH` so a client can't grow the buffer forever.
+fn read_line(buf: &[u8], start: usize) -> io::Result<Option<(&[u8], usize)>> {
+    match buf[start..].windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end <= MAX_INLINE_LENGTH => {
+            Ok(Some((&buf[start..start + end], start + end + 2)))
+        }
+        None if buf.len() - start <= MAX_INLINE_LENGTH => Ok(None),
+        _ => Err(invalid("Protocol error: too big request line")),
+    }
+}
+
+fn read_number(line: &[u8]) -> io::Result<i64> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.parse::<i64>().ok())
+        .ok_or_else(|| invalid("Protocol error: invalid length"))
+}
+
+/// Parses one command from the front of `buf`, either a RESP array of bulk
+/// strings or an inline command, returning it with the number of bytes it
+/// used. `None` means more input is needed. Nothing is copied until the
+/// whole command has arrived, so partial reads only rescan the headers.
+pub fn parse_command(buf: &[u8]) -> io::Result<Option<(Vec<Vec<u8>>, usize)>> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    if buf[0] != b'*' {
+        let end = match buf.iter().position(|&b| b == b'\n') {
+            Some(end) if end <= MAX_INLINE_LENGTH => end,
+            None if buf.len() <= MAX_INLINE_LENGTH => return Ok(None),
+            _ => return Err(invalid("Protocol error: too big inline request")),
+        };
+        let line = String::from_utf8_lossy(&buf[..end]);
+        let args = line
+            .split_whitespace()
+            .map(|arg| arg.as_bytes().to_vec())
+            .collect();
+        return Ok(Some((args, end + 1)));
+    }
+
+    let (line, mut position) = match read_line(buf, 1)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let count = read_number(line)?;
+    if count < 0 || count as usize > MAX_ARGUMENTS {
+        return Err(invalid("Protocol error: invalid multibulk length"));
+    }
+    let mut ranges = Vec::with_capacity((count as usize).min(1024));
+    for _ in 0..count {
+        if position >= buf.len() {
+            return Ok(None);
+        }
+        if buf[position] != b'$' {
+            return Err(invalid("Protocol error: expected '$'"));
+        }
+        let (line, next) = match read_line(buf, position + 1)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let length = read_number(line)?;
+        if length < 0 || length as usize > MAX_BULK_LENGTH {
+            return Err(invalid("Protocol error: invalid bulk length"));
+        }
+        let end = next + length as usize;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        ranges.push(next..end);
+        position = end + 2;
+    }
+    let args = ranges.into_iter().map(|range| buf[range].to_vec()).collect();
+    Ok(Some((args, position)))
+}
+
+pub struct RespCodec;
+
+impl Decoder for RespCodec {
+    type Item = Vec<Vec<u8>>;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Vec<Vec<u8>>>> {
+        match parse_command(&buf[..])? {
+            Some((args, used)) => {
+                buf.split_to(used);
+                Ok(Some(args))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder for RespCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Vec<u8>, dst: &mut BytesMut) -> io::Result<()> {
+        dst.extend_from_slice(&item);
+        Ok(())
+    }
+}
+
+fn wrong_arity(command: &str) -> Value {
+    Value::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        command.to_lowercase()
+    ))
+}
+
+/// Turns a MerkavaDB error into a RESP error, keeping the codes Redis
+/// clients already recognise for authentication failures and retries.
+fn error_value(message: String) -> Value {
+    if message.starts_with("NOAUTH")
+        || message.starts_with("NOPERM")
+        || message.starts_with("READONLY")
+        || message.starts_with("TRYAGAIN")
+    {
+        Value::Error(message)
+    } else {
+        Value::Error(format!("ERR {}", message))
+    }
+}
+
+/// Stream entries carry field/value pairs, but channel messages are plain
+/// strings. A lone `value` field is stored as-is and anything else as a JSON
+/// object, so both read back the way they were written.
+fn encode_fields(pairs: &[String]) -> String {
+    if pairs.len() == 2 && pairs[0] == "value" {
+        return pairs[1].clone();
+    }
+    let object: serde_json::Map<String, serde_json::Value> = pairs
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), serde_json::Value::String(pair[1].clone())))
+        .collect();
+    serde_json::Value::Object(object).to_string()
+}
+
+fn decode_fields(value: &str) -> Vec<Value> {
+    if let Ok(serde_json::Value::Object(object)) = serde_json::from_str(value) {
+        if object.values().all(|field| field.is_string()) {
+            return object
+                .iter()
+                .flat_map(|(key, field)| {
+                    vec![Value::bulk(key), Value::bulk(field.as_str().unwrap())]
+                })
+                .collect();
+        }
+    }
+    vec![Value::bulk("value"), Value::bulk(value)]
+}
+
+fn entry(message: &state::Message) -> Value {
+    Value::Array(vec![
+        Value::bulk(&message.uid),
+        Value::Array(decode_fields(&message.value)),
+    ])
+}
+
+/// The state of one RESP client: its session and negotiated protocol.
+pub struct RespConnection {
+    db: Arc<state::Database>,
+    conf: conf::Shared,
+    session: session::Session,
+    pub protocol: u8,
+}
+
+impl RespConnection {
+    #[cfg(test)]
+    pub fn new(db: &Arc<state::Database>, conf: &conf::Shared) -> RespConnection {
+        RespConnection::with_session(db, conf, session::Session::new())
+    }
+
+    pub fn with_session(
+        db: &Arc<state::Database>,
+        conf: &conf::Shared,
+        session: session::Session,
+    ) -> RespConnection {
+        RespConnection {
+            db: db.clone(),
+            conf: conf.clone(),
+            session,
+            protocol: 2,
+        }
+    }
+
+    fn dispatch(&mut self, request: types::Request) -> types::Response {
+        operations::dispatch(&self.db, &self.conf, &mut self.session, request)
+    }
+
+    fn authenticate(&mut self, token: &str) -> Option<Value> {
+        match self.dispatch(types::Request::Auth {
+            token: token.to_string(),
+        }) {
+            types::Response::Error { message } => {
+                Some(Value::Error(format!("WRONGPASS {}", message)))
+            }
+            _ => None,
+        }
+    }
+
+    fn hello(&mut self, args: &[String]) -> Value {
+        let protocol = match args.first().map(|version| version.parse::<u8>()) {
+            None => self.protocol,
+            Some(Ok(version)) if version == 2 || version == 3 => version,
+            Some(_) => return Value::Error("NOPROTO unsupported protocol version".to_string()),
+        };
+        let mut options = args.iter().skip(1);
+        while let Some(option) = options.next() {
+            match option.to_uppercase().as_str() {
+                "AUTH" => {
+                    let _username = options.next();
+                    let token = match options.next() {
+                        Some(token) => token.clone(),
+                        None => return wrong_arity("hello"),
+                    };
+                    if let Some(error) = self.authenticate(&token) {
+                        return error;
+                    }
+                }
+                "SETNAME" => {
+                    options.next();
+                }
+                _ => return Value::Error(format!("ERR syntax error in HELLO option '{}'", option)),
+            }
+        }
+        self.protocol = protocol;
+        let role = match self.db.replication.lock().unwrap().is_leader() {
+            true => "master",
+            false => "replica",
+        };
+        Value::Map(vec![
+            (Value::bulk("server"), Value::bulk("merkava")),
+            (
+                Value::bulk("version"),
+                Value::bulk(env!("CARGO_PKG_VERSION")),
+            ),
+            (Value::bulk("proto"), Value::Integer(self.protocol as i64)),
+            (Value::bulk("id"), Value::Integer(self.session.id as i64)),
+            (Value::bulk("mode"), Value::bulk("standalone")),
+            (Value::bulk("role"), Value::bulk(role)),
+            (Value::bulk("modules"), Value::Array(vec![])),
+        ])
+    }
+
+    fn xadd(&mut self, args: &[String]) -> Value {
+        if args.len() < 4 || !(args.len() - 2).is_multiple_of(2) {
+            return wrong_arity("xadd");
+        }
+        if args[1] != "*" {
+            return Value::Error("ERR only auto-generated IDs (*) are supported".to_string());
+        }
+        let request = types::Request::Push {
+            channel_id: args[0].clone(),
+            value: encode_fields(&args[2..]),
+            key: None,
+        };
+        match self.dispatch(request) {
+            types::Response::Push { message } => Value::bulk(&message.uid),
+            types::Response::Error { message } => error_value(message),
+            _ => Value::Error("ERR unexpected response".to_string()),
+        }
+    }
+
+    fn range(&mut self, command: &str, args: &[String], reverse: bool) -> Value {
+        if args.len() != 3 && args.len() != 5 {
+            return wrong_arity(command);
+        }
+        let bounds = match reverse {
+            true => ("+", "-"),
+            false => ("-", "+"),
+        };
+        if (args[1].as_str(), args[2].as_str()) != bounds {
+            return Value::Error(format!(
+                "ERR only the full range ({} {}) is supported",
+                bounds.0, bounds.1
+            ));
+        }
+        let count = match args.get(3) {
+            None => {
+                let conf = self.conf.read().unwrap();
+                conf.get::<usize>("limits.recent").unwrap_or(operations::MAXIMUM)
+            }
+            Some(option) if option.to_uppercase() == "COUNT" => match args[4].parse::<usize>() {
+                Ok(count) => count,
+                Err(_) => {
+                    return Value::Error("ERR value is not an integer or out of range".to_string())
+                }
+            },
+            Some(_) => return Value::Error("ERR syntax error".to_string()),
+        };
+        if count == 0 {
+            return Value::Array(vec![]);
+        }
+        let request = types::Request::Recent {
+            channel_id: args[0].clone(),
+            count,
+            offset: 0,
+        };
+        match self.dispatch(request) {
+            types::Response::Recent { messages } => {
+                let mut entries: Vec<Value> = messages.iter().map(entry).collect();
+                if reverse {
+                    entries.reverse();
+                }
+                Value::Array(entries)
+            }
+            types::Response::Error { ref message } if message == "No messages found" => {
+                Value::Array(vec![])
+            }
+            types::Response::Error { message } => error_value(message),
+            _ => Value::Error("ERR unexpected response".to_string()),
+        }
+    }
+
+    fn xlen(&mut self, args: &[String]) -> Value {
+        if args.len() != 1 {
+            return wrong_arity("xlen");
+        }
+        match self.dispatch(types::Request::Stats {
+            channel_id: args[0].clone(),
+        }) {
+            types::Response::Stats { stats } => {
+                Value::Integer(stats["messages"].as_i64().unwrap_or(0))
+            }
+            types::Response::Error { message } => error_value(message),
+            _ => Value::Error("ERR unexpected response".to_string()),
+        }
+    }
+
+    fn info(&mut self) -> Value {
+        let stats = match self.dispatch(types::Request::Info) {
+            types::Response::Stats { stats } => stats,
+            types::Response::Error { message } => return error_value(message),
+            _ => return Value::Error("ERR unexpected response".to_string()),
+        };
+        let mut text = String::new();
+        if let Some(sections) = stats.as_object() {
+            for (section, fields) in sections {
+                text.push_str(&format!("# {}\r\n", section));
+                for (key, value) in fields.as_object().into_iter().flatten() {
+                    let value = match value {
+                        serde_json::Value::String(value) => value.clone(),
+                        serde_json::Value::Null => String::new(),
+                        value => value.to_string(),
+                    };
+                    text.push_str(&format!("{}:{}\r\n", key, value));
+                }
+                text.push_str("\r\n");
+            }
+        }
+        Value::bulk(&text)
+    }
+
+    /// `CLIENT LIST`, `CLIENT KILL ID <id>` and `CLIENT ID`; other
+    /// subcommands, such as `SETNAME`, are accepted and ignored.
+    fn client(&mut self, args: &[String]) -> Value {
+        let subcommand = args.first().map(|arg| arg.to_uppercase()).unwrap_or_default();
+        match subcommand.as_str() {
+            "ID" => Value::Integer(self.session.id as i64),
+            "LIST" => match self.dispatch(types::Request::ClientList) {
+                types::Response::Clients { clients } => {
+                    let mut text = String::new();
+                    for client in clients {
+                        text.push_str(&format!(
+                            "id={} kind={} addr={} age={} idle={} cmds={} channel={}\n",
+                            client.id,
+                            client.kind,
+                            client.addr.unwrap_or_default(),
+                            client.age,
+                            client.idle,
+                            client.commands,
+                            client.channel.unwrap_or_default()
+                        ));
+                    }
+                    Value::bulk(&text)
+                }
+                types::Response::Error { message } => error_value(message),
+                _ => Value::Error("ERR unexpected response".to_string()),
+            },
+            "KILL" => {
+                let id = match args {
+                    [_, filter, id] if filter.eq_ignore_ascii_case("id") => id,
+                    _ => return Value::Error("ERR syntax error".to_string()),
+                };
+                let id = match id.parse::<u64>() {
+                    Ok(id) => id,
+                    Err(_) => {
+                        return Value::Error("ERR client-id should be greater than 0".to_string())
+                    }
+                };
+                match self.dispatch(types::Request::ClientKill { id }) {
+                    types::Response::Killed { killed, .. } => Value::Integer(killed as i64),
+                    types::Response::Error { message } => error_value(message),
+                    _ => Value::Error("ERR unexpected response".to_string()),
+                }
+            }
+            _ => Value::Simple("OK".to_string()),
+        }
+    }
+
+    fn del(&mut self, args: &[String]) -> Value {
+        if args.is_empty() {
+            return wrong_arity("del");
+        }
+        // Every key is checked first, so a bad one doesn't leave the keys
+        // before it deleted.
+        for channel_id in args {
+            if let Err(message) = types::check_channel_id(channel_id) {
+                return error_value(message);
+            }
+        }
+        let mut deleted = 0;
+        for channel_id in args {
+            let existed = self.db.channels.lock().unwrap().contains_key(channel_id);
+            match self.dispatch(types::Request::Flush {
+                channel_id: channel_id.clone(),
+                archive: false,
+            }) {
+                types::Response::Error { message } => return error_value(message),
+                _ if existed => deleted += 1,
+                _ => (),
+            }
+        }
+        Value::Integer(deleted)
+    }
+
+    /// Runs one command and returns the reply to send.
+    pub fn execute(&mut self, args: Vec<Vec<u8>>) -> Value {
+        let args: Vec<String> = match args.into_iter().map(String::from_utf8).collect() {
+            Ok(args) => args,
+            Err(_) => return Value::Error("ERR arguments must be UTF-8".to_string()),
+        };
+        let command = match args.first() {
+            Some(command) => command.to_uppercase(),
+            None => return Value::Error("ERR empty command".to_string()),
+        };
+        let args = &args[1..];
+        debug!("resp command: {} {:?}", command, args);
+
+        match command.as_str() {
+            "PING" => match args.first() {
+                Some(message) => Value::bulk(message),
+                None => Value::Simple("PONG".to_string()),
+            },
+            "ECHO" if args.len() == 1 => Value::bulk(&args[0]),
+            "HELLO" => self.hello(args),
+            "AUTH" if args.len() == 1 || args.len() == 2 => {
+                match self.authenticate(args.last().unwrap()) {
+                    Some(error) => error,
+                    None => Value::Simple("OK".to_string()),
+                }
+            }
+            "SELECT" if args.len() == 1 => match args[0].as_str() {
+                "0" => Value::Simple("OK".to_string()),
+                _ => Value::Error("ERR DB index is out of range".to_string()),
+            },
+            "QUIT" => Value::Simple("OK".to_string()),
+            "CLIENT" => self.client(args),
+            "INFO" => self.info(),
+            "COMMAND" => Value::Array(vec![]),
+            "XADD" => self.xadd(args),
+            "XRANGE" => self.range("xrange", args, false),
+            "XREVRANGE" => self.range("xrevrange", args, true),
+            "XLEN" => self.xlen(args),
+            "DEL" => self.del(args),
+            "ECHO" | "AUTH" | "SELECT" => wrong_arity(&command),
+            _ => Value::Error(format!("ERR unknown command '{}'", command.to_lowercase())),
+        }
+    }
+
+    pub fn reply(&mut self, args: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.execute(args).encode(self.protocol, &mut out);
+        out
+    }
+}
+
+/// Builds the optional RESP listener from the `[resp]` section, or `None`
+/// when it is disabled.
+pub fn server(
+    db: &Arc<state::Database>,
+    shared: &conf::Shared,
+) -> Result<Option<Box<dyn Future<Item = (), Error = ()> + Send>>, String> {
+    let conf = shared.read().unwrap();
+    if !conf.get::<bool>("resp.enabled").unwrap_or(false) {
+        return Ok(None);
+    }
+    let addr: SocketAddr = conf
+        .get::<String>("resp.address")
+        .map_err(|_| "resp.address is required when resp.enabled is set".to_string())?
+        .parse()
+        .map_err(|e| format!("resp.address is invalid: {}", e))?;
+    let socket = TcpListener::bind(&addr).map_err(|e| format!("unable to bind {}: {}", addr, e))?;
+    info!("RESP listening on: {}", addr);
+
+    let db = db.clone();
+    let conf = shared.clone();
+    let done = socket
+        .incoming()
+        .map_err(|e| error!("failed to accept resp socket; error = {:?}", e))
+        .for_each(move |socket| {
+            debug!("accepted resp socket; addr={:?}", socket.peer_addr().ok());
+            let addr = socket.peer_addr().ok().map(|addr| addr.to_string());
+            let (sink, stream) = Framed::new(socket, RespCodec).split();
+            let (session, killed) = session::Session::connect("resp", addr);
+            let mut connection = RespConnection::with_session(&db, &conf, session);
+            let replies = stream.map(move |args| connection.reply(args));
+            let connection = sink.send_all(replies).then(|_| Ok(()));
+            tokio::spawn(clients::killable(connection, killed));
+            Ok(())
+        });
+    Ok(Some(Box::new(done)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::testing::{make_conf, make_db};
+
+    /// Encodes a command the way Redis clients send it.
+    fn command(args: &[&str]) -> Vec<u8> {
+        let mut out = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            out.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+        out
+    }
+
+    fn make_connection() -> RespConnection {
+        let conf = conf::shared(make_conf("resp"));
+        RespConnection::new(&make_db(), &conf)
+    }
+
+    fn send(connection: &mut RespConnection, args: &[&str]) -> Vec<u8> {
+        let raw = command(args);
+        let (parsed, used) = parse_command(&raw).unwrap().unwrap();
+        assert_eq!(used, raw.len());
+        connection.reply(parsed)
+    }
+
+    fn text(bytes: Vec<u8>) -> String {
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn parse_command_incomplete_and_pipelined() {
+        let raw = command(&["XLEN", "foo"]);
+        for cut in 0..raw.len() {
+            assert!(
+                parse_command(&raw[..cut]).unwrap().is_none(),
+                "cut at {}",
+                cut
+            );
+        }
+        let mut pipelined = raw.clone();
+        pipelined.extend_from_slice(b"PING\r\n");
+        let (args, used) = parse_command(&pipelined).unwrap().unwrap();
+        assert_eq!(args, vec![b"XLEN".to_vec(), b"foo".to_vec()]);
+        let (args, _) = parse_command(&pipelined[used..]).unwrap().unwrap();
+        assert_eq!(args, vec![b"PING".to_vec()]);
+        assert!(parse_command(b"*1\r\n:1\r\n").is_err());
+    }
+
+    #[test]
+    fn parse_command_bounds_lines_and_preallocation() {
+        let mut inline = vec![b'a'; MAX_INLINE_LENGTH];
+        assert!(parse_command(&inline).unwrap().is_none());
+        inline.push(b'a');
+        assert!(parse_command(&inline).is_err());
+        let mut header = b"*1\r\n$".to_vec();
+        header.extend(vec![b'1'; MAX_INLINE_LENGTH + 1]);
+        assert!(parse_command(&header).is_err());
+
+        let huge = format!("*{}\r\n$4\r\nPING\r\n", MAX_ARGUMENTS);
+        assert!(parse_command(huge.as_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    fn ping_and_unknown() {
+        let mut connection = make_connection();
+        assert_eq!(text(send(&mut connection, &["PING"])), "+PONG\r\n");
+        assert_eq!(
+            text(send(&mut connection, &["GET", "foo"])),
+            "-ERR unknown command 'get'\r\n"
+        );
+    }
+
+    #[test]
+    fn info_and_client() {
+        let mut connection = make_connection();
+        let info = text(send(&mut connection, &["INFO"]));
+        assert!(info.contains("# server\r\n"), "{:?}", info);
+        assert!(info.contains("\r\nconnected:"), "{:?}", info);
+        let id = connection.session.id as i64;
+        assert_eq!(
+            text(send(&mut connection, &["CLIENT", "ID"])),
+            format!(":{}\r\n", id)
+        );
+        assert_eq!(
+            text(send(&mut connection, &["CLIENT", "SETNAME", "x"])),
+            "+OK\r\n"
+        );
+        assert_eq!(
+            text(send(&mut connection, &["CLIENT", "KILL", "ID", "0"])),
+            ":0\r\n"
+        );
+        assert_eq!(
+            text(send(&mut connection, &["CLIENT", "KILL", "127.0.0.1:1"])),
+            "-ERR syntax error\r\n"
+        );
+    }
+
+    #[test]
+    fn xadd_xlen_and_ranges() {
+        let mut connection = make_connection();
+        let first = text(send(&mut connection, &["XADD", "foo", "*", "value", "one"]));
+        assert!(first.starts_with("$22\r\n"), "{:?}", first);
+        send(
+            &mut connection,
+            &["XADD", "foo", "*", "user", "adam", "text", "two"],
+        );
+        assert_eq!(text(send(&mut connection, &["XLEN", "foo"])), ":2\r\n");
+        assert_eq!(text(send(&mut connection, &["XLEN", "missing"])), ":0\r\n");
+
+        let range = text(send(&mut connection, &["XRANGE", "foo", "-", "+"]));
+        assert!(range.starts_with("*2\r\n*2\r\n$22\r\n"), "{:?}", range);
+        assert!(
+            range.contains("$5\r\nvalue\r\n$3\r\none\r\n"),
+            "{:?}",
+            range
+        );
+        assert!(range.ends_with("*4\r\n$4\r\ntext\r\n$3\r\ntwo\r\n$4\r\nuser\r\n$4\r\nadam\r\n"));
+
+        let reversed = text(send(
+            &mut connection,
+            &["XREVRANGE", "foo", "+", "-", "COUNT", "1"],
+        ));
+        assert!(reversed.starts_with("*1\r\n"), "{:?}", reversed);
+        assert!(reversed.contains("$3\r\ntwo\r\n"), "{:?}", reversed);
+
+        assert_eq!(
+            text(send(&mut connection, &["XRANGE", "missing", "-", "+"])),
+            "*0\r\n"
+        );
+        assert!(text(send(&mut connection, &["XRANGE", "foo", "0", "+"])).starts_with("-ERR"));
+        assert!(text(send(&mut connection, &["XADD", "foo", "1-1", "a", "b"])).starts_with("-ERR"));
+        assert!(text(send(&mut connection, &["XADD", "foo", "*", "a"]))
+            .starts_with("-ERR wrong number"));
+    }
+
+    #[test]
+    fn del_counts_channels() {
+        let mut connection = make_connection();
+        send(&mut connection, &["XADD", "foo", "*", "value", "one"]);
+        assert_eq!(
+            text(send(&mut connection, &["DEL", "foo", "bar"])),
+            ":1\r\n"
+        );
+        assert_eq!(text(send(&mut connection, &["XLEN", "foo"])), ":0\r\n");
+    }
+
+    #[test]
+    fn del_refuses_unsafe_channel_ids() {
+        let mut connection = make_connection();
+        send(&mut connection, &["XADD", "foo", "*", "value", "one"]);
+        for id in &["", ".", "..", "../foo"] {
+            assert_eq!(
+                text(send(&mut connection, &["DEL", "foo", id])),
+                format!("-ERR invalid channel_id: {:?}\r\n", id)
+            );
+        }
+        assert_eq!(text(send(&mut connection, &["XLEN", "foo"])), ":1\r\n");
+    }
+
+    #[test]
+    fn hello_switches_protocol() {
+        let mut connection = make_connection();
+        let reply = text(send(&mut connection, &["HELLO", "3"]));
+        assert!(
+            reply.starts_with("%7\r\n$6\r\nserver\r\n$7\r\nmerkava\r\n"),
+            "{:?}",
+            reply
+        );
+        assert_eq!(connection.protocol, 3);
+
+        let mut out = Vec::new();
+        Value::Map(vec![(Value::bulk("a"), Value::Integer(1))]).encode(2, &mut out);
+        assert_eq!(text(out), "*2\r\n$1\r\na\r\n:1\r\n");
+
+        let reply = text(send(&mut connection, &["HELLO", "4"]));
+        assert!(reply.starts_with("-NOPROTO"), "{:?}", reply);
+    }
+
+    #[test]
+    fn xrange_without_count_uses_limits_recent() {
+        let mut connection = make_connection();
+        connection.conf.write().unwrap().set("limits.recent", 1).unwrap();
+        send(&mut connection, &["XADD", "limited", "*", "value", "one"]);
+        send(&mut connection, &["XADD", "limited", "*", "value", "two"]);
+        let range = text(send(&mut connection, &["XRANGE", "limited", "-", "+"]));
+        assert!(range.starts_with("*1\r\n"), "{:?}", range);
+    }
+
+    #[test]
+    fn auth_errors_use_redis_codes() {
+        let db = make_db();
+        let mut conf = config::Config::default();
+        conf.set("auth.enabled", true).unwrap();
+        conf.set("auth.tokens.reader.token", "secret").unwrap();
+        conf.set("auth.tokens.reader.read", vec!["*"]).unwrap();
+        let mut connection = RespConnection::new(&db, &conf::shared(conf));
+
+        assert!(text(send(&mut connection, &["XLEN", "foo"])).starts_with("-NOAUTH"));
+        assert!(text(send(&mut connection, &["AUTH", "wrong"])).starts_with("-WRONGPASS"));
+        let reply = text(send(&mut connection, &["HELLO", "3", "AUTH", "default", "wrong"]));
+        assert!(reply.starts_with("-WRONGPASS"), "{:?}", reply);
+        assert_eq!(connection.protocol, 2);
+        assert_eq!(
+            text(send(&mut connection, &["AUTH", "default", "secret"])),
+            "+OK\r\n"
+        );
+        assert_eq!(text(send(&mut connection, &["XLEN", "foo"])), ":0\r\n");
+        assert!(
+            text(send(&mut connection, &["XADD", "foo", "*", "value", "x"])).starts_with("-NOPERM")
+        );
+    }
+}
@@ -0,0 +1,49 @@
+use crate::lib::{auth, clients, state};
+use futures::sync::oneshot;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Debug)]
+pub struct Session {
+    pub id: u64,
+    /// The client's address, where the listener knows it.
+    pub addr: Option<String>,
+    pub grants: Option<auth::Grants>,
+    /// Where pushes to subscribed channels are delivered, for connections
+    /// that can receive messages they didn't ask for.
+    pub feed: Option<state::Feed>,
+    /// The change made by the last request, if it wrote anything.
+    pub written: Option<u64>,
+    /// Whether writes are answered only once a majority has applied them.
+    /// With elections on, other sessions can't write.
+    pub acknowledged: bool,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session {
+            id: NEXT_ID.fetch_add(1, Ordering::SeqCst) as u64,
+            addr: None,
+            grants: None,
+            feed: None,
+            written: None,
+            acknowledged: false,
+        }
+    }
+
+    /// A session for a long-lived connection, listed by `CLIENT LIST` until
+    /// it is dropped. The receiver resolves on `CLIENT KILL`.
+    pub fn connect(kind: &'static str, addr: Option<String>) -> (Session, oneshot::Receiver<()>) {
+        let mut session = Session::new();
+        let killed = clients::CLIENTS.register(session.id, kind, addr.clone());
+        session.addr = addr;
+        (session, killed)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        clients::CLIENTS.unregister(self.id);
+    }
+}
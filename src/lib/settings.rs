@@ -0,0 +1,723 @@
+use crate::lib::auth::TokenConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+
+/// Every setting the server reads, with the defaults used when a key is
+/// left out of the file. Sections reject keys they don't know so a typo is
+/// reported rather than silently ignored.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub network: Network,
+    pub http: Listener,
+    pub websocket: Listener,
+    pub resp: Listener,
+    pub metrics: Listener,
+    pub replication: Replication,
+    pub cluster: Cluster,
+    pub tls: Tls,
+    pub persistence: Persistence,
+    pub push: Push,
+    pub shutdown: Shutdown,
+    pub slowlog: Slowlog,
+    pub limits: Limits,
+    pub retention: Retention,
+    pub audit: Audit,
+    pub changes: Changes,
+    pub auth: Auth,
+    pub webhooks: HashMap<String, Webhook>,
+    pub logging: Logging,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Network {
+    pub address: String,
+    pub unix_socket: Option<String>,
+    pub unix_socket_mode: Option<String>,
+}
+
+impl Default for Network {
+    fn default() -> Network {
+        Network {
+            address: "127.0.0.1:6363".to_string(),
+            unix_socket: None,
+            unix_socket_mode: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Listener {
+    pub enabled: bool,
+    pub address: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Replication {
+    /// Where followers and peers connect to this node.
+    pub address: Option<String>,
+    /// The leader to follow. Setting it makes this node a read-only
+    /// follower.
+    pub leader: Option<String>,
+    /// The replication addresses of the other nodes. Setting it turns on
+    /// elections, and writes are only answered once a majority has them.
+    pub peers: Vec<String>,
+    /// Milliseconds without a leader before a node stands for election.
+    pub election_timeout: u64,
+    /// Milliseconds a write waits for a majority before failing.
+    pub ack_timeout: u64,
+    /// Shared by every node, and required of followers before they are
+    /// sent anything.
+    pub token: Option<String>,
+}
+
+impl Default for Replication {
+    fn default() -> Replication {
+        Replication {
+            address: None,
+            leader: None,
+            peers: Vec::new(),
+            election_timeout: 1000,
+            ack_timeout: 2000,
+            token: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cluster {
+    pub enabled: bool,
+    /// The line protocol address of every node, this one included. Every
+    /// node must list the same nodes in the same order.
+    pub nodes: Vec<String>,
+    /// This node's entry in `nodes`, when it isn't `network.address`.
+    pub address: Option<String>,
+    /// How many points each node has on the hash ring.
+    pub vnodes: u32,
+    /// Sent with `AUTH` before moving channels, when authentication is on.
+    pub token: Option<String>,
+}
+
+impl Default for Cluster {
+    fn default() -> Cluster {
+        Cluster {
+            enabled: false,
+            nodes: Vec::new(),
+            address: None,
+            vnodes: 64,
+            token: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    pub enabled: bool,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub client_ca: Option<String>,
+    pub require_client_cert: bool,
+}
+
+impl Default for Tls {
+    fn default() -> Tls {
+        Tls {
+            enabled: false,
+            cert: None,
+            key: None,
+            client_ca: None,
+            require_client_cert: true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Persistence {
+    pub interval: u64,
+    pub path: String,
+    pub archive_path: Option<String>,
+}
+
+impl Default for Persistence {
+    fn default() -> Persistence {
+        Persistence {
+            interval: 0,
+            path: "./merkava-data".to_string(),
+            archive_path: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Push {
+    /// Seconds a `PUSHKEY` is remembered, at most a year.
+    pub idempotency_window: u64,
+}
+
+/// The longest `push.idempotency_window`: a year.
+pub const IDEMPOTENCY_WINDOW_MAXIMUM: u64 = 365 * 24 * 60 * 60;
+
+impl Default for Push {
+    fn default() -> Push {
+        Push {
+            idempotency_window: 300,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Shutdown {
+    pub timeout: u64,
+}
+
+impl Default for Shutdown {
+    fn default() -> Shutdown {
+        Shutdown { timeout: 10 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Slowlog {
+    /// Commands taking at least this many milliseconds are recorded.
+    pub threshold: u64,
+    /// How many are kept; 0 turns the slow log off.
+    pub max_len: usize,
+}
+
+impl Default for Slowlog {
+    fn default() -> Slowlog {
+        Slowlog {
+            threshold: 10,
+            max_len: 128,
+        }
+    }
+}
+
+/// Caps on how much one request reads.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// The most messages `RECENT` returns.
+    pub recent: usize,
+    /// The most channels a `LIST` page examines.
+    pub list: usize,
+    /// The most events `CHANGES` returns.
+    pub changes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            recent: 10,
+            list: 1000,
+            changes: 1000,
+        }
+    }
+}
+
+/// How long messages are kept. Each node trims its own channels, once a
+/// second, and the trimming isn't replicated or recorded.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    /// Messages kept per channel, oldest dropped first; 0 keeps them all.
+    pub max_len: usize,
+    /// Seconds a message is kept after it was created; 0 keeps it forever.
+    pub max_age: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Audit {
+    pub enabled: bool,
+    pub path: String,
+    /// Bytes written before the file is rotated, or 0 to never rotate on size.
+    pub rotate_size: u64,
+    /// Seconds the file is written to before it is rotated, or 0 for never.
+    pub rotate_age: u64,
+}
+
+impl Default for Audit {
+    fn default() -> Audit {
+        Audit {
+            enabled: false,
+            path: "audit.log".to_string(),
+            rotate_size: 0,
+            rotate_age: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Changes {
+    pub enabled: bool,
+    pub path: String,
+    /// How many of the newest events are kept for `CHANGES`.
+    pub max_len: usize,
+}
+
+impl Default for Changes {
+    fn default() -> Changes {
+        Changes {
+            enabled: false,
+            path: "changes.log".to_string(),
+            max_len: 100_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    pub enabled: bool,
+    pub tokens: HashMap<String, TokenConfig>,
+}
+
+/// Where pushes and updates on matching channels are posted, under
+/// `[webhooks.<name>]`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Webhook {
+    /// A glob of the channels posted about.
+    pub channels: String,
+    /// An `http://` URL.
+    pub url: String,
+    /// Signs each body with HMAC-SHA256 when set.
+    pub secret: Option<String>,
+    /// Tries after the first before a delivery is given up on.
+    pub retries: u32,
+    /// Milliseconds before the first retry, doubled for each one after.
+    pub backoff: u64,
+    /// Milliseconds each try waits for an answer.
+    pub timeout: u64,
+    /// Deliveries given up on are appended here as JSON lines.
+    pub dead_letter: String,
+    /// Deliveries waiting to be posted; more go straight to `dead_letter`.
+    pub queue: usize,
+    /// Deliveries posted at once.
+    pub in_flight: usize,
+}
+
+/// The longest `backoff` a hook can have: an hour.
+pub const WEBHOOK_BACKOFF_MAXIMUM: u64 = 3_600_000;
+
+impl Default for Webhook {
+    fn default() -> Webhook {
+        Webhook {
+            channels: "*".to_string(),
+            url: String::new(),
+            secret: None,
+            retries: 5,
+            backoff: 500,
+            timeout: 5000,
+            dead_letter: "webhooks-dead.log".to_string(),
+            queue: 1000,
+            in_flight: 4,
+        }
+    }
+}
+
+fn check_webhook(source: &str, name: &str, webhook: &Webhook) -> Result<(), String> {
+    if let Err(e) = glob::Pattern::new(&webhook.channels) {
+        return Err(format!(
+            "{}: webhooks.{}.channels {:?} is not a valid pattern: {}",
+            source, name, webhook.channels, e.msg
+        ));
+    }
+    match webhook.url.parse::<hyper::Uri>() {
+        Ok(ref url) if url.scheme_str() == Some("http") && url.host().is_some() => {}
+        Ok(_) => {
+            return Err(format!(
+                "{}: webhooks.{}.url {:?} must be an http:// URL",
+                source, name, webhook.url
+            ));
+        }
+        Err(e) => {
+            return Err(format!(
+                "{}: webhooks.{}.url {:?} is not a valid URL: {}",
+                source, name, webhook.url, e
+            ));
+        }
+    }
+    if webhook.timeout == 0 {
+        return Err(format!(
+            "{}: webhooks.{}.timeout must be at least 1",
+            source, name
+        ));
+    }
+    if webhook.backoff > WEBHOOK_BACKOFF_MAXIMUM {
+        return Err(format!(
+            "{}: webhooks.{}.backoff can be at most {}",
+            source, name, WEBHOOK_BACKOFF_MAXIMUM
+        ));
+    }
+    if webhook.dead_letter.is_empty() {
+        return Err(format!(
+            "{}: webhooks.{}.dead_letter can't be empty",
+            source, name
+        ));
+    }
+    if webhook.queue == 0 || webhook.in_flight == 0 {
+        return Err(format!(
+            "{}: webhooks.{}.queue and webhooks.{}.in_flight must be at least 1",
+            source, name, name
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Logging {
+    pub verbosity: u64,
+    pub format: LogFormat,
+    pub color: bool,
+    pub stdout: bool,
+    /// An empty path turns the log file off.
+    pub file: String,
+    /// Bytes written before the file is rotated, or 0 to never rotate on size.
+    pub rotate_size: u64,
+    /// Seconds the file is written to before it is rotated, or 0 for never.
+    pub rotate_age: u64,
+    pub rotate_keep: usize,
+}
+
+impl Default for Logging {
+    fn default() -> Logging {
+        Logging {
+            verbosity: 0,
+            format: LogFormat::Text,
+            color: true,
+            stdout: true,
+            file: "program.log".to_string(),
+            rotate_size: 0,
+            rotate_age: 0,
+            rotate_keep: 5,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+fn check_address(source: &str, key: &str, address: &str) -> Result<(), String> {
+    address.parse::<SocketAddr>().map(|_| ()).map_err(|e| {
+        format!(
+            "{}: {} {:?} is not a valid address: {}",
+            source, key, address, e
+        )
+    })
+}
+
+/// Whether `inner` is `outer` or inside it, comparing the paths as written.
+fn is_within(inner: &str, outer: &str) -> bool {
+    let plain = |path: &str| -> PathBuf {
+        Path::new(path)
+            .components()
+            .filter(|component| *component != Component::CurDir)
+            .collect()
+    };
+    plain(inner).starts_with(plain(outer))
+}
+
+fn check_listener(source: &str, section: &str, listener: &Listener) -> Result<(), String> {
+    if !listener.enabled {
+        return Ok(());
+    }
+    match listener.address {
+        Some(ref address) => check_address(source, &format!("{}.address", section), address),
+        None => Err(format!(
+            "{}: {}.address is required when {}.enabled is set",
+            source, section, section
+        )),
+    }
+}
+
+impl Settings {
+    /// Reads the merged file and environment, naming `source` in any error.
+    pub fn from_config(conf: &config::Config, source: &str) -> Result<Settings, String> {
+        let settings: Settings = conf
+            .clone()
+            .try_into()
+            .map_err(|e| format!("{}: {}", source, e))?;
+        settings.validate(source)?;
+        Ok(settings)
+    }
+
+    pub fn validate(&self, source: &str) -> Result<(), String> {
+        if self.network.address.is_empty() && self.network.unix_socket.is_none() {
+            return Err(format!(
+                "{}: one of network.address or network.unix_socket is required",
+                source
+            ));
+        }
+        if !self.network.address.is_empty() {
+            check_address(source, "network.address", &self.network.address)?;
+        }
+        if let Some(ref mode) = self.network.unix_socket_mode {
+            if u32::from_str_radix(mode, 8).is_err() {
+                return Err(format!(
+                    "{}: network.unix_socket_mode {:?} is not octal",
+                    source, mode
+                ));
+            }
+        }
+        check_listener(source, "http", &self.http)?;
+        check_listener(source, "websocket", &self.websocket)?;
+        check_listener(source, "resp", &self.resp)?;
+        check_listener(source, "metrics", &self.metrics)?;
+        if let Some(ref address) = self.replication.address {
+            check_address(source, "replication.address", address)?;
+        }
+        if let Some(ref leader) = self.replication.leader {
+            check_address(source, "replication.leader", leader)?;
+        }
+        for peer in &self.replication.peers {
+            check_address(source, "replication.peers", peer)?;
+        }
+        let replicating = self.replication.address.is_some() || self.replication.leader.is_some();
+        match self.replication.token {
+            None if replicating => {
+                return Err(format!(
+                    "{}: replication.token is required when replication.address or \
+                     replication.leader is set",
+                    source
+                ));
+            }
+            Some(ref token) if token.is_empty() || token.contains(char::is_whitespace) => {
+                return Err(format!(
+                    "{}: replication.token can't be empty or contain spaces",
+                    source
+                ));
+            }
+            _ => {}
+        }
+        if !self.replication.peers.is_empty() {
+            if self.replication.address.is_none() {
+                return Err(format!(
+                    "{}: replication.address is required when replication.peers is set",
+                    source
+                ));
+            }
+            if self.replication.leader.is_some() {
+                return Err(format!(
+                    "{}: replication.leader and replication.peers can't both be set",
+                    source
+                ));
+            }
+            if self.replication.election_timeout < 500 {
+                return Err(format!(
+                    "{}: replication.election_timeout must be at least 500",
+                    source
+                ));
+            }
+        }
+        if self.cluster.enabled {
+            for node in &self.cluster.nodes {
+                check_address(source, "cluster.nodes", node)?;
+            }
+            let address = self
+                .cluster
+                .address
+                .as_ref()
+                .unwrap_or(&self.network.address);
+            if !self.cluster.nodes.contains(address) {
+                return Err(format!(
+                    "{}: cluster.nodes must include this node's address {:?}",
+                    source, address
+                ));
+            }
+            if self.cluster.vnodes == 0 {
+                return Err(format!("{}: cluster.vnodes must be at least 1", source));
+            }
+        }
+        if self.tls.enabled {
+            if self.tls.cert.is_none() {
+                return Err(format!(
+                    "{}: tls.cert is required when tls.enabled is set",
+                    source
+                ));
+            }
+            if self.tls.key.is_none() {
+                return Err(format!(
+                    "{}: tls.key is required when tls.enabled is set",
+                    source
+                ));
+            }
+        }
+        if self.audit.enabled && self.audit.path.is_empty() {
+            return Err(format!(
+                "{}: audit.path is required when audit.enabled is set",
+                source
+            ));
+        }
+        if self.changes.enabled && self.changes.path.is_empty() {
+            return Err(format!(
+                "{}: changes.path is required when changes.enabled is set",
+                source
+            ));
+        }
+        if self.changes.enabled && self.changes.max_len == 0 {
+            return Err(format!("{}: changes.max_len must be at least 1", source));
+        }
+        let mut names: Vec<&String> = self.webhooks.keys().collect();
+        names.sort();
+        for name in names {
+            check_webhook(source, name, &self.webhooks[name])?;
+        }
+        if self.limits.recent == 0 || self.limits.list == 0 || self.limits.changes == 0 {
+            return Err(format!(
+                "{}: limits.recent, limits.list and limits.changes must be at least 1",
+                source
+            ));
+        }
+        if self.push.idempotency_window > IDEMPOTENCY_WINDOW_MAXIMUM {
+            return Err(format!(
+                "{}: push.idempotency_window can be at most {}",
+                source, IDEMPOTENCY_WINDOW_MAXIMUM
+            ));
+        }
+        if self.persistence.path.is_empty() {
+            return Err(format!("{}: persistence.path can't be empty", source));
+        }
+        if let Some(ref archive_path) = self.persistence.archive_path {
+            // Archived channels there would be loaded back as channels.
+            if is_within(archive_path, &self.persistence.path) {
+                return Err(format!(
+                    "{}: persistence.archive_path can't be inside persistence.path",
+                    source
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The settings as a `config::Config` with every default filled in, for
+    /// the parts of the server that look keys up by name.
+    pub fn to_config(&self) -> Result<config::Config, String> {
+        config::Config::try_from(self).map_err(|e| e.to_string())
+    }
+
+    /// The effective settings as TOML, with token secrets hidden.
+    pub fn to_toml(&self) -> Result<String, String> {
+        let mut shown = self.clone();
+        for token in shown.auth.tokens.values_mut() {
+            token.token = "<redacted>".to_string();
+        }
+        if shown.replication.token.is_some() {
+            shown.replication.token = Some("<redacted>".to_string());
+        }
+        for webhook in shown.webhooks.values_mut() {
+            if webhook.secret.is_some() {
+                webhook.secret = Some("<redacted>".to_string());
+            }
+        }
+        toml::to_string(&shown).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_toml(body: &str) -> Result<Settings, String> {
+        let mut conf = config::Config::default();
+        conf.merge(config::File::from_str(body, config::FileFormat::Toml))
+            .unwrap();
+        Settings::from_config(&conf, "test.toml")
+    }
+
+    #[test]
+    fn defaults_fill_missing_keys() {
+        let settings = from_toml("[logging]\nverbosity = 2\nformat = \"json\"\n").unwrap();
+        assert_eq!(settings.logging.verbosity, 2);
+        assert_eq!(settings.logging.format, LogFormat::Json);
+        assert_eq!(settings.logging.file, "program.log");
+        assert_eq!(settings.network.address, "127.0.0.1:6363");
+        assert_eq!(settings.persistence.path, "./merkava-data");
+        assert_eq!(settings.push.idempotency_window, 300);
+        assert!(settings.tls.require_client_cert);
+
+        let conf = settings.to_config().unwrap();
+        assert_eq!(conf.get::<u64>("shutdown.timeout").unwrap(), 10);
+        assert!(conf.get::<String>("network.unix_socket").is_err());
+    }
+
+    #[test]
+    fn errors_name_the_key_and_source() {
+        let error = from_toml("[persistence]\ninterval = \"soon\"\n").unwrap_err();
+        assert!(error.starts_with("test.toml: "), "{}", error);
+        assert!(error.contains("persistence.interval"), "{}", error);
+
+        let error = from_toml("[persistence]\nintervall = 5\n").unwrap_err();
+        assert!(error.contains("intervall"), "{}", error);
+
+        let error = from_toml("[loging]\nverbosity = 2\n").unwrap_err();
+        assert!(error.starts_with("test.toml: "), "{}", error);
+        assert!(error.contains("loging"), "{}", error);
+
+        let error = from_toml("[network]\naddress = \"localhost\"\n").unwrap_err();
+        assert!(error.contains("network.address"), "{}", error);
+
+        let error = from_toml("[http]\nenabled = true\n").unwrap_err();
+        assert!(error.contains("http.address is required"), "{}", error);
+
+        let error = from_toml("[tls]\nenabled = true\ncert = \"a.pem\"\n").unwrap_err();
+        assert!(error.contains("tls.key"), "{}", error);
+
+        let error = from_toml("[logging]\nformat = \"xml\"\n").unwrap_err();
+        assert!(error.contains("xml"), "{}", error);
+
+        let error = from_toml("[webhooks.notify]\nurl = \"https://example.com/\"\n").unwrap_err();
+        assert!(error.contains("webhooks.notify.url"), "{}", error);
+
+        let error = from_toml("[push]\nidempotency_window = 10000000000000\n").unwrap_err();
+        assert!(error.contains("push.idempotency_window"), "{}", error);
+
+        let error = from_toml("[replication]\naddress = \"127.0.0.1:6364\"\n").unwrap_err();
+        assert!(error.contains("replication.token is required"), "{}", error);
+    }
+
+    #[test]
+    fn archive_path_must_be_outside_persistence_path() {
+        for archive in &["./data/archive", "data", "data/./old"] {
+            let body = format!(
+                "[persistence]\npath = \"./data\"\narchive_path = {:?}\n",
+                archive
+            );
+            let error = from_toml(&body).unwrap_err();
+            assert!(error.contains("persistence.archive_path"), "{}", error);
+        }
+        let body = "[persistence]\npath = \"data\"\narchive_path = \"data-archive\"\n";
+        assert!(from_toml(body).is_ok());
+    }
+
+    #[test]
+    fn to_toml_hides_tokens() {
+        let settings = from_toml(
+            "[auth]\nenabled = true\n[auth.tokens.ops]\ntoken = \"secret\"\nadmin = [\"*\"]\n\
+             [replication]\nleader = \"127.0.0.1:6364\"\ntoken = \"secret\"\n",
+        )
+        .unwrap();
+        let shown = settings.to_toml().unwrap();
+        assert!(!shown.contains("secret"), "{}", shown);
+        assert!(shown.contains("[auth.tokens.ops]"), "{}", shown);
+        assert!(shown.contains("<redacted>"), "{}", shown);
+    }
+}
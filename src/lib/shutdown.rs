@@ -0,0 +1,107 @@
+use futures::{Future, Stream};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+/// Counts requests being run so shutdown can wait for them, and turns new
+/// ones away once shutdown has started.
+pub struct Tracker {
+    stopping: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+pub static TRACKER: Tracker = Tracker::new();
+
+/// Held for the length of one request.
+pub struct InFlight<'a> {
+    tracker: &'a Tracker,
+}
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        self.tracker.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Tracker {
+    pub const fn new() -> Tracker {
+        Tracker {
+            stopping: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn begin(&self) -> Option<InFlight<'_>> {
+        // Count first so `drain` can't miss a request that slips in while
+        // `stop` is being called.
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let in_flight = InFlight { tracker: self };
+        if self.stopping.load(Ordering::SeqCst) {
+            return None;
+        }
+        Some(in_flight)
+    }
+
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    /// Waits for running requests to finish, returning false if some were
+    /// still running when the timeout passed.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.in_flight.load(Ordering::SeqCst) > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+}
+
+/// Resolves with the signal number once SIGTERM or SIGINT arrives.
+pub fn signals() -> impl Future<Item = i32, Error = io::Error> {
+    let first = |signal| {
+        Signal::new(signal)
+            .flatten_stream()
+            .into_future()
+            .map(move |_| signal)
+            .map_err(|(e, _)| e)
+    };
+    first(SIGTERM)
+        .select(first(SIGINT))
+        .map(|(signal, _)| signal)
+        .map_err(|(e, _)| e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn stop_turns_away_new_requests() {
+        let tracker = Tracker::new();
+        assert!(tracker.begin().is_some());
+        tracker.stop();
+        assert!(tracker.begin().is_none());
+        assert!(tracker.drain(Duration::from_millis(0)));
+    }
+
+    #[test]
+    fn drain_waits_for_running_requests() {
+        let tracker = Arc::new(Tracker::new());
+        let running = tracker.begin().unwrap();
+        tracker.stop();
+        assert!(!tracker.drain(Duration::from_millis(20)));
+
+        let waiter = tracker.clone();
+        let handle = thread::spawn(move || waiter.drain(Duration::from_secs(5)));
+        thread::sleep(Duration::from_millis(20));
+        drop(running);
+        assert!(handle.join().unwrap());
+    }
+}
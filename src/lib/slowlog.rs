@@ -0,0 +1,93 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// A command that took at least `slowlog.threshold` milliseconds.
+#[derive(Serialize, Debug, Clone)]
+pub struct Entry {
+    pub id: u64,
+    pub started: DateTime<Utc>,
+    pub duration_us: u64,
+    pub conn: u64,
+    pub addr: Option<String>,
+    pub command: &'static str,
+    pub channel: Option<String>,
+}
+
+/// The most recent slow commands, oldest dropped first once it is full.
+pub struct Ring {
+    entries: Mutex<VecDeque<Entry>>,
+    next_id: AtomicU64,
+}
+
+pub static SLOWLOG: Ring = Ring::new();
+
+impl Ring {
+    pub const fn new() -> Ring {
+        Ring {
+            entries: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds `entry` with the next id, keeping at most `max_len` entries.
+    pub fn record(&self, mut entry: Entry, max_len: usize) {
+        if max_len == 0 {
+            return;
+        }
+        entry.id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut entries = self.entries.lock().unwrap();
+        while entries.len() >= max_len {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Up to `count` entries, newest first.
+    pub fn get(&self, count: usize) -> Vec<Entry> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().rev().take(count).cloned().collect()
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(channel: &str) -> Entry {
+        Entry {
+            id: 0,
+            started: Utc::now(),
+            duration_us: 20_000,
+            conn: 1,
+            addr: None,
+            command: "RECENT",
+            channel: Some(channel.to_string()),
+        }
+    }
+
+    #[test]
+    fn ring_keeps_the_newest_entries() {
+        let ring = Ring::new();
+        for channel in &["a", "b", "c"] {
+            ring.record(entry(channel), 2);
+        }
+        let entries = ring.get(10);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].channel.as_ref().unwrap(), "c");
+        assert_eq!(entries[0].id, 2);
+        assert_eq!(entries[1].channel.as_ref().unwrap(), "b");
+        assert_eq!(ring.get(1).len(), 1);
+
+        ring.record(entry("d"), 0);
+        assert_eq!(ring.get(10).len(), 2);
+        ring.reset();
+        assert!(ring.get(10).is_empty());
+    }
+}
@@ -1,9 +1,12 @@
+use crate::lib::{changes, replication};
 use bincode::deserialize_from;
 use chrono::{DateTime, Utc};
 use glob::glob;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::{create_dir_all, File};
+use futures::sync::mpsc::UnboundedSender;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{create_dir_all, metadata, File};
+use std::path::Path;
 use std::result::Result;
 use std::sync::{Arc, Mutex};
 
@@ -11,11 +14,84 @@ use std::sync::{Arc, Mutex};
 pub struct Channel {
     pub index: Mutex<HashMap<String, usize>>,
     pub data: Mutex<Vec<Message>>,
+    pub keys: Mutex<HashMap<String, IdempotencyKey>>,
+    pub counters: Mutex<Counters>,
 }
 
+impl Channel {
+    pub fn new(index: HashMap<String, usize>, data: Vec<Message>) -> Channel {
+        Channel {
+            index: Mutex::new(index),
+            data: Mutex::new(data),
+            keys: Mutex::new(HashMap::new()),
+            counters: Mutex::new(Counters::default()),
+        }
+    }
+}
+
+pub type Feed = UnboundedSender<Message>;
+
 #[derive(Debug)]
 pub struct Database {
-    pub channels: Arc<Mutex<HashMap<String, Channel>>>,
+    pub channels: Arc<Mutex<BTreeMap<String, Channel>>>,
+    pub subscribers: Mutex<HashMap<String, HashMap<u64, Feed>>>,
+    pub replication: Mutex<replication::Hub>,
+}
+
+impl Database {
+    pub fn new(channels: BTreeMap<String, Channel>) -> Database {
+        Database {
+            channels: Arc::new(Mutex::new(channels)),
+            subscribers: Mutex::new(HashMap::new()),
+            replication: Mutex::new(replication::Hub::new()),
+        }
+    }
+
+    pub fn subscribe(&self, channel_id: &str, session_id: u64, feed: Feed) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers
+            .entry(channel_id.to_string())
+            .or_default()
+            .insert(session_id, feed);
+    }
+
+    pub fn unsubscribe(&self, channel_id: &str, session_id: u64) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(feeds) = subscribers.get_mut(channel_id) {
+            feeds.remove(&session_id);
+            if feeds.is_empty() {
+                subscribers.remove(channel_id);
+            }
+        }
+    }
+
+    pub fn unsubscribe_all(&self, session_id: u64) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for feeds in subscribers.values_mut() {
+            feeds.remove(&session_id);
+        }
+        subscribers.retain(|_, feeds| !feeds.is_empty());
+    }
+
+    /// Sends a newly pushed message to every session subscribed to the
+    /// channel, dropping any whose connection has gone away.
+    pub fn publish(&self, channel_id: &str, message: &Message) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(feeds) = subscribers.get_mut(channel_id) {
+            feeds.retain(|_, feed| feed.unbounded_send(message.clone()).is_ok());
+            if feeds.is_empty() {
+                subscribers.remove(channel_id);
+            }
+        }
+    }
+
+    /// Adds a change to the change feed and sends it to every follower.
+    /// Called with the channels lock held, so both see changes in the order
+    /// they were made.
+    pub fn record(&self, change: replication::Change) {
+        changes::record(&change);
+        self.replication.lock().unwrap().record(change);
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -26,43 +102,75 @@ pub struct Message {
     // pub data: String,
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct Counters {
+    pub pushes: u64,
+    pub updates: u64,
+    pub last_backup: Option<DateTime<Utc>>,
+    pub dirty: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct IdempotencyKey {
+    pub uid: String,
+    pub seen: DateTime<Utc>,
+}
+
+/// A channel on its way to another node: its messages, the idempotency
+/// keys it has seen, so a push retried there isn't stored twice, and its
+/// push and update counts.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Export {
+    pub messages: Vec<Message>,
+    pub keys: HashMap<String, IdempotencyKey>,
+    pub pushes: u64,
+    pub updates: u64,
+}
+
 pub fn create_db(data_directory: String) -> Arc<Database> {
     debug!("Creating database");
-    let mut channels = HashMap::new();
+    let mut channels = BTreeMap::new();
 
     create_dir_all(&data_directory).expect("unable to create data directory");
 
-    debug!("{}", format!("Loading records from {}", data_directory));
+    debug!("Loading records from {}", data_directory);
 
     let glob_path = format!("{}/*", data_directory);
 
     for entry in glob(&glob_path).unwrap().filter_map(Result::ok) {
         let path = entry.as_path();
-        let split_path = path.components();
-        let channel_id = match split_path.last() {
+        if !path.is_dir() {
+            // Such as the replication epoch kept alongside the channels.
+            continue;
+        }
+        let mut split_path = path.components();
+        let channel_id = match split_path.next_back() {
             Some(item) => item.as_os_str().to_os_string().into_string().unwrap(),
             _ => break,
         };
 
         let data_file = format!("{}/data.mrkv", path.display());
-        let reader = File::open(data_file).unwrap();
+        let index_file = format!("{}/index.mrkv", path.display());
+        if !Path::new(&data_file).is_file() || !Path::new(&index_file).is_file() {
+            warn!("Skipping {}, which holds no channel backup", path.display());
+            continue;
+        }
+        let reader = File::open(&data_file).unwrap();
         let data: Vec<Message> = deserialize_from(reader).unwrap();
 
-        let index_file = format!("{}/index.mrkv", path.display());
         let reader = File::open(index_file).unwrap();
         let index: HashMap<String, usize> = deserialize_from(reader).unwrap();
 
-        let channel = Channel {
-            index: Mutex::new(index),
-            data: Mutex::new(data),
-        };
+        let channel = Channel::new(index, data);
+        channel.counters.lock().unwrap().last_backup = metadata(&data_file)
+            .and_then(|meta| meta.modified())
+            .ok()
+            .map(DateTime::<Utc>::from);
         channels.insert(channel_id, channel);
     }
 
-    let db = Arc::new(Database {
-        channels: Arc::new(Mutex::new(channels)),
-    });
-    db
+    Arc::new(Database::new(channels))
 }
 
 // pub fn get_channel<'a>(db: &'a Database, channel_id: String) -> &'a Channel {
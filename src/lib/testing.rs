@@ -0,0 +1,18 @@
+use crate::lib::state;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// An empty database for tests.
+pub fn make_db() -> Arc<state::Database> {
+    Arc::new(state::Database::new(BTreeMap::new()))
+}
+
+/// A configuration whose `persistence.path` is a fresh directory under the
+/// system's temporary one, named after the test so tests don't share it.
+pub fn make_conf(name: &str) -> config::Config {
+    let path = std::env::temp_dir().join(format!("merkava-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let mut conf = config::Config::default();
+    conf.set("persistence.path", path.to_str().unwrap()).unwrap();
+    conf
+}
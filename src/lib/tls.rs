@@ -0,0 +1,261 @@
+use futures::{Async, Poll};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate, NoClientAuth,
+    PrivateKey, RootCertStore, ServerConfig, ServerSession, Session, StreamOwned,
+};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A server-side TLS connection over any byte stream. The handshake is
+/// driven lazily by the first read or write, so this can be handed straight
+/// to the same per-connection handling as a plain `TcpStream`.
+pub struct TlsStream<S: Read + Write> {
+    inner: StreamOwned<ServerSession, S>,
+}
+
+impl<S: Read + Write> TlsStream<S> {
+    pub fn new(config: &Arc<ServerConfig>, stream: S) -> TlsStream<S> {
+        TlsStream {
+            inner: StreamOwned::new(ServerSession::new(config), stream),
+        }
+    }
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.sess.send_close_notify();
+        while self.inner.sess.wants_write() {
+            match self.inner.sess.write_tls(&mut self.inner.sock) {
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e),
+            }
+        }
+        self.inner.sock.shutdown()
+    }
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    let file = File::open(path).map_err(|e| format!("unable to open {}: {}", path, e))?;
+    match certs(&mut BufReader::new(file)) {
+        Ok(ref found) if found.is_empty() => Err(format!("no certificates found in {}", path)),
+        Ok(found) => Ok(found),
+        Err(_) => Err(format!("unable to parse certificates in {}", path)),
+    }
+}
+
+fn load_key(path: &str) -> Result<PrivateKey, String> {
+    let open = || File::open(path).map_err(|e| format!("unable to open {}: {}", path, e));
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(open()?)).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(open()?)).unwrap_or_default();
+    }
+    match keys.into_iter().next() {
+        Some(key) => Ok(key),
+        None => Err(format!("no PKCS8 or RSA private key found in {}", path)),
+    }
+}
+
+/// Builds the listener's TLS config from the `[tls]` section, or `None`
+/// when TLS is disabled.
+pub fn load_config(conf: &config::Config) -> Result<Option<Arc<ServerConfig>>, String> {
+    if !conf.get::<bool>("tls.enabled").unwrap_or(false) {
+        return Ok(None);
+    }
+    let cert_path = conf
+        .get::<String>("tls.cert")
+        .map_err(|_| "tls.cert is required when tls.enabled is set".to_string())?;
+    let key_path = conf
+        .get::<String>("tls.key")
+        .map_err(|_| "tls.key is required when tls.enabled is set".to_string())?;
+
+    let verifier = match conf.get::<String>("tls.client_ca") {
+        Ok(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(&ca_path)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| format!("invalid CA certificate in {}: {:?}", ca_path, e))?;
+            }
+            if conf.get::<bool>("tls.require_client_cert").unwrap_or(true) {
+                AllowAnyAuthenticatedClient::new(roots)
+            } else {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+            }
+        }
+        Err(_) => NoClientAuth::new(),
+    };
+
+    let mut config = ServerConfig::new(verifier);
+    config
+        .set_single_cert(load_certs(&cert_path)?, load_key(&key_path)?)
+        .map_err(|e| format!("unable to use {} with {}: {:?}", cert_path, key_path, e))?;
+    Ok(Some(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa};
+    use rustls::{ClientConfig, ClientSession};
+    use std::fs;
+    use std::io::BufRead;
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::thread;
+
+    struct Certs {
+        dir: PathBuf,
+        ca: rcgen::Certificate,
+    }
+
+    impl Certs {
+        fn new(name: &str) -> Certs {
+            let dir = std::env::temp_dir().join(format!("merkava-tls-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let mut params = CertificateParams::new(vec![]);
+            params.distinguished_name = DistinguishedName::new();
+            params.distinguished_name.push(DnType::CommonName, "merkava test ca");
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = rcgen::Certificate::from_params(params).unwrap();
+            fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+            Certs { dir, ca }
+        }
+
+        fn issue(&self, name: &str) -> (String, String) {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+            let cert_path = self.dir.join(format!("{}.pem", name));
+            let key_path = self.dir.join(format!("{}.key", name));
+            fs::write(&cert_path, cert.serialize_pem_with_signer(&self.ca).unwrap()).unwrap();
+            fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+            (
+                cert_path.to_str().unwrap().to_string(),
+                key_path.to_str().unwrap().to_string(),
+            )
+        }
+
+        fn path(&self, file: &str) -> String {
+            self.dir.join(file).to_str().unwrap().to_string()
+        }
+    }
+
+    fn make_conf(certs: &Certs, mutual: bool) -> config::Config {
+        let (cert, key) = certs.issue("server");
+        let mut conf = config::Config::default();
+        conf.set("tls.enabled", true).unwrap();
+        conf.set("tls.cert", cert).unwrap();
+        conf.set("tls.key", key).unwrap();
+        if mutual {
+            conf.set("tls.client_ca", certs.path("ca.pem")).unwrap();
+        }
+        conf
+    }
+
+    fn client_config(certs: &Certs, client_cert: bool) -> Arc<ClientConfig> {
+        let mut config = ClientConfig::new();
+        for cert in load_certs(&certs.path("ca.pem")).unwrap() {
+            config.root_store.add(&cert).unwrap();
+        }
+        if client_cert {
+            let (cert, key) = certs.issue("client");
+            config.set_single_client_cert(load_certs(&cert).unwrap(), load_key(&key).unwrap());
+        }
+        Arc::new(config)
+    }
+
+    /// Serves a single connection that echoes one line back, prefixed with
+    /// "OK", and returns whatever the client read back.
+    fn echo_once(server: Arc<ServerConfig>, client: Arc<ClientConfig>) -> io::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = io::BufReader::new(TlsStream::new(&server, socket));
+            let mut line = String::new();
+            if stream.read_line(&mut line).is_ok() {
+                let _ = stream.get_mut().write_all(format!("OK {}", line).as_bytes());
+                let _ = stream.get_mut().flush();
+            }
+        });
+
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let session = ClientSession::new(&client, dns_name);
+        let socket = TcpStream::connect(addr).unwrap();
+        let mut stream = io::BufReader::new(StreamOwned::new(session, socket));
+        let result = stream
+            .get_mut()
+            .write_all(b"foo STATS\n")
+            .and_then(|_| stream.get_mut().flush())
+            .and_then(|_| {
+                let mut line = String::new();
+                stream.read_line(&mut line).map(|_| line)
+            });
+        drop(stream);
+        handle.join().unwrap();
+        result
+    }
+
+    #[test]
+    fn load_config_disabled() {
+        let conf = config::Config::default();
+        assert!(load_config(&conf).unwrap().is_none());
+    }
+
+    #[test]
+    fn load_config_missing_files() {
+        let mut conf = config::Config::default();
+        conf.set("tls.enabled", true).unwrap();
+        assert!(load_config(&conf).is_err());
+        conf.set("tls.cert", "/nonexistent/server.pem").unwrap();
+        conf.set("tls.key", "/nonexistent/server.key").unwrap();
+        match load_config(&conf) {
+            Err(error) => assert!(error.contains("/nonexistent/server.pem"), "{}", error),
+            Ok(_) => panic!("expected missing certificate to be reported"),
+        }
+    }
+
+    #[test]
+    fn tls_round_trip() {
+        let certs = Certs::new("plain");
+        let server = load_config(&make_conf(&certs, false)).unwrap().unwrap();
+        let response = echo_once(server, client_config(&certs, false)).unwrap();
+        assert_eq!(response, "OK foo STATS\n");
+    }
+
+    #[test]
+    fn mutual_tls_accepts_client_certificate() {
+        let certs = Certs::new("mutual");
+        let server = load_config(&make_conf(&certs, true)).unwrap().unwrap();
+        let response = echo_once(server, client_config(&certs, true)).unwrap();
+        assert_eq!(response, "OK foo STATS\n");
+    }
+
+    #[test]
+    fn mutual_tls_rejects_missing_client_certificate() {
+        let certs = Certs::new("mutual-reject");
+        let server = load_config(&make_conf(&certs, true)).unwrap().unwrap();
+        let response = echo_once(server, client_config(&certs, false));
+        assert!(response.map(|line| line.is_empty()).unwrap_or(true));
+    }
+}
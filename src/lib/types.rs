@@ -1,9 +1,16 @@
-use crate::lib::state::Message;
+use crate::lib::{changes, clients, slowlog};
+use crate::lib::state::{Export, Message};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
 
 pub enum Request {
+    Auth {
+        token: String,
+    },
     Push {
         channel_id: String,
         value: String,
+        key: Option<String>,
     },
     Retrieve {
         channel_id: String,
@@ -24,6 +31,7 @@ pub enum Request {
     },
     Flush {
         channel_id: String,
+        archive: bool,
     },
     Backup {
         channel_id: String,
@@ -31,38 +39,223 @@ pub enum Request {
     Stats {
         channel_id: String,
     },
+    Subscribe {
+        channel_id: String,
+    },
+    Unsubscribe {
+        channel_id: String,
+    },
+    Rename {
+        channel_id: String,
+        target_id: String,
+    },
+    Copy {
+        channel_id: String,
+        target_id: String,
+    },
+    List {
+        pattern: String,
+        cursor: String,
+        count: usize,
+    },
+    ConfigGet {
+        key: String,
+    },
+    ConfigSet {
+        key: String,
+        value: String,
+    },
+    SlowlogGet {
+        count: usize,
+    },
+    SlowlogReset,
+    Info,
+    ClientList,
+    ClientKill {
+        id: u64,
+    },
+    Replication,
+    Promote,
+    Import {
+        channel_id: String,
+        export: Export,
+    },
+    ClusterSlots,
+    Changes {
+        from: u64,
+        count: usize,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ChannelStats {
+    pub channel: String,
+    pub messages: usize,
+    pub bytes: usize,
+    pub first_created: Option<DateTime<Utc>>,
+    pub last_created: Option<DateTime<Utc>>,
+    pub pushes: u64,
+    pub updates: u64,
+    pub last_backup: Option<DateTime<Utc>>,
+    pub dirty: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ServerStats {
+    pub channels: usize,
+    pub messages: usize,
+    pub bytes: usize,
+    pub pushes: u64,
+    pub updates: u64,
+    pub last_backup: Option<DateTime<Utc>>,
+    pub dirty_channels: usize,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ChannelSummary {
+    pub channel: String,
+    pub messages: usize,
 }
 
 pub enum Response {
     Push { message: Message },
     Recent { messages: Vec<Message> },
     Retrieve { message: Message },
-    Stats { message: String },
+    Stats { stats: serde_json::Value },
+    List { cursor: String, channels: Vec<ChannelSummary> },
+    Value { value: String },
+    Slowlog { entries: Vec<slowlog::Entry> },
+    Clients { clients: Vec<clients::Info> },
+    Changes { changes: Vec<changes::Event>, next: u64 },
+    Killed { id: u64, killed: bool },
     Done {},
     Error { message: String },
 }
 
+/// The verbs `Request::parse` reads in place of a channel id, so channels
+/// with these names could never be reached with the line protocol.
+pub const RESERVED_CHANNEL_IDS: &[&str] = &[
+    "AUTH",
+    "LIST",
+    "CONFIG",
+    "SLOWLOG",
+    "INFO",
+    "REPLICATION",
+    "PROMOTE",
+    "CHANGES",
+    "CLUSTER",
+    "CLIENT",
+    "STATS",
+];
+
+/// Checks that a channel id can be used as a directory name under
+/// `persistence.path`, so no request reaches a path outside it, and that it
+/// isn't one of `RESERVED_CHANNEL_IDS`.
+pub fn check_channel_id(channel_id: &str) -> Result<(), String> {
+    let unsafe_char = |c| c == '/' || c == '\\' || c == '\0';
+    if channel_id.is_empty()
+        || channel_id == "."
+        || channel_id == ".."
+        || channel_id.contains(unsafe_char)
+        || RESERVED_CHANNEL_IDS.contains(&channel_id)
+    {
+        return Err(format!("invalid channel_id: {:?}", channel_id));
+    }
+    Ok(())
+}
+
 impl Request {
     pub fn parse(input: &str) -> Result<Request, String> {
         // println!("Incoming: {:?}", &input);
         let mut parts = input.splitn(4, " ");
         let channel_id = match parts.next() {
             Some(channel_id) => channel_id,
-            None => return Err(format!("PUSH needs a channel_id")),
+            None => return Err("PUSH needs a channel_id".to_string()),
         };
+        if channel_id == "AUTH" {
+            return match parts.next() {
+                Some("") | None => Err("AUTH needs a token".to_string()),
+                Some(token) => Ok(Request::Auth {
+                    token: token.to_string(),
+                }),
+            };
+        }
+        if channel_id == "LIST" {
+            return Request::parse_list(parts);
+        }
+        if channel_id == "CONFIG" {
+            return Request::parse_config(parts);
+        }
+        if channel_id == "SLOWLOG" {
+            return Request::parse_slowlog(parts);
+        }
+        if channel_id == "INFO" {
+            return match parts.next() {
+                None => Ok(Request::Info),
+                Some(_) => Err("INFO takes no arguments".to_string()),
+            };
+        }
+        if channel_id == "REPLICATION" {
+            return match parts.next() {
+                None => Ok(Request::Replication),
+                Some(_) => Err("REPLICATION takes no arguments".to_string()),
+            };
+        }
+        if channel_id == "PROMOTE" {
+            return match parts.next() {
+                None => Ok(Request::Promote),
+                Some(_) => Err("PROMOTE takes no arguments".to_string()),
+            };
+        }
+        if channel_id == "CHANGES" {
+            return Request::parse_changes(parts);
+        }
+        if channel_id == "CLUSTER" {
+            return match (parts.next(), parts.next()) {
+                (Some("SLOTS"), None) => Ok(Request::ClusterSlots),
+                _ => Err("CLUSTER needs SLOTS".to_string()),
+            };
+        }
+        if channel_id == "CLIENT" {
+            return Request::parse_client(parts);
+        }
+        if channel_id == "STATS" {
+            return match parts.next() {
+                Some("*") => Ok(Request::Stats {
+                    channel_id: "*".to_string(),
+                }),
+                _ => Err("STATS needs a channel_id or *".to_string()),
+            };
+        }
         match parts.next() {
             Some("PUSH") => {
                 let temp = match parts.next() {
                     Some(temp) => temp,
-                    None => return Err(format!("PUSH needs a value")),
+                    None => return Err("PUSH needs a value".to_string()),
                 };
                 let value = match parts.next() {
                     Some(value) => format!("{} {}", temp, value),
-                    None => format!("{}", temp),
+                    None => temp.to_string(),
                 };
                 Ok(Request::Push {
                     channel_id: channel_id.to_string(),
                     value: value.to_string(),
+                    key: None,
+                })
+            }
+            Some("PUSHKEY") => {
+                let key = match parts.next() {
+                    Some("") | None => return Err("PUSHKEY needs a key".to_string()),
+                    Some(key) => key,
+                };
+                let value = match parts.next() {
+                    Some(value) => value,
+                    None => return Err("PUSHKEY needs a value".to_string()),
+                };
+                Ok(Request::Push {
+                    channel_id: channel_id.to_string(),
+                    value: value.to_string(),
+                    key: Some(key.to_string()),
                 })
             }
             Some("RECENT") => {
@@ -85,7 +278,7 @@ impl Request {
             Some("RETRIEVE") => {
                 let uid = match parts.next() {
                     Some(uid) => uid,
-                    None => return Err(format!("RETRIEVE needs a uid")),
+                    None => return Err("RETRIEVE needs a uid".to_string()),
                 };
                 Ok(Request::Retrieve {
                     channel_id: channel_id.to_string(),
@@ -95,15 +288,15 @@ impl Request {
             Some("UPDATE") => {
                 let uid = match parts.next() {
                     Some(uid) => uid,
-                    None => return Err(format!("UPDATE needs a uid")),
+                    None => return Err("UPDATE needs a uid".to_string()),
                 };
                 let temp = match parts.next() {
                     Some(temp) => temp,
-                    None => return Err(format!("UPDATE needs a value")),
+                    None => return Err("UPDATE needs a value".to_string()),
                 };
                 let value = match parts.next() {
                     Some(value) => format!("{} {}", temp, value),
-                    None => format!("{}", temp),
+                    None => temp.to_string(),
                 };
                 Ok(Request::Update {
                     channel_id: channel_id.to_string(),
@@ -114,22 +307,264 @@ impl Request {
             Some("CONNECT") => Ok(Request::Connect {
                 channel_id: channel_id.to_string(),
             }),
-            Some("FLUSH") => Ok(Request::Flush {
-                channel_id: channel_id.to_string(),
-            }),
+            Some("FLUSH") => {
+                let archive = match parts.next() {
+                    Some("") | None => false,
+                    Some("ARCHIVE") => true,
+                    Some(mode) => return Err(format!("unknown FLUSH mode: {}", mode)),
+                };
+                Ok(Request::Flush {
+                    channel_id: channel_id.to_string(),
+                    archive,
+                })
+            }
             Some("BACKUP") => Ok(Request::Backup {
                 channel_id: channel_id.to_string(),
             }),
             Some("STATS") => Ok(Request::Stats {
                 channel_id: channel_id.to_string(),
             }),
+            Some("SUBSCRIBE") => Ok(Request::Subscribe {
+                channel_id: channel_id.to_string(),
+            }),
+            Some("UNSUBSCRIBE") => Ok(Request::Unsubscribe {
+                channel_id: channel_id.to_string(),
+            }),
+            Some("RENAME") => {
+                let target_id = match parts.next() {
+                    Some("") | None => return Err("RENAME needs a new channel_id".to_string()),
+                    Some(target_id) => target_id,
+                };
+                Ok(Request::Rename {
+                    channel_id: channel_id.to_string(),
+                    target_id: target_id.to_string(),
+                })
+            }
+            Some("COPY") => {
+                let target_id = match parts.next() {
+                    Some("") | None => return Err("COPY needs a new channel_id".to_string()),
+                    Some(target_id) => target_id,
+                };
+                Ok(Request::Copy {
+                    channel_id: channel_id.to_string(),
+                    target_id: target_id.to_string(),
+                })
+            }
+            Some("IMPORT") => {
+                let messages = match (parts.next(), parts.next()) {
+                    (Some(temp), Some(rest)) => format!("{} {}", temp, rest),
+                    (Some(temp), None) => temp.to_string(),
+                    _ => return Err("IMPORT needs messages".to_string()),
+                };
+                // A bare array of messages, or a whole exported channel.
+                let export = match messages.starts_with('[') {
+                    true => serde_json::from_str(&messages).map(|messages| Export {
+                        messages,
+                        ..Export::default()
+                    }),
+                    false => serde_json::from_str(&messages),
+                };
+                match export {
+                    Ok(export) => Ok(Request::Import {
+                        channel_id: channel_id.to_string(),
+                        export,
+                    }),
+                    Err(e) => Err(format!("IMPORT messages are invalid: {}", e)),
+                }
+            }
             Some(cmd) => Err(format!("ER unknown command: {}\n", cmd)),
-            None => Err(format!("ER empty input\n")),
+            None => Err("ER empty input\n".to_string()),
+        }
+    }
+
+    fn parse_config<'a, I: Iterator<Item = &'a str>>(mut parts: I) -> Result<Request, String> {
+        let action = parts.next();
+        let key = match parts.next() {
+            Some("") | None => return Err("CONFIG needs a key".to_string()),
+            Some(key) => key.to_string(),
+        };
+        match (action, parts.next()) {
+            (Some("GET"), None) => Ok(Request::ConfigGet { key }),
+            (Some("SET"), Some(value)) if !value.is_empty() => {
+                Ok(Request::ConfigSet {
+                    key,
+                    value: value.to_string(),
+                })
+            }
+            (Some("SET"), _) => Err("CONFIG SET needs a value".to_string()),
+            _ => Err("CONFIG needs GET <key> or SET <key> <value>".to_string()),
+        }
+    }
+
+    fn parse_slowlog<'a, I: Iterator<Item = &'a str>>(mut parts: I) -> Result<Request, String> {
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("GET"), None, None) => Ok(Request::SlowlogGet { count: 10 }),
+            (Some("GET"), Some(count), None) => match count.parse::<usize>() {
+                Ok(count) => Ok(Request::SlowlogGet { count }),
+                Err(_) => Err("SLOWLOG GET count must be a number".to_string()),
+            },
+            (Some("RESET"), None, None) => Ok(Request::SlowlogReset),
+            _ => Err("SLOWLOG needs GET [count] or RESET".to_string()),
+        }
+    }
+
+    fn parse_changes<'a, I: Iterator<Item = &'a str>>(mut parts: I) -> Result<Request, String> {
+        let (from, count) = match (parts.next(), parts.next(), parts.next()) {
+            (Some("FROM"), Some(from), None) => (from, "100"),
+            (Some("FROM"), Some(from), Some(count)) => (from, count),
+            _ => return Err("CHANGES needs FROM <lsn> [count]".to_string()),
+        };
+        match (from.parse::<u64>(), count.parse::<usize>()) {
+            (Ok(from), Ok(count)) => Ok(Request::Changes { from, count }),
+            _ => Err("CHANGES lsn and count must be numbers".to_string()),
+        }
+    }
+
+    fn parse_client<'a, I: Iterator<Item = &'a str>>(mut parts: I) -> Result<Request, String> {
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("LIST"), None, None) => Ok(Request::ClientList),
+            (Some("KILL"), Some(id), None) => match id.parse::<u64>() {
+                Ok(id) => Ok(Request::ClientKill { id }),
+                Err(_) => Err("CLIENT KILL needs a client id".to_string()),
+            },
+            _ => Err("CLIENT needs LIST or KILL <id>".to_string()),
+        }
+    }
+
+    fn parse_list<'a, I: Iterator<Item = &'a str>>(mut parts: I) -> Result<Request, String> {
+        let pattern = match parts.next() {
+            Some("") | None => "*",
+            Some(pattern) => pattern,
+        };
+        let cursor = match parts.next() {
+            Some("") | None => "0",
+            Some(cursor) => cursor,
+        };
+        let count = match parts.next() {
+            Some("") | None => "10",
+            Some(count) => count,
+        };
+        let count = match count.parse::<usize>() {
+            Ok(count) => count,
+            Err(_) => return Err("LIST count must be a number".to_string()),
+        };
+        Ok(Request::List {
+            pattern: pattern.to_string(),
+            cursor: cursor.to_string(),
+            count,
+        })
+    }
+
+    /// The command's name as it is written in the line protocol.
+    pub fn command(&self) -> &'static str {
+        match *self {
+            Request::Auth { .. } => "AUTH",
+            Request::Push { key: Some(_), .. } => "PUSHKEY",
+            Request::Push { .. } => "PUSH",
+            Request::Retrieve { .. } => "RETRIEVE",
+            Request::Update { .. } => "UPDATE",
+            Request::Recent { .. } => "RECENT",
+            Request::Connect { .. } => "CONNECT",
+            Request::Flush { .. } => "FLUSH",
+            Request::Backup { .. } => "BACKUP",
+            Request::Stats { .. } => "STATS",
+            Request::Subscribe { .. } => "SUBSCRIBE",
+            Request::Unsubscribe { .. } => "UNSUBSCRIBE",
+            Request::Rename { .. } => "RENAME",
+            Request::Copy { .. } => "COPY",
+            Request::List { .. } => "LIST",
+            Request::ConfigGet { .. } => "CONFIG GET",
+            Request::ConfigSet { .. } => "CONFIG SET",
+            Request::SlowlogGet { .. } => "SLOWLOG GET",
+            Request::SlowlogReset => "SLOWLOG RESET",
+            Request::Info => "INFO",
+            Request::ClientList => "CLIENT LIST",
+            Request::ClientKill { .. } => "CLIENT KILL",
+            Request::Replication => "REPLICATION",
+            Request::Promote => "PROMOTE",
+            Request::Import { .. } => "IMPORT",
+            Request::ClusterSlots => "CLUSTER SLOTS",
+            Request::Changes { .. } => "CHANGES",
+        }
+    }
+
+    /// Whether the request changes stored data, and so is audited.
+    pub fn mutates(&self) -> bool {
+        match *self {
+            Request::Backup { .. } => true,
+            _ => self.writes(),
+        }
+    }
+
+    /// Whether the request changes channel data, and so is replicated to
+    /// followers and refused by them.
+    pub fn writes(&self) -> bool {
+        matches!(
+            *self,
+            Request::Push { .. }
+                | Request::Update { .. }
+                | Request::Flush { .. }
+                | Request::Rename { .. }
+                | Request::Copy { .. }
+                | Request::Import { .. }
+        )
+    }
+
+    /// Checks every channel id the request names with `check_channel_id`.
+    pub fn check_ids(&self) -> Result<(), String> {
+        if let Some(channel_id) = self.channel_id() {
+            check_channel_id(channel_id)?;
+        }
+        match *self {
+            Request::Rename { ref target_id, .. } | Request::Copy { ref target_id, .. } => {
+                check_channel_id(target_id)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The channel the request is for, if it names one.
+    pub fn channel_id(&self) -> Option<&str> {
+        match *self {
+            Request::Push { ref channel_id, .. }
+            | Request::Retrieve { ref channel_id, .. }
+            | Request::Update { ref channel_id, .. }
+            | Request::Recent { ref channel_id, .. }
+            | Request::Connect { ref channel_id }
+            | Request::Flush { ref channel_id, .. }
+            | Request::Backup { ref channel_id }
+            | Request::Stats { ref channel_id }
+            | Request::Subscribe { ref channel_id }
+            | Request::Unsubscribe { ref channel_id }
+            | Request::Rename { ref channel_id, .. }
+            | Request::Copy { ref channel_id, .. }
+            | Request::Import { ref channel_id, .. } => Some(channel_id),
+            Request::Auth { .. }
+            | Request::List { .. }
+            | Request::ConfigGet { .. }
+            | Request::ConfigSet { .. }
+            | Request::SlowlogGet { .. }
+            | Request::SlowlogReset
+            | Request::Info
+            | Request::ClientList
+            | Request::ClientKill { .. }
+            | Request::Replication
+            | Request::Promote
+            | Request::ClusterSlots
+            | Request::Changes { .. } => None,
         }
     }
 }
 
 impl Response {
+    /// Whether the response is sent as an `ER` line.
+    pub fn is_error(&self) -> bool {
+        matches!(
+            *self,
+            Response::Error { .. } | Response::Killed { killed: false, .. }
+        )
+    }
+
     pub fn serialize(&self) -> String {
         match *self {
             // Response::Foo { ref message } => {
@@ -144,8 +579,39 @@ impl Response {
                 let serialized = serde_json::to_string(message).unwrap();
                 format!("OK {}\n", serialized)
             }
-            Response::Stats { ref message } => format!("OK {}\n", message),
-            Response::Done {} => format!("OK Done.\n"),
+            Response::Stats { ref stats } => format!("OK {}\n", stats),
+            Response::List {
+                ref cursor,
+                ref channels,
+            } => {
+                let serialized = serde_json::json!({
+                    "cursor": cursor,
+                    "channels": channels,
+                });
+                format!("OK {}\n", serialized)
+            }
+            Response::Value { ref value } => format!("OK {}\n", value),
+            Response::Slowlog { ref entries } => {
+                let serialized = serde_json::to_string(entries).unwrap();
+                format!("OK {}\n", serialized)
+            }
+            Response::Clients { ref clients } => {
+                let serialized = serde_json::to_string(clients).unwrap();
+                format!("OK {}\n", serialized)
+            }
+            Response::Changes {
+                ref changes,
+                next,
+            } => {
+                let serialized = serde_json::json!({
+                    "changes": changes,
+                    "next": next,
+                });
+                format!("OK {}\n", serialized)
+            }
+            Response::Killed { killed: true, .. } => "OK Done.\n".to_string(),
+            Response::Killed { id, killed: false } => format!("ER no such client: {}\n", id),
+            Response::Done {} => "OK Done.\n".to_string(),
             Response::Error { ref message } => format!("ER {}\n", message),
         }
     }
@@ -0,0 +1,446 @@
+use crate::lib::settings::{Webhook, WEBHOOK_BACKOFF_MAXIMUM};
+use crate::lib::state;
+use chrono::Utc;
+use futures::future::{self, loop_fn, Loop};
+use futures::sync::mpsc::{channel, Receiver, Sender};
+use futures::{Future, Stream};
+use glob::Pattern;
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request, Uri};
+use ring::hmac;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::timer::{Delay, Timeout};
+
+pub const SIGNATURE_HEADER: &str = "x-merkava-signature";
+pub const EVENT_HEADER: &str = "x-merkava-event";
+pub const CHANNEL_HEADER: &str = "x-merkava-channel";
+
+/// A configured webhook, ready to post to.
+pub struct Hook {
+    name: String,
+    channels: Pattern,
+    url: Uri,
+    key: Option<hmac::Key>,
+    retries: u32,
+    backoff: Duration,
+    timeout: Duration,
+    dead_letter: PathBuf,
+    queue: usize,
+    in_flight: usize,
+}
+
+impl Hook {
+    pub fn new(name: &str, settings: &Webhook) -> Result<Hook, String> {
+        Ok(Hook {
+            name: name.to_string(),
+            channels: Pattern::new(&settings.channels)
+                .map_err(|e| format!("webhooks.{}.channels is invalid: {}", name, e.msg))?,
+            url: settings
+                .url
+                .parse()
+                .map_err(|e| format!("webhooks.{}.url is invalid: {}", name, e))?,
+            key: settings
+                .secret
+                .as_ref()
+                .map(|secret| hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes())),
+            retries: settings.retries,
+            backoff: Duration::from_millis(settings.backoff),
+            timeout: Duration::from_millis(settings.timeout),
+            dead_letter: PathBuf::from(&settings.dead_letter),
+            queue: settings.queue,
+            in_flight: settings.in_flight,
+        })
+    }
+
+    /// `sha256=` and the hex HMAC-SHA256 of `body`, when the hook has a
+    /// secret.
+    fn signature(&self, body: &[u8]) -> Option<String> {
+        let key = self.key.as_ref()?;
+        let tag = hmac::sign(key, body);
+        let hex: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        Some(format!("sha256={}", hex))
+    }
+}
+
+/// A push or update to post to one hook.
+pub struct Delivery {
+    hook: Arc<Hook>,
+    event: &'static str,
+    channel: String,
+    message: state::Message,
+}
+
+/// The configured hooks, each with the bounded queue its deliveries wait
+/// in until the worker from `install` posts them.
+pub struct Dispatcher {
+    hooks: Vec<(Arc<Hook>, Sender<Delivery>)>,
+}
+
+impl Dispatcher {
+    /// Queues a delivery for every hook whose pattern matches `channel`.
+    /// One that finds its hook's queue full goes to the dead letter file.
+    pub fn notify(&mut self, event: &'static str, channel: &str, message: &state::Message) {
+        for (hook, queue) in &mut self.hooks {
+            if !hook.channels.matches(channel) {
+                continue;
+            }
+            let delivery = Delivery {
+                hook: hook.clone(),
+                event,
+                channel: channel.to_string(),
+                message: message.clone(),
+            };
+            match queue.try_send(delivery) {
+                Ok(()) => {}
+                Err(ref e) if e.is_disconnected() => {
+                    warn!("webhook {} dropped: the worker has stopped", hook.name);
+                }
+                Err(e) => {
+                    warn!("webhook {} is behind, not posting {}", hook.name, message.uid);
+                    write_dead_letter(&e.into_inner(), 0, "queue full");
+                }
+            }
+        }
+    }
+}
+
+pub static WEBHOOKS: Mutex<Option<Dispatcher>> = Mutex::new(None);
+
+/// Sets up the hooks from `[webhooks]`, returning the worker that posts
+/// them, or `None` when there are none.
+pub fn install(
+    settings: &HashMap<String, Webhook>,
+) -> Result<Option<Box<dyn Future<Item = (), Error = ()> + Send>>, String> {
+    if settings.is_empty() {
+        return Ok(None);
+    }
+    let mut names: Vec<&String> = settings.keys().collect();
+    names.sort();
+    let hooks = names
+        .into_iter()
+        .map(|name| Hook::new(name, &settings[name]).map(Arc::new))
+        .collect::<Result<Vec<_>, _>>()?;
+    for hook in &hooks {
+        info!(
+            "Posting {} pushes and updates to {}",
+            hook.channels, hook.url
+        );
+    }
+    let (hooks, queues): (Vec<_>, Vec<_>) = hooks
+        .into_iter()
+        .map(|hook| {
+            let (queue, deliveries) = channel(hook.queue);
+            ((hook.clone(), queue), (hook, deliveries))
+        })
+        .unzip();
+    *WEBHOOKS.lock().unwrap() = Some(Dispatcher { hooks });
+
+    let worker = future::lazy(|| {
+        let client = Client::new();
+        let workers = queues
+            .into_iter()
+            .map(move |(hook, deliveries)| work(client.clone(), &hook, deliveries));
+        future::join_all(workers).map(|_| ())
+    });
+    Ok(Some(Box::new(worker)))
+}
+
+/// Posts a hook's deliveries as they're queued, `in_flight` at a time.
+fn work(
+    client: Client<HttpConnector>,
+    hook: &Hook,
+    deliveries: Receiver<Delivery>,
+) -> impl Future<Item = (), Error = ()> {
+    deliveries
+        .map(move |delivery| deliver(client.clone(), delivery))
+        .buffer_unordered(hook.in_flight)
+        .for_each(|_| Ok(()))
+}
+
+/// Posts a push or update to the hooks matching its channel. Only a node
+/// taking writes does, so replication followers don't post them again.
+pub fn notify(db: &state::Database, event: &'static str, channel: &str, message: &state::Message) {
+    if let Some(dispatcher) = WEBHOOKS.lock().unwrap().as_mut() {
+        if db.replication.lock().unwrap().is_leader() {
+            dispatcher.notify(event, channel, message);
+        }
+    }
+}
+
+/// One try at posting a delivery. Anything but a 2xx answer is a failure.
+fn post(
+    client: &Client<HttpConnector>,
+    delivery: &Delivery,
+    body: Vec<u8>,
+) -> impl Future<Item = (), Error = String> {
+    let hook = &delivery.hook;
+    let mut request = Request::post(hook.url.clone());
+    request
+        .header(CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, delivery.event)
+        .header(CHANNEL_HEADER, delivery.channel.as_str());
+    if let Some(signature) = hook.signature(&body) {
+        request.header(SIGNATURE_HEADER, signature.as_str());
+    }
+    let posted = future::result(request.body(Body::from(body)))
+        .map_err(|e| e.to_string())
+        .and_then({
+            let client = client.clone();
+            move |request| client.request(request).map_err(|e| e.to_string())
+        })
+        .and_then(|response| {
+            let status = response.status();
+            // Read the body so the connection can be reused.
+            response.into_body().concat2().then(move |_| {
+                if status.is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("HTTP {}", status))
+                }
+            })
+        });
+    Timeout::new(posted, hook.timeout)
+        .map_err(|e| e.into_inner().unwrap_or_else(|| "timed out".to_string()))
+}
+
+/// Posts a delivery, retrying failures after `backoff`, doubling each
+/// time, and appending it to the hook's dead letter file once `retries`
+/// have failed too.
+pub fn deliver(
+    client: Client<HttpConnector>,
+    delivery: Delivery,
+) -> impl Future<Item = (), Error = ()> {
+    let body = serde_json::to_vec(&delivery.message).unwrap();
+    let delivery = Arc::new(delivery);
+    loop_fn(0, move |attempt| {
+        let delivery = delivery.clone();
+        post(&client, &delivery, body.clone()).then(move |posted| {
+            let error = match posted {
+                Ok(()) => return future::Either::A(future::ok(Loop::Break(()))),
+                Err(error) => error,
+            };
+            let hook = &delivery.hook;
+            if attempt >= hook.retries {
+                warn!(
+                    "webhook {} gave up on {} {} after {} attempt(s): {}",
+                    hook.name,
+                    delivery.event,
+                    delivery.message.uid,
+                    attempt + 1,
+                    error
+                );
+                write_dead_letter(&delivery, attempt + 1, &error);
+                return future::Either::A(future::ok(Loop::Break(())));
+            }
+            debug!("webhook {} failed, retrying: {}", hook.name, error);
+            future::Either::B(
+                Delay::new(Instant::now() + backoff(hook, attempt))
+                    .then(move |_| Ok(Loop::Continue(attempt + 1))),
+            )
+        })
+    })
+}
+
+/// How long to wait after a failed `attempt`: `backoff`, doubled for each
+/// attempt before it, but never longer than an hour.
+fn backoff(hook: &Hook, attempt: u32) -> Duration {
+    hook.backoff
+        .checked_mul(2u32.pow(attempt.min(16)))
+        .unwrap_or(Duration::MAX)
+        .min(Duration::from_millis(WEBHOOK_BACKOFF_MAXIMUM))
+}
+
+fn write_dead_letter(delivery: &Delivery, attempts: u32, error: &str) {
+    if let Err(e) = dead_letter(delivery, attempts, error) {
+        error!(
+            "unable to write to {}; error = {:?}",
+            delivery.hook.dead_letter.display(),
+            e
+        );
+    }
+}
+
+fn dead_letter(delivery: &Delivery, attempts: u32, error: &str) -> io::Result<()> {
+    let hook = &delivery.hook;
+    let line = json!({
+        "at": Utc::now().to_rfc3339(),
+        "hook": hook.name,
+        "url": hook.url.to_string(),
+        "event": delivery.event,
+        "channel": delivery.channel,
+        "message": delivery.message,
+        "attempts": attempts,
+        "error": error,
+    });
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&hook.dead_letter)?;
+    file.write_all(format!("{}\n", line).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Response, Server, StatusCode};
+    use std::net::SocketAddr;
+    use tokio::runtime::Runtime;
+
+    type Received = Arc<Mutex<Vec<(Option<String>, Option<String>, Vec<u8>)>>>;
+
+    /// Starts an HTTP server answering 500 to the first `failures`
+    /// requests and 200 after, and keeping the signature and event headers
+    /// and body of each.
+    fn stub(runtime: &mut Runtime, failures: usize) -> (SocketAddr, Received) {
+        let received: Received = Arc::new(Mutex::new(Vec::new()));
+        let kept = received.clone();
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(move || {
+            let kept = kept.clone();
+            hyper::service::service_fn(move |request: Request<Body>| {
+                let header = |name| {
+                    request
+                        .headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_string)
+                };
+                let (signature, event) = (header(SIGNATURE_HEADER), header(EVENT_HEADER));
+                let kept = kept.clone();
+                request.into_body().concat2().map(move |body| {
+                    let mut kept = kept.lock().unwrap();
+                    kept.push((signature, event, body.to_vec()));
+                    let status = match kept.len() > failures {
+                        true => StatusCode::OK,
+                        false => StatusCode::INTERNAL_SERVER_ERROR,
+                    };
+                    let mut response = Response::new(Body::empty());
+                    *response.status_mut() = status;
+                    response
+                })
+            })
+        });
+        let addr = server.local_addr();
+        runtime.spawn(server.map_err(|e| panic!("stub server failed: {}", e)));
+        (addr, received)
+    }
+
+    fn hook(name: &str, addr: SocketAddr, retries: u32) -> Hook {
+        let dead_letter = std::env::temp_dir().join(format!(
+            "merkava-webhooks-{}-{}.log",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&dead_letter);
+        let settings = Webhook {
+            channels: "room-*".to_string(),
+            url: format!("http://{}/hook", addr),
+            secret: Some("sekrit".to_string()),
+            retries,
+            backoff: 10,
+            timeout: 1000,
+            dead_letter: dead_letter.to_str().unwrap().to_string(),
+            ..Webhook::default()
+        };
+        Hook::new(name, &settings).unwrap()
+    }
+
+    fn message() -> state::Message {
+        state::Message {
+            uid: "abc".to_string(),
+            created: Utc::now(),
+            value: "hello".to_string(),
+        }
+    }
+
+    fn queue(hook: Hook, pushes: usize) -> Vec<Delivery> {
+        let (queue, deliveries) = channel(hook.queue);
+        let mut dispatcher = Dispatcher {
+            hooks: vec![(Arc::new(hook), queue)],
+        };
+        dispatcher.notify("push", "lobby", &message());
+        for _ in 0..pushes {
+            dispatcher.notify("push", "room-1", &message());
+        }
+        drop(dispatcher);
+        deliveries.collect().wait().unwrap()
+    }
+
+    #[test]
+    fn deliveries_are_signed_and_retried() {
+        let mut runtime = Runtime::new().unwrap();
+        let (addr, received) = stub(&mut runtime, 2);
+        let deliveries = queue(hook("retried", addr, 5), 1);
+        assert_eq!(deliveries.len(), 1);
+        let delivery = deliveries.into_iter().next().unwrap();
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"sekrit");
+
+        runtime.block_on(deliver(Client::new(), delivery)).unwrap();
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        let (ref signature, ref event, ref body) = received[2];
+        assert_eq!(event.as_deref(), Some("push"));
+        let sent: state::Message = serde_json::from_slice(body).unwrap();
+        assert_eq!((sent.uid.as_str(), sent.value.as_str()), ("abc", "hello"));
+        let hex = signature.as_ref().unwrap().strip_prefix("sha256=").unwrap();
+        let tag: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        assert!(hmac::verify(&key, body, &tag).is_ok());
+    }
+
+    #[test]
+    fn failed_deliveries_go_to_the_dead_letter_file() {
+        let mut runtime = Runtime::new().unwrap();
+        let (addr, received) = stub(&mut runtime, 10);
+        let hook = hook("dead", addr, 1);
+        let dead_letter = hook.dead_letter.clone();
+        let delivery = queue(hook, 1).into_iter().next().unwrap();
+
+        runtime.block_on(deliver(Client::new(), delivery)).unwrap();
+        assert_eq!(received.lock().unwrap().len(), 2);
+        let body = fs::read_to_string(&dead_letter).unwrap();
+        let line: serde_json::Value = serde_json::from_str(body.trim()).unwrap();
+        assert_eq!(line["hook"], "dead");
+        assert_eq!(line["channel"], "room-1");
+        assert_eq!(line["attempts"], 2);
+        assert_eq!(line["error"], "HTTP 500 Internal Server Error");
+        assert_eq!(line["message"]["value"], "hello");
+    }
+
+    #[test]
+    fn deliveries_past_a_full_queue_go_to_the_dead_letter_file() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let mut hook = hook("full", addr, 0);
+        hook.queue = 1;
+        let dead_letter = hook.dead_letter.clone();
+
+        // The one sender gets a slot of its own besides the queue's.
+        assert_eq!(queue(hook, 5).len(), 2);
+        let body = fs::read_to_string(&dead_letter).unwrap();
+        let lines: Vec<serde_json::Value> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["attempts"], 0);
+        assert_eq!(lines[0]["error"], "queue full");
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_an_hour() {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let mut hook = hook("backoff", addr, 0);
+        assert_eq!(backoff(&hook, 0), Duration::from_millis(10));
+        assert_eq!(backoff(&hook, 3), Duration::from_millis(80));
+        hook.backoff = Duration::from_millis(u64::MAX);
+        assert_eq!(backoff(&hook, 16), Duration::from_secs(3600));
+    }
+}
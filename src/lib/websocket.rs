@@ -0,0 +1,243 @@
+use crate::lib::{conf, operations, session, state};
+use futures::sync::mpsc::{unbounded, UnboundedReceiver};
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll, Stream};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+use tungstenite::handshake::{HandshakeError, MidHandshake};
+use tungstenite::{Error, Message, WebSocket};
+
+/// One WebSocket client. Each text frame holds one or more protocol lines,
+/// answered with one frame per line, and pushes to subscribed channels
+/// arrive as frames holding the JSON message.
+pub struct Connection {
+    db: Arc<state::Database>,
+    conf: conf::Shared,
+    session: session::Session,
+    feed: UnboundedReceiver<state::Message>,
+    killed: oneshot::Receiver<()>,
+    stream: Option<TcpStream>,
+    handshake: Option<MidHandshake<ServerHandshake<TcpStream, NoCallback>>>,
+    socket: Option<WebSocket<TcpStream>>,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream, db: &Arc<state::Database>, conf: &conf::Shared) -> Connection {
+        let (sender, feed) = unbounded();
+        let addr = stream.peer_addr().ok().map(|addr| addr.to_string());
+        let (mut session, killed) = session::Session::connect("websocket", addr);
+        session.feed = Some(sender);
+
+        Connection {
+            db: db.clone(),
+            conf: conf.clone(),
+            session,
+            feed,
+            killed,
+            stream: Some(stream),
+            handshake: None,
+            socket: None,
+        }
+    }
+
+    /// Drives the upgrade handshake, which has to happen inside `poll` so
+    /// the socket wakes this task rather than the accept loop.
+    fn poll_handshake(&mut self) -> Poll<bool, ()> {
+        let result = match (self.stream.take(), self.handshake.take()) {
+            (Some(stream), _) => tungstenite::accept(stream),
+            (None, Some(mid)) => mid.handshake(),
+            (None, None) => return Ok(Async::Ready(self.socket.is_some())),
+        };
+        match result {
+            Ok(socket) => self.socket = Some(socket),
+            Err(HandshakeError::Interrupted(mid)) => {
+                self.handshake = Some(mid);
+                return Ok(Async::NotReady);
+            }
+            Err(HandshakeError::Failure(e)) => debug!("websocket handshake failed: {}", e),
+        }
+        Ok(Async::Ready(self.socket.is_some()))
+    }
+}
+
+fn would_block(error: &Error) -> bool {
+    match *error {
+        Error::Io(ref e) => e.kind() == io::ErrorKind::WouldBlock,
+        _ => false,
+    }
+}
+
+impl Future for Connection {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        // Killed with CLIENT KILL; returning drops the socket.
+        match self.killed.poll() {
+            Ok(Async::NotReady) => (),
+            _ => return Ok(Async::Ready(())),
+        }
+        match self.poll_handshake()? {
+            Async::NotReady => return Ok(Async::NotReady),
+            Async::Ready(false) => return Ok(Async::Ready(())),
+            Async::Ready(true) => (),
+        }
+        let Connection {
+            ref db,
+            ref conf,
+            ref mut session,
+            ref mut feed,
+            ref mut socket,
+            ..
+        } = *self;
+        let socket = socket.as_mut().unwrap();
+
+        loop {
+            match socket.read_message() {
+                Ok(Message::Text(text)) => {
+                    for line in text.lines() {
+                        let response =
+                            operations::handle_request(db, conf, session, line.to_string());
+                        let response = response.serialize().trim_end().to_string();
+                        if let Err(ref e) = socket.write_message(Message::Text(response)) {
+                            if !would_block(e) {
+                                debug!("websocket write failed: {}", e);
+                                return Ok(Async::Ready(()));
+                            }
+                        }
+                    }
+                }
+                Ok(_) => (),
+                Err(ref e) if would_block(e) => break,
+                Err(Error::ConnectionClosed) | Err(Error::AlreadyClosed) => {
+                    return Ok(Async::Ready(()));
+                }
+                Err(e) => {
+                    debug!("websocket read failed: {}", e);
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+
+        while let Ok(Async::Ready(Some(message))) = feed.poll() {
+            let frame = Message::Text(serde_json::to_string(&message).unwrap());
+            if let Err(ref e) = socket.write_message(frame) {
+                if !would_block(e) {
+                    debug!("websocket write failed: {}", e);
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
+
+        match socket.write_pending() {
+            Ok(_) => Ok(Async::NotReady),
+            Err(ref e) if would_block(e) => Ok(Async::NotReady),
+            Err(_) => Ok(Async::Ready(())),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.db.unsubscribe_all(self.session.id);
+    }
+}
+
+/// Builds the optional WebSocket listener from the `[websocket]` section, or
+/// `None` when it is disabled.
+pub fn server(
+    db: &Arc<state::Database>,
+    shared: &conf::Shared,
+) -> Result<Option<Box<dyn Future<Item = (), Error = ()> + Send>>, String> {
+    let conf = shared.read().unwrap();
+    if !conf.get::<bool>("websocket.enabled").unwrap_or(false) {
+        return Ok(None);
+    }
+    let addr: SocketAddr = conf
+        .get::<String>("websocket.address")
+        .map_err(|_| "websocket.address is required when websocket.enabled is set".to_string())?
+        .parse()
+        .map_err(|e| format!("websocket.address is invalid: {}", e))?;
+    let socket = TcpListener::bind(&addr).map_err(|e| format!("unable to bind {}: {}", addr, e))?;
+    info!("WebSocket listening on: {}", addr);
+
+    let db = db.clone();
+    let conf = shared.clone();
+    let done = socket
+        .incoming()
+        .map_err(|e| error!("failed to accept websocket; error = {:?}", e))
+        .for_each(move |socket| {
+            debug!("accepted websocket; addr={:?}", socket.peer_addr().ok());
+            tokio::spawn(Connection::new(socket, &db, &conf));
+            Ok(())
+        });
+    Ok(Some(Box::new(done)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::testing;
+    use std::net::TcpStream as StdTcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    fn read_text(client: &mut WebSocket<StdTcpStream>) -> String {
+        loop {
+            match client.read_message().unwrap() {
+                Message::Text(text) => return text,
+                _ => continue,
+            }
+        }
+    }
+
+    #[test]
+    fn subscribe_receives_pushes() {
+        let db = testing::make_db();
+        let conf = conf::shared(config::Config::default());
+
+        // Bind here so the test knows the port, then serve each accepted
+        // socket with the same `Connection` the listener uses.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_db = db.clone();
+        let server_conf = conf.clone();
+        thread::spawn(move || {
+            let listener =
+                TcpListener::from_std(listener, &tokio::reactor::Handle::default()).unwrap();
+            let done = listener.incoming().map_err(|_| ()).for_each(move |socket| {
+                tokio::spawn(Connection::new(socket, &server_db, &server_conf));
+                Ok(())
+            });
+            tokio::run(done);
+        });
+
+        let stream = StdTcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (mut client, _) = tungstenite::client(format!("ws://{}/", addr), stream).unwrap();
+
+        client
+            .write_message(Message::Text("foo SUBSCRIBE".to_string()))
+            .unwrap();
+        assert_eq!(read_text(&mut client), "OK Done.");
+
+        client
+            .write_message(Message::Text("foo PUSH hello".to_string()))
+            .unwrap();
+        let mut frames = vec![read_text(&mut client), read_text(&mut client)];
+        frames.sort();
+        assert!(frames[0].starts_with("OK "), "{:?}", frames);
+        let pushed: serde_json::Value = serde_json::from_str(&frames[1]).unwrap();
+        assert_eq!(pushed["value"], "hello");
+
+        let mut session = session::Session::new();
+        operations::handle_request(&db, &conf, &mut session, "foo PUSH from tcp".to_string());
+        let pushed: serde_json::Value = serde_json::from_str(&read_text(&mut client)).unwrap();
+        assert_eq!(pushed["value"], "from tcp");
+    }
+}
@@ -12,89 +12,622 @@ extern crate uuid;
 
 mod lib;
 
-use futures::future::lazy;
-use lib::{conf, logging, operations, state};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use futures::future::Shared;
+use futures::sync::oneshot;
+use glob::Pattern;
+use lib::settings::Settings;
+use lib::{
+    audit, changes, clients, cluster, conf, dump, gateway, info, logging, metrics, operations,
+    replication, resp, session, shutdown, state, tls, webhooks, websocket,
+};
 // use log::Level;
-use std::env;
-use std::io::BufReader;
+use std::fs;
+use std::io::{self, BufReader};
 use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::io::{lines, write_all};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{lines, write_all, AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
 use tokio::prelude::*;
+use tokio::runtime::Runtime;
 use tokio::timer::Interval;
 
-fn main() -> Result<(), Box<std::error::Error>> {
+fn serve<S>(
+    stream: S,
+    kind: &'static str,
+    addr: Option<String>,
+    db: &Arc<state::Database>,
+    conf: &conf::Shared,
+) where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = stream.split();
+    let lines = lines(BufReader::new(reader));
+
+    let db = db.clone();
+    let conf = conf.clone();
+    let (mut session, killed) = session::Session::connect(kind, addr);
+    session.acknowledged = true;
+
+    // Writes are answered once replication has acknowledged them.
+    let responses = lines.and_then(move |line| {
+        let response = operations::handle_request(&db, &conf, &mut session, line);
+        replication::acknowledged(&db, &conf, response, session.written)
+    });
+    let writes = responses.fold(writer, |writer, response| {
+        let mut response = response.serialize();
+        response.push('\n');
+        write_all(writer, response.into_bytes()).map(|(w, _)| w)
+    });
+
+    let msg = writes.then(move |_| Ok(()));
+
+    tokio::spawn(clients::killable(msg, killed));
+}
+
+/// Runs a listener until shutdown starts, then drops it so no more
+/// connections are accepted.
+fn until_stopped<F>(
+    listener: F,
+    stopped: &Shared<oneshot::Receiver<()>>,
+) -> impl Future<Item = (), Error = ()>
+where
+    F: Future<Item = (), Error = ()>,
+{
+    listener
+        .select(stopped.clone().map(|_| ()).map_err(|_| ()))
+        .map(|_| ())
+        .map_err(|_| ())
+}
+
+/// Binds the Unix socket at `path`. A socket nothing answers on, left
+/// behind by an unclean exit, is replaced, but anything else already there
+/// is an error. The socket is bound in a directory only this user can
+/// enter and moved into place once it has `mode`, so it's never reachable
+/// with the umask's permissions.
+fn bind_unix(path: &str, mode: Option<u32>) -> io::Result<UnixListener> {
+    let target = Path::new(path);
+    match fs::symlink_metadata(target) {
+        Ok(ref metadata) if !metadata.file_type().is_socket() => {
+            let message = format!("{} exists and is not a socket", path);
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, message));
+        }
+        Ok(_) => match UnixStream::connect(target) {
+            Ok(_) => {
+                let message = format!("another server is listening on {}", path);
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, message));
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                fs::remove_file(target)?
+            }
+            Err(e) => return Err(e),
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let parent = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let private = parent.join(format!(".merkava-{}", std::process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|socket| {
+        if let Some(mode) = mode {
+            fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+        }
+        fs::rename(&staged, target)?;
+        Ok(socket)
+    });
+    let _ = fs::remove_file(&staged);
+    let _ = fs::remove_dir(&private);
+    bound
+}
+
+/// Exit codes for failures outside the server itself, from sysexits.h.
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_IOERR: i32 = 74;
+const EX_CONFIG: i32 = 78;
+
+const DEFAULT_CONFIG: &str = "./mrkvconf.toml";
+
+fn cli() -> App<'static, 'static> {
+    let config = Arg::with_name("config")
+        .short("c")
+        .long("config")
+        .value_name("FILE")
+        .help("Config file to read [default: ./mrkvconf.toml, if it exists]");
+    let data_dir = Arg::with_name("data-dir")
+        .short("d")
+        .long("data-dir")
+        .value_name("DIR")
+        .help("Where channels are stored, overriding persistence.path");
+    let print_config = Arg::with_name("print-config")
+        .long("print-config")
+        .help("Print the effective settings and exit");
+
+    App::new("merkava")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("A fast and lightweight DB for storing ordered data")
+        .setting(AppSettings::ArgRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        // `merkava [FILE] --print-config` from before the subcommands.
+        .arg(config.clone().conflicts_with("file"))
+        .arg(
+            Arg::with_name("file")
+                .index(1)
+                .hidden(true)
+                .requires("print-config"),
+        )
+        .arg(print_config.clone())
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Run the server")
+                .arg(config.clone())
+                .arg(
+                    Arg::with_name("listen")
+                        .short("l")
+                        .long("listen")
+                        .value_name("ADDRESS")
+                        .help("Address for the line protocol, overriding network.address"),
+                )
+                .arg(data_dir.clone())
+                .arg(
+                    Arg::with_name("verbose")
+                        .short("v")
+                        .multiple(true)
+                        .help("Log more, repeat for more detail, overriding logging.verbosity"),
+                )
+                .arg(print_config.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("check-config")
+                .about("Validate the config and exit")
+                .arg(config.clone())
+                .arg(print_config),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Write the channels stored on disk as JSON lines")
+                .arg(config.clone())
+                .arg(data_dir.clone())
+                .arg(
+                    Arg::with_name("channel")
+                        .long("channel")
+                        .value_name("PATTERN")
+                        .default_value("*")
+                        .help("Only dump channels matching this pattern"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Write to a file instead of stdout"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Write channels from a dump to disk; run it while the server is stopped")
+                .arg(config)
+                .arg(data_dir)
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .value_name("FILE")
+                        .help("Read from a file instead of stdin"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Overwrite channels that already exist"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("audit-verify")
+                .about("Check the hash chain of audit log files")
+                .arg(
+                    Arg::with_name("files")
+                        .value_name("FILE")
+                        .multiple(true)
+                        .required(true)
+                        .help("Audit log files, oldest first"),
+                ),
+        )
+        .subcommand(SubCommand::with_name("version").about("Print the version"))
+}
+
+/// Builds the config source from the flags shared by the subcommands.
+fn source(matches: &ArgMatches) -> conf::Source {
+    let mut source = match matches.value_of("config") {
+        Some(path) => conf::Source::new(path),
+        None => {
+            let mut source = conf::Source::new(DEFAULT_CONFIG);
+            source.required = false;
+            source
+        }
+    };
+    if let Some(listen) = matches.value_of("listen") {
+        source.set("network.address", listen);
+    }
+    if let Some(data_dir) = matches.value_of("data-dir") {
+        source.set("persistence.path", data_dir);
+    }
+    if matches.occurrences_of("verbose") > 0 {
+        source.set("logging.verbosity", matches.occurrences_of("verbose") as i64);
+    }
+    source
+}
+
+fn load_settings(source: &conf::Source) -> Result<Settings, i32> {
+    source.load_settings().map_err(|e| {
+        eprintln!("{}", e);
+        EX_CONFIG
+    })
+}
+
+fn check_config(matches: &ArgMatches) -> i32 {
+    let source = source(matches);
+    let settings = match load_settings(&source) {
+        Ok(settings) => settings,
+        Err(code) => return code,
+    };
+    if matches.is_present("print-config") {
+        return print_config(&settings);
+    }
+    println!("{}: OK", source.path);
+    0
+}
+
+fn print_config(settings: &Settings) -> i32 {
+    match settings.to_toml() {
+        Ok(shown) => {
+            print!("{}", shown);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            EX_CONFIG
+        }
+    }
+}
+
+fn dump(matches: &ArgMatches) -> i32 {
+    let settings = match load_settings(&source(matches)) {
+        Ok(settings) => settings,
+        Err(code) => return code,
+    };
+    let pattern = match Pattern::new(matches.value_of("channel").unwrap()) {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            eprintln!("invalid --channel pattern: {}", e.msg);
+            return EX_USAGE;
+        }
+    };
+    let db = state::create_db(settings.persistence.path);
+    let written = match matches.value_of("output") {
+        Some(path) => fs::File::create(path)
+            .and_then(|file| dump::dump(&db, &pattern, io::BufWriter::new(file))),
+        None => dump::dump(&db, &pattern, io::stdout().lock()),
+    };
+    match written {
+        Ok(written) => {
+            eprintln!("dumped {} message(s)", written);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            EX_IOERR
+        }
+    }
+}
+
+fn restore(matches: &ArgMatches) -> i32 {
+    let conf = match load_settings(&source(matches)).map(|settings| settings.to_config()) {
+        Ok(Ok(conf)) => conf,
+        Ok(Err(e)) => {
+            eprintln!("{}", e);
+            return EX_CONFIG;
+        }
+        Err(code) => return code,
+    };
+    let force = matches.is_present("force");
+    let stdin = io::stdin();
+    let restored = match matches.value_of("input") {
+        Some(path) => match fs::File::open(path) {
+            Ok(file) => dump::restore(&conf, io::BufReader::new(file), force),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                return EX_IOERR;
+            }
+        },
+        None => dump::restore(&conf, stdin.lock(), force),
+    };
+    match restored {
+        Ok(restored) => {
+            eprintln!("restored {} channel(s)", restored);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            EX_DATAERR
+        }
+    }
+}
+
+fn audit_verify(matches: &ArgMatches) -> i32 {
+    let mut chain = audit::Chain::default();
+    for path in matches.values_of("files").unwrap() {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                return EX_IOERR;
+            }
+        };
+        if let Err(e) = chain.verify(path, BufReader::new(file)) {
+            eprintln!("{}", e);
+            return EX_DATAERR;
+        }
+    }
+    if let Some(seq) = chain.continues {
+        eprintln!("note: starts at seq {}, so earlier files were not checked", seq);
+    }
+    match chain.seq {
+        Some(seq) => println!("{} record(s) OK, last seq {}", chain.records, seq),
+        None => println!("no records"),
+    }
+    0
+}
+
+fn main() {
+    let matches = match cli().get_matches_safe() {
+        Ok(matches) => matches,
+        // Help and --version go to stdout and exit 0.
+        Err(ref e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            eprintln!("{}", e.message);
+            std::process::exit(EX_USAGE);
+        }
+    };
+    let code = match matches.subcommand() {
+        ("serve", Some(matches)) => {
+            let source = source(matches);
+            match load_settings(&source) {
+                Ok(ref settings) if matches.is_present("print-config") => print_config(settings),
+                Ok(settings) => serve_forever(settings, source).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    1
+                }),
+                Err(code) => code,
+            }
+        }
+        ("check-config", Some(matches)) => check_config(matches),
+        ("dump", Some(matches)) => dump(matches),
+        ("restore", Some(matches)) => restore(matches),
+        ("audit-verify", Some(matches)) => audit_verify(matches),
+        ("version", _) => {
+            println!("merkava {}", env!("CARGO_PKG_VERSION"));
+            0
+        }
+        _ if matches.is_present("print-config") => {
+            let mut source = source(&matches);
+            if let Some(path) = matches.value_of("file") {
+                source = conf::Source::new(path);
+            }
+            match load_settings(&source) {
+                Ok(ref settings) => print_config(settings),
+                Err(code) => code,
+            }
+        }
+        _ => {
+            let _ = cli().print_help();
+            println!();
+            EX_USAGE
+        }
+    };
+    std::process::exit(code);
+}
+
+/// Runs the server until SIGTERM or SIGINT, returning the exit code.
+fn serve_forever(
+    settings: Settings,
+    source: conf::Source,
+) -> Result<i32, Box<dyn std::error::Error>> {
     // info!(target: "overly-verbose-target", "completed operation.");
 
-    let mrkvconf = env::args()
-        .nth(1)
-        .unwrap_or("./src/mrkvconf.toml".to_string());
-    let conf = conf::get_conf(mrkvconf);
-    let addr_raw = conf.get::<String>("network.address").unwrap();
-    let addr: SocketAddr = addr_raw.parse().unwrap();
-    let backup_interval = conf.get::<u64>("persistence.interval").unwrap();
-    let backup_path = conf.get::<String>("persistence.path").unwrap();
+    let conf = settings.to_config()?;
+    // Already checked by `Settings::validate`.
+    let addr: Option<SocketAddr> = match settings.network.address.as_str() {
+        "" => None,
+        addr_raw => Some(addr_raw.parse()?),
+    };
+    let unix_socket = settings.network.unix_socket.clone();
+    let backup_path = settings.persistence.path.clone();
 
-    logging::setup_logging(conf.get::<u64>("logging.verbosity").unwrap())
+    logging::setup_logging(&settings.logging)
         .expect("failed to initialize logging.");
     info!("MerkavaDB starting up");
+    info::started(match Path::new(&source.path).exists() {
+        true => Some(source.path.clone()),
+        false => None,
+    });
 
-    let start_backup = Interval::new(
-        Instant::now(),
-        Duration::from_millis(&backup_interval * 1_000 + 1),
-    )
-    .for_each(move |_| {
-        debug!("running");
-        let connection = TcpStream::connect(&addr);
-        let _do_process = connection.and_then(|socket| {
-            debug!("process");
-            let (_, mut tx) = socket.split();
-            tx.poll_write(b"foo PUSH something\n")
-                .expect("Unable to send to TCP connection");
-            return Ok(());
-        });
-
-        Ok(())
-    })
-    .map_err(|e| panic!("interval errored; err={:?}", e));
+    if settings.audit.enabled {
+        audit::install(audit::Sink::open(&settings.audit)?);
+        info!("Auditing changes to {}", settings.audit.path);
+    }
+    if settings.changes.enabled {
+        changes::install(changes::Log::open(&settings.changes)?);
+        info!("Recording the change feed in {}", settings.changes.path);
+    }
+    let webhooks = webhooks::install(&settings.webhooks)?;
 
-    let socket = TcpListener::bind(&addr)?;
-    info!("Listening on: {}", addr);
+    let tls = tls::load_config(&conf)?;
     let db = state::create_db(backup_path);
-    let done = socket
-        .incoming()
-        .map_err(|e| error!("failed to accept socket; error = {:?}", e))
-        .for_each(move |socket| {
-            debug!("accepted socket; addr={:?}", socket.peer_addr().unwrap());
+    let conf = conf::shared(conf);
 
-            let (reader, writer) = socket.split();
-            let lines = lines(BufReader::new(reader));
+    // Ticks every second and reads the interval each time, so a reload or
+    // CONFIG SET takes effect without restarting the timer.
+    let backup_db = db.clone();
+    let backup_conf = conf.clone();
+    let mut last_backup = Instant::now();
+    let start_backup = Interval::new_interval(Duration::from_secs(1))
+        .for_each(move |now| {
+            let conf = backup_conf.read().unwrap();
+            let interval = conf.get::<u64>("persistence.interval").unwrap_or(0);
+            if interval == 0 || now.duration_since(last_backup) < Duration::from_secs(interval) {
+                return Ok(());
+            }
+            last_backup = now;
+            let (written, errors) = operations::backup_dirty(&backup_db, &conf);
+            for error in &errors {
+                error!("Scheduled backup failed for {}", error);
+            }
+            debug!("Scheduled backup wrote {} channel(s)", written);
+            Ok(())
+        })
+        .map_err(|e| panic!("interval errored; err={:?}", e));
 
+    let retention_db = db.clone();
+    let retention_conf = conf.clone();
+    let retention = Interval::new_interval(Duration::from_secs(1))
+        .for_each(move |_| {
+            let dropped =
+                operations::apply_retention(&retention_db, &retention_conf.read().unwrap());
+            if dropped > 0 {
+                debug!("Retention dropped {} message(s)", dropped);
+            }
+            Ok(())
+        })
+        .map_err(|e| panic!("interval errored; err={:?}", e));
+
+    let tcp = match addr {
+        Some(addr) => {
+            let socket = TcpListener::bind(&addr)?;
+            match tls {
+                Some(_) => info!("Listening on: {} (TLS)", addr),
+                None => info!("Listening on: {}", addr),
+            }
             let db = db.clone();
             let conf = conf.clone();
+            let done = socket
+                .incoming()
+                .map_err(|e| error!("failed to accept socket; error = {:?}", e))
+                .for_each(move |socket| {
+                    debug!("accepted socket; addr={:?}", socket.peer_addr().unwrap());
+                    let addr = socket.peer_addr().ok().map(|addr| addr.to_string());
 
-            let responses = lines.map(move |line| operations::handle_request(&db, &conf, line));
-            let writes = responses.fold(writer, |writer, response| {
-                let mut response = response.serialize();
-                response.push('\n');
-                write_all(writer, response.into_bytes()).map(|(w, _)| w)
-            });
-
-            let msg = writes.then(move |_| Ok(()));
+                    match tls {
+                        Some(ref tls) => {
+                            serve(tls::TlsStream::new(tls, socket), "tls", addr, &db, &conf)
+                        }
+                        None => serve(socket, "tcp", addr, &db, &conf),
+                    }
+                    Ok(())
+                });
+            Some(done)
+        }
+        None => None,
+    };
 
-            tokio::spawn(msg)
-        });
+    let http = gateway::server(&db, &conf)?;
+    let ws = websocket::server(&db, &conf)?;
+    let redis = resp::server(&db, &conf)?;
+    let prometheus = metrics::server(&db, &conf)?;
+    replication::configure(&db, &conf)?;
+    let replicas = replication::server(&db, &conf)?;
 
-    tokio::run(lazy(move || {
-        if backup_interval > 0 {
-            info!("starting backup");
-            tokio::spawn(start_backup);
+    let unix = match unix_socket {
+        Some(path) => {
+            let mode = match settings.network.unix_socket_mode {
+                Some(ref mode) => Some(u32::from_str_radix(mode, 8)?),
+                None => None,
+            };
+            let socket = bind_unix(&path, mode)?;
+            info!("Listening on: {}", path);
+            let db = db.clone();
+            let conf = conf.clone();
+            let done = socket
+                .incoming()
+                .map_err(|e| error!("failed to accept unix socket; error = {:?}", e))
+                .for_each(move |socket| {
+                    debug!("accepted unix socket; addr={:?}", socket.peer_addr().ok());
+                    serve(socket, "unix", None, &db, &conf);
+                    Ok(())
+                });
+            Some(done)
         }
+        None => None,
+    };
+
+    let (stop, stopped) = oneshot::channel::<()>();
+    let stopped = stopped.shared();
+    let mut runtime = Runtime::new()?;
+    runtime.spawn(start_backup);
+    runtime.spawn(retention);
+    runtime.spawn(conf::reload_on_hangup(&conf, &source));
+
+    info!("Ready to receive");
+    if let Some(done) = tcp {
+        runtime.spawn(until_stopped(done, &stopped));
+    }
+    if let Some(done) = unix {
+        runtime.spawn(until_stopped(done, &stopped));
+    }
+    if let Some(done) = http {
+        runtime.spawn(until_stopped(done, &stopped));
+    }
+    if let Some(done) = ws {
+        runtime.spawn(until_stopped(done, &stopped));
+    }
+    if let Some(done) = redis {
+        runtime.spawn(until_stopped(done, &stopped));
+    }
+    if let Some(done) = prometheus {
+        runtime.spawn(until_stopped(done, &stopped));
+    }
+    if let Some(done) = replicas {
+        runtime.spawn(until_stopped(done, &stopped));
+    }
+    if settings.replication.address.is_some() || settings.replication.leader.is_some() {
+        runtime.spawn(until_stopped(replication::heartbeat(&db), &stopped));
+        runtime.spawn(until_stopped(replication::follower(&db, &conf), &stopped));
+    }
+    runtime.spawn(until_stopped(cluster::rebalancer(&db, &conf), &stopped));
+    if let Some(worker) = webhooks {
+        runtime.spawn(until_stopped(worker, &stopped));
+    }
+
+    let signal = runtime.block_on(shutdown::signals())?;
+    info!("Received signal {}, shutting down", signal);
+    shutdown::TRACKER.stop();
+    let _ = stop.send(());
 
-        info!("Ready to receive");
-        tokio::spawn(done);
-        Ok(())
-    }));
-    Ok(())
+    let timeout = conf.read().unwrap().get::<u64>("shutdown.timeout").unwrap_or(10);
+    let drained = shutdown::TRACKER.drain(Duration::from_secs(timeout));
+    if !drained {
+        warn!("Requests still running after {}s, closing connections", timeout);
+    }
+    let _ = runtime.shutdown_now().wait();
+
+    let (written, errors) = operations::backup_dirty(&db, &conf.read().unwrap());
+    for error in &errors {
+        error!("Final snapshot failed for {}", error);
+    }
+    info!("Final snapshot wrote {} channel(s)", written);
+    log::logger().flush();
+
+    Ok(match (errors.is_empty(), drained) {
+        (false, _) => 1,
+        (true, false) => 2,
+        (true, true) => 0,
+    })
 }
@@ -0,0 +1,71 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+/// A server started with `merkava serve`, killed when dropped.
+pub struct Node {
+    pub address: String,
+    pub child: Option<Child>,
+}
+
+impl Node {
+    /// Writes `config` to `path`, adding the line protocol address, and
+    /// starts a server with it.
+    pub fn start(path: &Path, address: &str, config: &str) -> Node {
+        write_config(path, address, config);
+        let child = Command::new(env!("CARGO_BIN_EXE_merkava"))
+            .arg("serve")
+            .arg("--config")
+            .arg(path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        Node {
+            address: address.to_string(),
+            child: Some(child),
+        }
+    }
+
+    pub fn kill(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+pub fn write_config(path: &Path, address: &str, config: &str) {
+    let body = format!(
+        "[network]\naddress = {:?}\n\n{}\n\n[logging]\nstdout = false\nfile = \"\"\n",
+        address, config
+    );
+    fs::write(path, body).unwrap();
+}
+
+pub fn free_address() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Sends one line and returns the answer, or `None` if the server can't be
+/// reached.
+pub fn request(address: &str, line: &str) -> Option<String> {
+    let mut stream = TcpStream::connect(address).ok()?;
+    stream.set_read_timeout(Some(Duration::from_secs(5))).ok()?;
+    stream.write_all(format!("{}\n", line).as_bytes()).ok()?;
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer).ok()?;
+    Some(answer.trim_end().to_string())
+}
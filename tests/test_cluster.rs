@@ -0,0 +1,95 @@
+mod common;
+
+use common::{free_address, request, write_config, Node};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+fn config(dir: &Path, name: &str, nodes: &[&String]) -> String {
+    format!(
+        "[cluster]\nenabled = true\nnodes = {:?}\n\n[persistence]\npath = {:?}",
+        nodes,
+        dir.join(name).to_str().unwrap()
+    )
+}
+
+/// Sends `line` to `address`, following one `MOVED` redirect.
+fn redirected(address: &str, line: &str) -> Option<String> {
+    let answer = request(address, line)?;
+    match answer.strip_prefix("ER MOVED ") {
+        Some(owner) => request(owner, line),
+        None => Some(answer),
+    }
+}
+
+fn wait_for(what: &str, done: impl Fn() -> bool) {
+    let started = Instant::now();
+    while !done() {
+        assert!(started.elapsed() < Duration::from_secs(20), "{}", what);
+        sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn channels_move_to_a_node_joining_the_cluster() {
+    let dir = std::env::temp_dir().join(format!("merkava-cluster-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let (a, b) = (free_address(), free_address());
+
+    let a_config = dir.join("a.toml");
+    let node_a = Node::start(&a_config, &a, &config(&dir, "a", &[&a]));
+    wait_for("node a didn't start", || {
+        request(&a, "CLUSTER SLOTS").is_some()
+    });
+    let channels: Vec<String> = (0..30).map(|i| format!("room-{}", i)).collect();
+    for channel in &channels {
+        let answer = request(&a, &format!("{} PUSH hello {}", channel, channel)).unwrap();
+        assert!(answer.starts_with("OK "), "{}", answer);
+    }
+
+    let _node_b = Node::start(&dir.join("b.toml"), &b, &config(&dir, "b", &[&a, &b]));
+    wait_for("node b didn't start", || {
+        request(&b, "CLUSTER SLOTS").is_some()
+    });
+    write_config(&a_config, &a, &config(&dir, "a", &[&a, &b]));
+    let pid = node_a.child.as_ref().unwrap().id().to_string();
+    assert!(Command::new("kill")
+        .args(["-HUP", &pid])
+        .status()
+        .unwrap()
+        .success());
+
+    let moved = || {
+        channels
+            .iter()
+            .filter(|channel| {
+                let answer = request(&a, &format!("{} RECENT", channel));
+                answer.is_some_and(|answer| answer.starts_with("ER MOVED"))
+            })
+            .count()
+    };
+    wait_for("no channels moved", || moved() > 0);
+    let everywhere = || {
+        channels.iter().all(|channel| {
+            let answer = redirected(&a, &format!("{} RECENT", channel));
+            answer.is_some_and(|answer| {
+                answer.contains(&format!("hello {}", channel))
+            })
+        })
+    };
+    wait_for("channels went missing", everywhere);
+    assert!(moved() < channels.len());
+
+    let answer = request(&b, "room-0 PUSH after").unwrap();
+    let answer = match answer.strip_prefix("ER MOVED ") {
+        Some(owner) => request(owner, "room-0 PUSH after").unwrap(),
+        None => answer,
+    };
+    assert!(answer.starts_with("OK "), "{}", answer);
+
+    drop(node_a);
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use common::{free_address, request, Node};
+use std::fs;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+fn start(dir: &Path, name: &str, address: &str, replication: &str, peers: &[String]) -> Node {
+    let config = format!(
+        "[replication]\naddress = {:?}\npeers = {:?}\nelection_timeout = 500\n\
+         token = \"s3cret\"\n\n[persistence]\npath = {:?}",
+        replication,
+        peers,
+        dir.join(name).to_str().unwrap()
+    );
+    Node::start(&dir.join(format!("{}.toml", name)), address, &config)
+}
+
+fn role(node: &Node) -> Option<String> {
+    let answer = request(&node.address, "REPLICATION")?;
+    let status: serde_json::Value = serde_json::from_str(answer.strip_prefix("OK ")?).ok()?;
+    status["role"].as_str().map(str::to_string)
+}
+
+/// Waits for one of the live nodes to lead, returning its index.
+fn leader(nodes: &[Node]) -> usize {
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(20) {
+        let leading = nodes
+            .iter()
+            .position(|node| node.child.is_some() && role(node).as_deref() == Some("leader"));
+        if let Some(leading) = leading {
+            return leading;
+        }
+        sleep(Duration::from_millis(100));
+    }
+    panic!("no leader was elected");
+}
+
+#[test]
+fn acknowledged_writes_survive_losing_the_leader() {
+    let dir = std::env::temp_dir().join(format!("merkava-failover-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let addresses: Vec<(String, String)> =
+        (0..3).map(|_| (free_address(), free_address())).collect();
+    let mut nodes: Vec<Node> = addresses
+        .iter()
+        .enumerate()
+        .map(|(i, (address, replication))| {
+            let peers: Vec<String> = addresses
+                .iter()
+                .filter(|(_, other)| other != replication)
+                .map(|(_, other)| other.clone())
+                .collect();
+            start(&dir, &format!("node{}", i), address, replication, &peers)
+        })
+        .collect();
+
+    let first = leader(&nodes);
+    let mut uids = Vec::new();
+    for i in 0..20 {
+        let answer = request(&nodes[first].address, &format!("room PUSH message-{}", i)).unwrap();
+        let uid = answer.strip_prefix("OK ").expect(&answer);
+        uids.push(uid.to_string());
+    }
+
+    nodes[first].kill();
+    let second = leader(&nodes);
+    assert_ne!(first, second);
+
+    for (i, uid) in uids.iter().enumerate() {
+        let answer = request(&nodes[second].address, &format!("room RETRIEVE {}", uid)).unwrap();
+        assert!(answer.contains(&format!("message-{}", i)), "{}", answer);
+    }
+    let answer = request(&nodes[second].address, "room PUSH after").unwrap();
+    assert!(answer.starts_with("OK "), "{}", answer);
+
+    drop(nodes);
+    let _ = fs::remove_dir_all(&dir);
+}
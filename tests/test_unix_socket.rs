@@ -0,0 +1,68 @@
+mod common;
+
+use common::{free_address, Node};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+fn start(dir: &Path, name: &str, socket: &Path) -> Node {
+    let config = format!(
+        "unix_socket = {:?}\nunix_socket_mode = \"600\"\n\n[persistence]\npath = {:?}",
+        socket.to_str().unwrap(),
+        dir.join(name).to_str().unwrap()
+    );
+    Node::start(&dir.join(format!("{}.toml", name)), &free_address(), &config)
+}
+
+/// Sends one line over the socket at `path`, waiting for the server to
+/// bind it, and returns the answer.
+fn request(path: &Path, line: &str) -> String {
+    let started = Instant::now();
+    let mut stream = loop {
+        match UnixStream::connect(path) {
+            Ok(stream) => break stream,
+            Err(e) if started.elapsed() > Duration::from_secs(10) => panic!("{}", e),
+            Err(_) => sleep(Duration::from_millis(50)),
+        }
+    };
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(format!("{}\n", line).as_bytes()).unwrap();
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer).unwrap();
+    answer.trim_end().to_string()
+}
+
+#[test]
+fn serves_over_a_unix_socket_replacing_a_stale_one() {
+    let dir = std::env::temp_dir().join(format!("merkava-unix-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("merkava.sock");
+    drop(UnixListener::bind(&socket).unwrap());
+
+    let mut node = start(&dir, "node", &socket);
+    assert!(request(&socket, "room PUSH hello").starts_with("OK "));
+    assert!(request(&socket, "room RECENT 1").contains("hello"));
+    let mode = fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // A second server must not take the live socket over.
+    let mut second = start(&dir, "second", &socket);
+    let exited = second.child.as_mut().unwrap().wait().unwrap();
+    assert!(!exited.success());
+    assert!(request(&socket, "room RECENT 1").contains("hello"));
+    node.kill();
+
+    let file = dir.join("file.sock");
+    fs::write(&file, "keep me").unwrap();
+    let mut refused = start(&dir, "refused", &file);
+    let exited = refused.child.as_mut().unwrap().wait().unwrap();
+    assert!(!exited.success());
+    assert_eq!(fs::read_to_string(&file).unwrap(), "keep me");
+
+    let _ = fs::remove_dir_all(&dir);
+}